@@ -1,5 +1,7 @@
 use tame_oauth::gcp::*;
 
+use bytes::Bytes;
+
 // This example shows the basics for creating a token provider for the default
 // credentials on the system. If you want to use a service account, set
 // `GOOGLE_APPLICATION_CREDENTIALS` to a service account key path, if have
@@ -14,12 +16,17 @@ async fn main() {
 
     println!("Using {}", provider.kind());
 
+    let audience = "my-audience";
+
     // Attempt to get a token, since we have never used this accessor
     // before, it's guaranteed that we will need to make an HTTPS
     // request to the token provider to retrieve a token. This
     // will also happen if we want to get a token for a different
     // audience, or if the token has expired.
-    match provider.get_id_token("my-audience").unwrap() {
+    match provider.get_id_token(audience).unwrap() {
+        IdTokenOrRequest::IdToken(_) => {
+            println!("cool, we already had an id token!");
+        }
         IdTokenOrRequest::IdTokenRequest {
             // This is an http::Request that we can use to build
             // a client request for whichever HTTP client implementation
@@ -28,51 +35,76 @@ async fn main() {
             audience_hash,
             ..
         } => {
-            let client = reqwest::Client::new();
-
-            let (parts, body) = request.into_parts();
-            let uri = parts.uri.to_string();
-
-            // This will always be a POST, but for completeness sake...
-            let builder = match parts.method {
-                http::Method::GET => client.get(&uri),
-                http::Method::POST => client.post(&uri),
-                http::Method::DELETE => client.delete(&uri),
-                http::Method::PUT => client.put(&uri),
-                method => unimplemented!("{} not implemented", method),
-            };
-
-            // Build the full request from the headers and body that were
-            // passed to you, without modifying them.
-            let request = builder.headers(parts.headers).body(body).build().unwrap();
-
-            // Send the actual request
-            let response = client.execute(request).await.unwrap();
+            let response = execute_request(request).await;
 
-            let mut builder = http::Response::builder()
-                .status(response.status())
-                .version(response.version());
+            let _token = provider
+                .parse_id_token_response(audience_hash, response)
+                .expect("invalid token response");
 
-            let headers = builder.headers_mut().unwrap();
+            println!("cool, we were able to receive an id token!");
+        }
+        // Some credentials, eg. a service account, first need an access token
+        // which is then exchanged for the id token
+        IdTokenOrRequest::AccessTokenRequest {
+            request,
+            audience_hash,
+            ..
+        } => {
+            let access_token_response = execute_request(request).await;
 
-            // Unfortunately http doesn't expose a way to just use
-            // an existing HeaderMap, so we have to copy them :(
-            headers.extend(
-                response
-                    .headers()
-                    .into_iter()
-                    .map(|(k, v)| (k.clone(), v.clone())),
-            );
+            let id_token_request = provider
+                .get_id_token_with_access_token(audience, access_token_response)
+                .expect("invalid access token response");
 
-            let buffer = response.bytes().await.unwrap();
-            let response = builder.body(buffer).unwrap();
+            let response = execute_request(id_token_request).await;
 
             let _token = provider
                 .parse_id_token_response(audience_hash, response)
                 .expect("invalid token response");
 
-            println!("cool, we were able to receive a id token!");
+            println!("cool, we were able to receive an id token!");
         }
-        _ => unreachable!(),
     }
 }
+
+async fn execute_request(request: http::Request<Vec<u8>>) -> http::Response<Bytes> {
+    let client = reqwest::Client::new();
+
+    let (parts, body) = request.into_parts();
+    let uri = parts.uri.to_string();
+
+    // This will usually be a POST, but for completeness sake...
+    let builder = match parts.method {
+        http::Method::GET => client.get(&uri),
+        http::Method::POST => client.post(&uri),
+        http::Method::DELETE => client.delete(&uri),
+        http::Method::PUT => client.put(&uri),
+        method => unimplemented!("{} not implemented", method),
+    };
+
+    // Build the full request from the headers and body that were
+    // passed to you, without modifying them.
+    let request = builder.headers(parts.headers).body(body).build().unwrap();
+
+    // Send the actual request
+    let response = client.execute(request).await.unwrap();
+
+    let mut builder = http::Response::builder()
+        .status(response.status())
+        .version(response.version());
+
+    let headers = builder.headers_mut().unwrap();
+
+    // Unfortunately http doesn't expose a way to just use
+    // an existing HeaderMap, so we have to copy them :(
+    headers.extend(
+        response
+            .headers()
+            .into_iter()
+            .map(|(k, v)| (k.clone(), v.clone())),
+    );
+
+    let buffer = response.bytes().await.unwrap();
+
+    builder.body(buffer).unwrap()
+}
@@ -1,5 +1,7 @@
 use tame_oauth::gcp::*;
 
+use bytes::Bytes;
+
 // This example shows the basics for creating a GCP service account token
 // provider and requesting a token from it. This particular example uses the
 // reqwest HTTP client, but the point of this crate is that you can use
@@ -27,6 +29,7 @@ async fn main() {
     // will also happen if we want to get a token for a different set
     // of scopes, or if the token has expired.
     let token = match sa_provider.get_token(&scopes).unwrap() {
+        TokenOrRequest::Token(token) => token,
         TokenOrRequest::Request {
             // This is an http::Request that we can use to build
             // a client request for whichever HTTP client implementation
@@ -35,44 +38,7 @@ async fn main() {
             scope_hash,
             ..
         } => {
-            let client = reqwest::Client::new();
-
-            let (parts, body) = request.into_parts();
-            let uri = parts.uri.to_string();
-
-            // This will always be a POST, but for completeness sake...
-            let builder = match parts.method {
-                http::Method::GET => client.get(&uri),
-                http::Method::POST => client.post(&uri),
-                http::Method::DELETE => client.delete(&uri),
-                http::Method::PUT => client.put(&uri),
-                method => unimplemented!("{} not implemented", method),
-            };
-
-            // Build the full request from the headers and body that were
-            // passed to you, without modifying them.
-            let request = builder.headers(parts.headers).body(body).build().unwrap();
-
-            // Send the actual request
-            let response = client.execute(request).await.unwrap();
-
-            let mut builder = http::Response::builder()
-                .status(response.status())
-                .version(response.version());
-
-            let headers = builder.headers_mut().unwrap();
-
-            // Unfortunately http doesn't expose a way to just use
-            // an existing HeaderMap, so we have to copy them :(
-            headers.extend(
-                response
-                    .headers()
-                    .into_iter()
-                    .map(|(k, v)| (k.clone(), v.clone())),
-            );
-
-            let buffer = response.bytes().await.unwrap();
-            let response = builder.body(buffer).unwrap();
+            let response = execute_request(request).await;
 
             // Tell our accessor about the response, also passing
             // the scope_hash for the scopes we initially requested,
@@ -82,11 +48,30 @@ async fn main() {
                 .parse_token_response(scope_hash, response)
                 .unwrap()
         }
-        _ => unreachable!(),
+        // Service accounts sign their own assertions, but other providers,
+        // eg. an impersonated service account, first need an access token
+        // from their source credentials
+        TokenOrRequest::AccessTokenRequest {
+            request,
+            scope_hash,
+            ..
+        } => {
+            let access_token_response = execute_request(request).await;
+
+            let token_request = sa_provider
+                .get_token_with_access_token(&scopes, access_token_response)
+                .unwrap();
+
+            let response = execute_request(token_request).await;
+
+            sa_provider
+                .parse_token_response(scope_hash, response)
+                .unwrap()
+        }
     };
 
-    // Uncomment this if you want to go to lunch and see an unreachable panic
-    // when you get back
+    // Uncomment this if you want to go to lunch and see the cached token
+    // assertion fail when you get back
     // std::thread::sleep(std::time::Duration::from_secs(60 * 60))
 
     // Retrieving a token for the same scopes for which a token has been acquired
@@ -100,6 +85,48 @@ async fn main() {
                 if scopes.len() == 1 { "" } else { "s" }
             );
         }
-        _ => unreachable!(),
+        other => panic!("expected the cached token, got {:?}", other),
     }
 }
+
+async fn execute_request(request: http::Request<Vec<u8>>) -> http::Response<Bytes> {
+    let client = reqwest::Client::new();
+
+    let (parts, body) = request.into_parts();
+    let uri = parts.uri.to_string();
+
+    // This will usually be a POST, but for completeness sake...
+    let builder = match parts.method {
+        http::Method::GET => client.get(&uri),
+        http::Method::POST => client.post(&uri),
+        http::Method::DELETE => client.delete(&uri),
+        http::Method::PUT => client.put(&uri),
+        method => unimplemented!("{} not implemented", method),
+    };
+
+    // Build the full request from the headers and body that were
+    // passed to you, without modifying them.
+    let request = builder.headers(parts.headers).body(body).build().unwrap();
+
+    // Send the actual request
+    let response = client.execute(request).await.unwrap();
+
+    let mut builder = http::Response::builder()
+        .status(response.status())
+        .version(response.version());
+
+    let headers = builder.headers_mut().unwrap();
+
+    // Unfortunately http doesn't expose a way to just use
+    // an existing HeaderMap, so we have to copy them :(
+    headers.extend(
+        response
+            .headers()
+            .into_iter()
+            .map(|(k, v)| (k.clone(), v.clone())),
+    );
+
+    let buffer = response.bytes().await.unwrap();
+
+    builder.body(buffer).unwrap()
+}
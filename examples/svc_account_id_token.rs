@@ -30,6 +30,20 @@ async fn main() {
     // will also happen if we want to get a token for a different
     // audience, or if the token has expired.
     let token = match sa_provider.get_id_token(audience).unwrap() {
+        IdTokenOrRequest::IdToken(token) => token,
+        IdTokenOrRequest::IdTokenRequest {
+            request,
+            audience_hash,
+            ..
+        } => {
+            let id_token_response = execute_request(request).await;
+
+            sa_provider
+                .parse_id_token_response(audience_hash, id_token_response)
+                .unwrap()
+        }
+        // Service accounts first need an access token, which is then
+        // exchanged for the id token
         IdTokenOrRequest::AccessTokenRequest {
             request,
             audience_hash,
@@ -47,11 +61,10 @@ async fn main() {
                 .parse_id_token_response(audience_hash, id_token_response)
                 .unwrap()
         }
-        _ => unreachable!(),
     };
 
-    // Uncomment this if you want to go to lunch and see an unreachable panic
-    // when you get back
+    // Uncomment this if you want to go to lunch and see the cached token
+    // assertion fail when you get back
     // std::thread::sleep(std::time::Duration::from_secs(60 * 60))
 
     // Retrieving a token for the same scopes for which a token has been acquired
@@ -64,7 +77,7 @@ async fn main() {
                 audience,
             );
         }
-        _ => unreachable!(),
+        other => panic!("expected the cached token, got {:?}", other),
     }
 }
 
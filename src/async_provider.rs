@@ -0,0 +1,183 @@
+//! An async facade over the sans-io token providers, for the common case of
+//! just wanting a token. The providers create requests which are sent with an
+//! [`HttpClient`], and the responses are passed back to the provider, so the
+//! crate itself stays agnostic of the HTTP client and async runtime used.
+
+use crate::{
+    id_token::{IdTokenOrRequest, IdTokenProvider},
+    token::{TokenOrRequest, TokenProvider},
+    Error, IdToken, Token,
+};
+use std::future::Future;
+
+/// An HTTP client that can send the requests created by token providers
+pub trait HttpClient: Sync {
+    /// The body of the responses
+    type Body: AsRef<[u8]> + Send;
+
+    /// Sends the request, returning the response. Failures to send the request
+    /// or receive the response should be returned as [`Error::Transport`],
+    /// while unsuccessful status codes are handled by the token providers.
+    fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> impl Future<Output = Result<http::Response<Self::Body>, Error>> + Send;
+}
+
+/// Retrieves tokens asynchronously, implemented for all [`TokenProvider`]s
+pub trait AsyncTokenProvider {
+    /// Gets a token for the specified scopes, sending the request(s) needed
+    /// to retrieve a new token with the client if the provider doesn't have a
+    /// valid cached token
+    fn token<C: HttpClient>(
+        &self,
+        client: &C,
+        scopes: &[&str],
+    ) -> impl Future<Output = Result<Token, Error>> + Send;
+}
+
+impl<P> AsyncTokenProvider for P
+where
+    P: TokenProvider + Sync,
+{
+    async fn token<C: HttpClient>(&self, client: &C, scopes: &[&str]) -> Result<Token, Error> {
+        let (request, scope_hash) = match self.get_token(scopes)? {
+            TokenOrRequest::Token(token) => return Ok(token),
+            TokenOrRequest::Request {
+                request,
+                scope_hash,
+                ..
+            } => (request, scope_hash),
+            TokenOrRequest::AccessTokenRequest {
+                request,
+                scope_hash,
+                ..
+            } => {
+                let response = client.send(request).await?;
+                let request = self.get_token_with_access_token(scopes, response)?;
+                (request, scope_hash)
+            }
+        };
+
+        let response = client.send(request).await?;
+        self.parse_token_response(scope_hash, response)
+    }
+}
+
+/// Retrieves id tokens asynchronously, implemented for all [`IdTokenProvider`]s
+pub trait AsyncIdTokenProvider {
+    /// Gets an id token for the specified audience, sending the request(s)
+    /// needed to retrieve a new token with the client if the provider doesn't
+    /// have a valid cached token
+    fn id_token<C: HttpClient>(
+        &self,
+        client: &C,
+        audience: &str,
+    ) -> impl Future<Output = Result<IdToken, Error>> + Send;
+}
+
+impl<P> AsyncIdTokenProvider for P
+where
+    P: IdTokenProvider + Sync,
+{
+    async fn id_token<C: HttpClient>(&self, client: &C, audience: &str) -> Result<IdToken, Error> {
+        let (request, audience_hash) = match self.get_id_token(audience)? {
+            IdTokenOrRequest::IdToken(token) => return Ok(token),
+            IdTokenOrRequest::IdTokenRequest {
+                request,
+                audience_hash,
+                ..
+            } => (request, audience_hash),
+            IdTokenOrRequest::AccessTokenRequest {
+                request,
+                audience_hash,
+                ..
+            } => {
+                let response = client.send(request).await?;
+                let request = self.get_id_token_with_access_token(audience, response)?;
+                (request, audience_hash)
+            }
+        };
+
+        let response = client.send(request).await?;
+        self.parse_id_token_response(audience_hash, response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::token_cache::CachedTokenProvider;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A client that responds to every request with the same token
+    #[derive(Default)]
+    struct FakeClient {
+        requests: AtomicUsize,
+    }
+
+    impl HttpClient for FakeClient {
+        type Body = &'static str;
+
+        async fn send(
+            &self,
+            request: http::Request<Vec<u8>>,
+        ) -> Result<http::Response<Self::Body>, Error> {
+            assert_eq!(request.uri(), "https://example.com/token");
+            self.requests.fetch_add(1, Ordering::Relaxed);
+
+            Ok(http::Response::new(r#"{"access_token":"access-token"}"#))
+        }
+    }
+
+    /// A token provider that requests tokens from a fixed URL
+    struct FakeProvider;
+
+    impl TokenProvider for FakeProvider {
+        fn get_token_with_subject<'a, S, I, T>(
+            &self,
+            _subject: Option<T>,
+            _scopes: I,
+        ) -> Result<TokenOrRequest, Error>
+        where
+            S: AsRef<str> + 'a,
+            I: IntoIterator<Item = &'a S> + Clone,
+            T: Into<String>,
+        {
+            Ok(TokenOrRequest::Request {
+                request: http::Request::post("https://example.com/token").body(Vec::new())?,
+                reason: crate::token::RequestReason::ParametersChanged,
+                scope_hash: 0,
+            })
+        }
+
+        fn parse_token_response<S>(
+            &self,
+            _hash: u64,
+            response: http::Response<S>,
+        ) -> Result<Token, Error>
+        where
+            S: AsRef<[u8]>,
+        {
+            let token: serde_json::Value = serde_json::from_slice(response.body().as_ref())?;
+            Ok(Token::new(
+                token["access_token"].as_str().unwrap(),
+                "Bearer",
+                std::time::Duration::from_secs(3599),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn async_token() {
+        let provider = CachedTokenProvider::wrap(FakeProvider);
+        let client = FakeClient::default();
+
+        let token = provider.token(&client, &["scope"]).await.unwrap();
+        assert_eq!(token.access_token, "access-token");
+
+        let cached = provider.token(&client, &["scope"]).await.unwrap();
+        assert_eq!(cached, token);
+        assert_eq!(client.requests.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,8 +1,9 @@
 use std::{error::Error as Err, fmt};
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
-    /// The private_key field in the [Service Account Key](https://cloud.google.com/iam/docs/creating-managing-service-account-keys)
+    /// The `private_key` field in the [Service Account Key](https://cloud.google.com/iam/docs/creating-managing-service-account-keys)
     /// is invalid and cannot be parsed
     #[cfg(feature = "jwt")]
     InvalidKeyFormat,
@@ -12,7 +13,17 @@ pub enum Error {
     Http(http::Error),
     /// Failed to authenticate and retrieve an oauth token, and were unable to
     /// deserialize a more exact reason from the error response
-    HttpStatus(http::StatusCode),
+    #[non_exhaustive]
+    HttpStatus {
+        /// The status code of the response
+        status: http::StatusCode,
+        /// The identifier the server attached to the response, if any, see
+        /// [`AuthError::correlation_id`]
+        correlation_id: Option<String>,
+        /// The body of the response, eg. the HTML error page of a proxy,
+        /// truncated to 1024 bytes, if it wasn't empty
+        body: Option<String>,
+    },
     /// Failed to de/serialize JSON
     Json(serde_json::Error),
     /// Failed to authenticate and retrieve an oauth token
@@ -23,8 +34,36 @@ pub enum Error {
     /// The RSA key is invalid and cannot be used to sign
     #[cfg(feature = "jwt")]
     InvalidRsaKeyRejected(ring::error::KeyRejected),
-    /// A mutex has been poisoned due to a panic while a lock was held
-    Poisoned,
+    /// The signature of a JWT doesn't match its contents, or was made with an
+    /// algorithm that isn't allowed
+    #[cfg(feature = "jwt")]
+    SignatureInvalid,
+    /// The algorithm is not supported for signing or verifying
+    #[cfg(feature = "jwt")]
+    UnsupportedAlgorithm(crate::jwt::Algorithm),
+    /// The signature of a JWT is valid, but the claim with the specified name
+    /// is not, eg. the token has expired or is meant for another audience
+    #[cfg(feature = "jwt")]
+    InvalidClaim(&'static str),
+    /// The key set has no key with the `kid` in the header of a JWT, or the
+    /// header has no `kid` at all. If the key set was retrieved a while ago
+    /// this might mean the keys have been rotated.
+    #[cfg(feature = "jwt")]
+    UnknownKeyId(Option<String>),
+    /// The key with the specified id is not meant to verify the JWT, ie. its
+    /// `use` is not `sig` or its `alg` is not the algorithm of the JWT
+    #[cfg(feature = "jwt")]
+    IncompatibleKey(String),
+    /// An additional claim was added to a JWT with the name of a claim that is
+    /// set by this crate, eg. `iss` or `exp`
+    #[cfg(feature = "jwt")]
+    ReservedClaim(String),
+    /// Failed to generate secure random bytes, eg. for a PKCE code verifier
+    #[cfg(feature = "oauth2")]
+    Random(ring::error::Unspecified),
+    /// The requested token lifetime is outside of the range allowed by the
+    /// token server
+    InvalidLifetime(std::time::Duration),
     /// An I/O error occurred when reading credentials
     #[cfg(feature = "gcp")]
     Io(std::io::Error),
@@ -34,10 +73,32 @@ pub enum Error {
         file: std::path::PathBuf,
         error: Box<Error>,
     },
+    /// The PEM encoded private key of a service account is invalid, for the
+    /// specified reason
+    #[cfg(feature = "gcp")]
+    InvalidPem { reason: String },
+    /// The `type` of credentials JSON is missing or is not one of the
+    /// supported credential types
+    #[cfg(feature = "gcp")]
+    UnsupportedCredentials(Option<String>),
     /// An error occurred due to [`SystemTime`](std::time::SystemTime)
     SystemTime(std::time::SystemTimeError),
+    /// The system clock is set to a time before the UNIX epoch, so no valid
+    /// timestamps can be created, which usually means it has never been synced
+    ClockBeforeEpoch,
     /// Unable to parse the returned token
     InvalidTokenFormat,
+    /// The `expires_in` of a token response is so large that the expiry of
+    /// the token can't be represented
+    InvalidExpiresIn(i64),
+    /// An `Authorization` header could not be parsed as a bearer token, for
+    /// the specified reason
+    InvalidAuthorizationHeader(&'static str),
+    /// A URL supplied to a provider is not a valid `http` or `https` URL, or a
+    /// URI, eg. a grant type, is not a valid absolute URI
+    InvalidUrl(String),
+    /// The HTTP client failed to send a request or receive its response
+    Transport(Box<dyn Err + Send + Sync>),
 }
 
 impl fmt::Display for Error {
@@ -50,26 +111,78 @@ impl fmt::Display for Error {
             InvalidKeyFormat => f.write_str("The key format is invalid or unknown"),
             Base64Decode(err) => write!(f, "{}", err),
             Http(err) => write!(f, "{}", err),
-            HttpStatus(sc) => write!(f, "HTTP error status: {}", sc),
+            HttpStatus {
+                status,
+                correlation_id,
+                body,
+            } => {
+                write!(f, "HTTP error status: {}", status)?;
+
+                if let Some(id) = correlation_id {
+                    write!(f, " (correlation id: {})", id)?;
+                }
+
+                if let Some(body) = body {
+                    write!(f, ": {}", body)?;
+                }
+
+                Ok(())
+            }
             Json(err) => write!(f, "{}", err),
             Auth(err) => write!(f, "{}", err),
             #[cfg(feature = "jwt")]
             InvalidRsaKey(_err) => f.write_str("RSA key is invalid"),
             #[cfg(feature = "jwt")]
             InvalidRsaKeyRejected(err) => write!(f, "RSA key is invalid: {}", err),
-            Poisoned => f.write_str("A mutex is poisoned"),
+            #[cfg(feature = "jwt")]
+            SignatureInvalid => f.write_str("The JWT signature is invalid"),
+            #[cfg(feature = "jwt")]
+            UnsupportedAlgorithm(alg) => write!(f, "Unsupported algorithm {:?}", alg),
+            #[cfg(feature = "jwt")]
+            InvalidClaim(claim) => write!(f, "The '{}' claim of the JWT is invalid", claim),
+            #[cfg(feature = "jwt")]
+            UnknownKeyId(Some(kid)) => write!(f, "No key with the id '{}' in the key set", kid),
+            #[cfg(feature = "jwt")]
+            UnknownKeyId(None) => f.write_str("The JWT header has no key id"),
+            #[cfg(feature = "jwt")]
+            IncompatibleKey(kid) => write!(f, "The key '{}' can't verify the JWT", kid),
+            #[cfg(feature = "jwt")]
+            ReservedClaim(claim) => write!(f, "The '{}' claim is reserved and can't be set", claim),
+            #[cfg(feature = "oauth2")]
+            Random(_err) => f.write_str("Failed to generate random bytes"),
+            InvalidLifetime(lifetime) => write!(f, "Invalid token lifetime: {:?}", lifetime),
             #[cfg(feature = "gcp")]
             Io(inner) => write!(f, "{}", inner),
             #[cfg(feature = "gcp")]
             InvalidCredentials { file, error } => {
                 write!(f, "Invalid credentials in '{}': {}", file.display(), error)
             }
+            #[cfg(feature = "gcp")]
+            InvalidPem { reason } => write!(f, "Invalid PEM private key: {}", reason),
+            #[cfg(feature = "gcp")]
+            UnsupportedCredentials(Some(cred_type)) => {
+                write!(f, "Unsupported credentials type '{}'", cred_type)
+            }
+            #[cfg(feature = "gcp")]
+            UnsupportedCredentials(None) => f.write_str("The credentials have no type"),
             SystemTime(te) => {
                 write!(f, "System Time error: {}", te)
             }
+            ClockBeforeEpoch => write!(
+                f,
+                "The system clock is set before 1970-01-01, it needs to be synced (eg. via NTP)"
+            ),
             InvalidTokenFormat => {
                 write!(f, "Invalid token format")
             }
+            InvalidAuthorizationHeader(reason) => {
+                write!(f, "Invalid Authorization header: {}", reason)
+            }
+            InvalidExpiresIn(expires_in) => {
+                write!(f, "Invalid token expires_in: {}", expires_in)
+            }
+            InvalidUrl(url) => write!(f, "Invalid URL '{}'", url),
+            Transport(err) => write!(f, "HTTP transport error: {}", err),
         }
     }
 }
@@ -85,6 +198,7 @@ impl std::error::Error for Error {
             Json(err) => Some(err as &dyn Err),
             Auth(err) => Some(err as &dyn Err),
             SystemTime(err) => Some(err as &dyn Err),
+            Transport(err) => Some(err.as_ref() as &dyn Err),
             _ => None,
         }
     }
@@ -114,22 +228,188 @@ impl From<std::time::SystemTimeError> for Error {
     }
 }
 
+impl Error {
+    /// Returns true if the error is likely transient, and the request that
+    /// caused it can be retried, ideally with a backoff. These are:
+    ///
+    /// * [`Error::HttpStatus`] with a 5xx server error status, `408 Request Timeout`
+    ///   or `429 Too Many Requests`
+    /// * [`Error::Io`] of the kind [`TimedOut`](std::io::ErrorKind::TimedOut)
+    ///   or [`Interrupted`](std::io::ErrorKind::Interrupted)
+    ///
+    /// All other errors, eg. [`Error::Auth`] or invalid keys, will fail again
+    /// if retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::HttpStatus { status, .. } => {
+                status.is_server_error()
+                    || *status == http::StatusCode::REQUEST_TIMEOUT
+                    || *status == http::StatusCode::TOO_MANY_REQUESTS
+            }
+            #[cfg(feature = "gcp")]
+            Error::Io(err) => matches!(
+                err.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+
+    /// Creates an [`Error::HttpStatus`] for an unsuccessful response
+    #[cfg(any(feature = "jwt", feature = "oauth2"))]
+    pub(crate) fn http_status(parts: &http::response::Parts, body: &[u8]) -> Self {
+        Error::HttpStatus {
+            status: parts.status,
+            correlation_id: correlation_id(&parts.headers),
+            body: error_body(body),
+        }
+    }
+}
+
+/// The maximum number of bytes of a response body that are kept in an
+/// [`Error::HttpStatus`]
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+const MAX_ERROR_BODY_LEN: usize = 1024;
+
+/// Converts the body of an unsuccessful response to a string for diagnostics,
+/// truncated to [`MAX_ERROR_BODY_LEN`]
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+fn error_body(body: &[u8]) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    let mut body = body.trim();
+
+    if body.len() > MAX_ERROR_BODY_LEN {
+        let end = (0..=MAX_ERROR_BODY_LEN)
+            .rev()
+            .find(|i| body.is_char_boundary(*i))
+            .unwrap_or(0);
+        body = &body[..end];
+    }
+
+    (!body.is_empty()).then(|| body.to_owned())
+}
+
+/// Response headers that servers and proxies use to identify a request, in
+/// order of preference
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+const CORRELATION_HEADERS: &[&str] = &[
+    "x-debug-tracking-id",
+    "x-guploader-uploadid",
+    "x-request-id",
+    "x-cloud-trace-context",
+];
+
+/// Gets the identifier the server attached to a response, if any
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+pub(crate) fn correlation_id(headers: &http::HeaderMap) -> Option<String> {
+    CORRELATION_HEADERS.iter().find_map(|name| {
+        headers
+            .get(*name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    })
+}
+
+/// Checks if the content type of a response is JSON, regardless of its
+/// parameters, eg. `charset=utf-8`, or case
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+pub(crate) fn is_json(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .and_then(|ct| ct.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// The [error format](https://cloud.google.com/apis/design/errors#http_mapping)
+/// of Google APIs, eg. the IAM credentials API, which nests the error rather
+/// than using the flat OAuth error response
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+#[derive(serde::Deserialize)]
+struct ApiErrorResponse {
+    error: ApiError,
+}
+
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+#[derive(serde::Deserialize)]
+struct ApiError {
+    /// The canonical error code, eg. `PERMISSION_DENIED`
+    status: Option<String>,
+    /// A description of the error
+    message: Option<String>,
+}
+
+/// Creates the error for an unsuccessful response, which is an
+/// [`Error::Auth`] if the body is a JSON
+/// [error response](https://tools.ietf.org/html/rfc6749#section-5.2), or a
+/// Google API error, and an [`Error::HttpStatus`] otherwise
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+pub(crate) fn from_response(parts: &http::response::Parts, body: &[u8]) -> Error {
+    if is_json(&parts.headers) {
+        let auth_error = serde_json::from_slice::<AuthError>(body).ok().or_else(|| {
+            serde_json::from_slice::<ApiErrorResponse>(body)
+                .ok()
+                .map(|res| AuthError {
+                    error: res.error.status,
+                    error_description: res.error.message,
+                    error_uri: None,
+                    correlation_id: None,
+                })
+        });
+
+        if let Some(mut auth_error) = auth_error {
+            auth_error.correlation_id = correlation_id(&parts.headers);
+            return Error::Auth(auth_error);
+        }
+    }
+
+    Error::http_status(parts, body)
+}
+
 #[derive(serde::Deserialize, Debug)]
+#[non_exhaustive]
 pub struct AuthError {
     /// Top level error type
     pub error: Option<String>,
     /// More specific details on the error
     pub error_description: Option<String>,
+    /// A URI of a page with more information about the error
+    pub error_uri: Option<String>,
+    /// The identifier the server attached to the response, if any, eg. the
+    /// `x-debug-tracking-id` or `x-request-id` header. This can be used to
+    /// correlate the failure with server side logs or support tickets.
+    ///
+    /// A request id can be added to the requests created by this crate with
+    /// [`CachedTokenProvider::with_request_id`](crate::token_cache::CachedTokenProvider::with_request_id),
+    /// which some servers and proxies will echo back.
+    #[serde(skip)]
+    pub correlation_id: Option<String>,
+}
+
+impl AuthError {
+    /// Creates the error returned when a provider doesn't support an
+    /// operation, eg. a subject or an intermediate access token
+    pub(crate) fn unsupported(description: &str) -> Self {
+        Self {
+            error: Some("Unsupported".to_owned()),
+            error_description: Some(description.to_owned()),
+            error_uri: None,
+            correlation_id: None,
+        }
+    }
 }
 
 impl fmt::Display for AuthError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Some(ref err) = self.error {
-            write!(f, "{}", err)?;
+        match (&self.error, &self.error_description) {
+            (Some(err), Some(desc)) => write!(f, "{}: {}", err, desc)?,
+            (Some(err), None) => f.write_str(err)?,
+            (None, Some(desc)) => f.write_str(desc)?,
+            (None, None) => f.write_str("Unknown authentication error")?,
+        }
 
-            if let Some(ref desc) = self.error_description {
-                write!(f, "desc: {}", desc)?;
-            }
+        if let Some(ref uri) = self.error_uri {
+            write!(f, " (see {})", uri)?;
         }
 
         Ok(())
@@ -137,3 +417,141 @@ impl fmt::Display for AuthError {
 }
 
 impl std::error::Error for AuthError {}
+
+#[cfg(test)]
+mod test {
+    use super::{AuthError, Error};
+
+    #[cfg(feature = "gcp")]
+    #[test]
+    fn json_content_type() {
+        use super::is_json;
+
+        let headers = |content_type: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_str(content_type).unwrap(),
+            );
+            headers
+        };
+
+        assert!(is_json(&headers("application/json; charset=utf-8")));
+        assert!(is_json(&headers("application/json")));
+        assert!(is_json(&headers("application/json; charset=UTF-8")));
+        assert!(is_json(&headers("Application/JSON;charset=utf-8")));
+        assert!(!is_json(&headers("text/html; charset=utf-8")));
+        assert!(!is_json(&headers("application/jsonp")));
+        assert!(!is_json(&http::HeaderMap::new()));
+    }
+
+    #[cfg(feature = "jwt")]
+    #[test]
+    fn error_body() {
+        assert!(super::error_body(b"").is_none());
+        assert!(super::error_body(b" \n ").is_none());
+        assert_eq!(
+            super::error_body(b"  Bad Gateway\n").as_deref(),
+            Some("Bad Gateway")
+        );
+
+        // Truncated on a char boundary
+        let long = "\u{e9}".repeat(super::MAX_ERROR_BODY_LEN);
+        let truncated = super::error_body(long.as_bytes()).unwrap();
+        assert_eq!(truncated.len(), super::MAX_ERROR_BODY_LEN);
+        assert!(truncated.chars().all(|c| c == '\u{e9}'));
+
+        let error = Error::HttpStatus {
+            status: http::StatusCode::BAD_GATEWAY,
+            correlation_id: Some("abcd".to_owned()),
+            body: Some("<html>Bad Gateway</html>".to_owned()),
+        };
+        assert_eq!(
+            error.to_string(),
+            "HTTP error status: 502 Bad Gateway (correlation id: abcd): <html>Bad Gateway</html>"
+        );
+    }
+
+    #[test]
+    fn retryable() {
+        let status = |status| Error::HttpStatus {
+            status,
+            correlation_id: None,
+            body: None,
+        };
+
+        assert!(status(http::StatusCode::INTERNAL_SERVER_ERROR).is_retryable());
+        assert!(status(http::StatusCode::SERVICE_UNAVAILABLE).is_retryable());
+        assert!(status(http::StatusCode::REQUEST_TIMEOUT).is_retryable());
+        assert!(status(http::StatusCode::TOO_MANY_REQUESTS).is_retryable());
+        assert!(!status(http::StatusCode::UNAUTHORIZED).is_retryable());
+        assert!(!status(http::StatusCode::NOT_FOUND).is_retryable());
+
+        #[cfg(feature = "gcp")]
+        {
+            use std::io::{Error as IoError, ErrorKind};
+
+            assert!(Error::Io(IoError::from(ErrorKind::TimedOut)).is_retryable());
+            assert!(Error::Io(IoError::from(ErrorKind::Interrupted)).is_retryable());
+            assert!(!Error::Io(IoError::from(ErrorKind::NotFound)).is_retryable());
+        }
+
+        assert!(!Error::Auth(AuthError {
+            error: Some("invalid_grant".to_owned()),
+            error_description: None,
+            error_uri: None,
+            correlation_id: None,
+        })
+        .is_retryable());
+        assert!(!Error::InvalidTokenFormat.is_retryable());
+    }
+
+    fn auth_error(error: Option<&str>, desc: Option<&str>, uri: Option<&str>) -> String {
+        AuthError {
+            error: error.map(String::from),
+            error_description: desc.map(String::from),
+            error_uri: uri.map(String::from),
+            correlation_id: None,
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn auth_error_display() {
+        assert_eq!(
+            auth_error(Some("invalid_grant"), Some("Invalid JWT"), None),
+            "invalid_grant: Invalid JWT"
+        );
+        assert_eq!(
+            auth_error(Some("invalid_grant"), None, None),
+            "invalid_grant"
+        );
+        assert_eq!(auth_error(None, Some("Invalid JWT"), None), "Invalid JWT");
+        assert_eq!(auth_error(None, None, None), "Unknown authentication error");
+        assert_eq!(
+            auth_error(
+                Some("invalid_grant"),
+                Some("Invalid JWT"),
+                Some("https://example.com/errors")
+            ),
+            "invalid_grant: Invalid JWT (see https://example.com/errors)"
+        );
+        assert_eq!(
+            auth_error(None, None, Some("https://example.com/errors")),
+            "Unknown authentication error (see https://example.com/errors)"
+        );
+    }
+
+    #[test]
+    fn auth_error_uri() {
+        let auth_error: AuthError = serde_json::from_str(
+            r#"{"error":"invalid_request","error_description":"Missing grant","error_uri":"https://example.com/errors"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            auth_error.error_uri.as_deref(),
+            Some("https://example.com/errors")
+        );
+    }
+}
@@ -4,35 +4,208 @@
 use crate::token_cache::CachedTokenProvider;
 use crate::{error::Error, jwt};
 
+pub mod downscoped;
 pub mod end_user;
+pub mod impersonated_service_account;
 pub mod metadata_server;
 pub mod service_account;
+pub mod sign;
 
 use end_user as eu;
+use impersonated_service_account as isa;
 use metadata_server as ms;
 use service_account as sa;
 
 pub use crate::id_token::{
-    AccessTokenResponse, IdToken, IdTokenOrRequest, IdTokenProvider, IdTokenRequest,
-    IdTokenResponse,
+    drive_id_token, google_jwks_request, AccessTokenResponse, ComputeEngineClaims, IdToken,
+    IdTokenClaims, IdTokenOrRequest, IdTokenProvider, IdTokenRequest, IdTokenResponse,
+    GOOGLE_JWKS_URL,
 };
-pub use crate::token::{Token, TokenOrRequest, TokenProvider};
+pub use crate::oauth2::revoke::{parse_revoke_response, revoke_request, GOOGLE_REVOKE_URL};
+pub use crate::token::{drive_token, Token, TokenOrRequest, TokenProvider};
 pub use {
+    downscoped::{CredentialAccessBoundary, DownscopedTokenProvider},
     end_user::{EndUserCredentials, EndUserCredentialsInfo},
+    impersonated_service_account::{
+        ImpersonatedServiceAccountInfo, ImpersonatedServiceAccountProvider,
+    },
     metadata_server::MetadataServerProvider,
     service_account::{ServiceAccountInfo, ServiceAccountProvider},
 };
 
-/// Both the [`ServiceAccountProvider`] and [`MetadataServerProvider`] get back
-/// JSON responses with this schema from their endpoints.
+/// The domain of the Google Cloud APIs, unless the credentials are for
+/// another universe, eg. Google Distributed Cloud
+pub const DEFAULT_UNIVERSE_DOMAIN: &str = "googleapis.com";
+
+/// The endpoint of the IAM credentials API in the specified universe
+fn iam_endpoint(universe_domain: &str) -> String {
+    format!("https://iamcredentials.{}", universe_domain)
+}
+
+/// The base URL of the service accounts in the IAM credentials API in the
+/// specified universe
+fn iam_credentials_url(universe_domain: &str) -> String {
+    format!(
+        "{}/v1/projects/-/serviceAccounts",
+        iam_endpoint(universe_domain)
+    )
+}
+
+/// The header that attributes quota and billing of a request to a project,
+/// rather than the project of the credentials
+const USER_PROJECT_HEADER: &str = "x-goog-user-project";
+
+/// Sets the [`USER_PROJECT_HEADER`] on a request, if a project is specified
+fn set_user_project(
+    request: &mut http::Request<Vec<u8>>,
+    quota_project_id: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(project) = quota_project_id {
+        let value = http::HeaderValue::from_str(project).map_err(http::Error::from)?;
+        request.headers_mut().insert(USER_PROJECT_HEADER, value);
+    }
+
+    Ok(())
+}
+
+/// Used instead of a path in [`Error::InvalidCredentials`] if the credentials
+/// in `GOOGLE_APPLICATION_CREDENTIALS` are inline JSON
+const INLINE_CREDENTIALS: &str = "<inline JSON in GOOGLE_APPLICATION_CREDENTIALS>";
+
+/// The only part of a credentials file we need to look at to determine how
+/// the rest of it should be deserialized
 #[derive(serde::Deserialize, Debug)]
-struct TokenResponse {
-    /// The actual token
-    access_token: String,
-    /// The token type, pretty much always Header
-    token_type: String,
-    /// The time until the token expires and a new one needs to be requested
-    expires_in: i64,
+struct CredentialsType {
+    #[serde(rename = "type")]
+    cred_type: Option<String>,
+}
+
+/// Checks if the credentials file is for an impersonated service account
+fn is_impersonated_service_account(key_data: &str) -> bool {
+    serde_json::from_str::<CredentialsType>(key_data)
+        .ok()
+        .and_then(|ct| ct.cred_type)
+        .as_deref()
+        == Some("impersonated_service_account")
+}
+
+/// Gets the path to the gcloud `application_default_credentials.json` file,
+/// which is checked by [`TokenProviderWrapper::get_default_provider`] if
+/// `GOOGLE_APPLICATION_CREDENTIALS` is not set.
+///
+/// This respects the `CLOUDSDK_CONFIG` environment variable. If unset, it
+/// looks in the platform-specific gcloud configuration directories. Note that
+/// the file is not guaranteed to exist.
+pub fn default_credentials_path() -> Option<std::path::PathBuf> {
+    credentials_path(|var| std::env::var_os(var), cfg!(windows))
+}
+
+/// Resolves the path of the application default credentials file with the
+/// specified environment, separate from [`default_credentials_path`] so that
+/// the resolution for every platform can be tested
+fn credentials_path(
+    var_os: impl Fn(&str) -> Option<std::ffi::OsString>,
+    windows: bool,
+) -> Option<std::path::PathBuf> {
+    use std::path::PathBuf;
+
+    let cred_file = "application_default_credentials.json";
+
+    // If the user has set CLOUDSDK_CONFIG, that overrides the default
+    // directory. Unlike the platform defaults, it points at the gcloud
+    // configuration directory itself, so `gcloud` is not appended.
+    if let Some(override_dir) = var_os("CLOUDSDK_CONFIG") {
+        let mut pb = PathBuf::from(override_dir);
+        pb.push(cred_file);
+        return Some(pb);
+    }
+
+    // Otherwise, use the default for the platform.
+    // * Windows - %APPDATA%/gcloud/<file>
+    // * Unix - $HOME/.config/gcloud/<file>
+    if windows {
+        var_os("APPDATA").map(PathBuf::from)
+    } else {
+        var_os("HOME").map(|pb| {
+            let mut pb = PathBuf::from(pb);
+            pb.push(".config");
+            pb
+        })
+    }
+    .map(|mut bd| {
+        bd.push("gcloud");
+        bd.push(cred_file);
+        bd
+    })
+}
+
+/// The GCP environment the process is running in, which all provide a
+/// metadata server
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GcpEnvironment {
+    /// Compute Engine, including GKE, or any environment where the metadata
+    /// server is explicitly configured via `GCE_METADATA_HOST` or
+    /// `GCE_METADATA_IP`
+    ComputeEngine,
+    /// A Cloud Run service or job
+    CloudRun,
+    /// Cloud Functions
+    CloudFunctions,
+}
+
+/// Detects the GCP environment the process is running in, without making any
+/// network requests, or `None` if it doesn't appear to be running on GCP.
+///
+/// Cloud Run and Cloud Functions are detected by the environment variables
+/// they set, ie. `K_SERVICE` or `CLOUD_RUN_JOB`, and `FUNCTION_TARGET`. Note
+/// that `K_SERVICE` is also set by Knative outside of GCP. Compute Engine is
+/// detected by the `GCE_METADATA_HOST` or `GCE_METADATA_IP` environment
+/// variables, or, on Linux only, by the DMI product name.
+pub fn detect_environment() -> Option<GcpEnvironment> {
+    environment(
+        |var| std::env::var_os(var),
+        || {
+            // The DMI product name is only available on Linux, on other
+            // platforms we would just fail to read the file
+            if cfg!(target_os = "linux") {
+                std::fs::read_to_string("/sys/class/dmi/id/product_name").ok()
+            } else {
+                None
+            }
+        },
+    )
+}
+
+/// Detects the GCP environment with the specified environment variable lookup
+/// and DMI product name
+fn environment(
+    var_os: impl Fn(&str) -> Option<std::ffi::OsString>,
+    product_name: impl FnOnce() -> Option<String>,
+) -> Option<GcpEnvironment> {
+    let is_set = |var: &str| var_os(var).is_some_and(|value| !value.is_empty());
+
+    // Cloud Functions (2nd gen) also sets `K_SERVICE`, so check it first
+    if is_set("FUNCTION_TARGET") {
+        return Some(GcpEnvironment::CloudFunctions);
+    }
+
+    if is_set("K_SERVICE") || is_set("CLOUD_RUN_JOB") {
+        return Some(GcpEnvironment::CloudRun);
+    }
+
+    // An explicitly configured metadata server is the only signal that works
+    // on every platform
+    if is_set("GCE_METADATA_HOST") || is_set("GCE_METADATA_IP") {
+        return Some(GcpEnvironment::ComputeEngine);
+    }
+
+    product_name()
+        // The product name can annoyingly include a newline...
+        // This matches the Golang client. If new products add additional
+        // values, this will need to be updated.
+        .filter(|full_name| matches!(full_name.trim(), "Google" | "Google Compute Engine"))
+        .map(|_| GcpEnvironment::ComputeEngine)
 }
 
 pub type TokenProviderWrapper = CachedTokenProvider<TokenProviderWrapperInner>;
@@ -41,13 +214,23 @@ impl TokenProviderWrapper {
     /// flow, in order:
     ///
     /// * If the `GOOGLE_APPLICATION_CREDENTIALS` environment variable is
-    ///   set, use that as a path to a [`ServiceAccountInfo`](sa::ServiceAccountInfo).
+    ///   set, use that as a path to a [`ServiceAccountInfo`](sa::ServiceAccountInfo),
+    ///   or as the JSON of the [`ServiceAccountInfo`](sa::ServiceAccountInfo)
+    ///   itself if it starts with `{`.
     ///
     /// * Check for a gcloud's
-    /// [Application Default Credentials](https://cloud.google.com/sdk/gcloud/reference/auth/application-default)
-    /// for [`EndUserCredentials`](eu::EndUserCredentials)
+    ///   [Application Default Credentials](https://cloud.google.com/sdk/gcloud/reference/auth/application-default)
+    ///   for [`EndUserCredentials`](eu::EndUserCredentials)
     ///
-    /// * If we're running on GCP, use the local metadata server.
+    /// * If we're running on GCP, use the local metadata server. This is
+    ///   detected by [`detect_environment`], ie. by the environment variables
+    ///   set by Cloud Run and Cloud Functions, the `GCE_METADATA_HOST` or
+    ///   `GCE_METADATA_IP` environment variables, or, on Linux only, by the
+    ///   DMI product name. On other platforms, eg. Windows instances, one of
+    ///   the environment variables must be set, or a
+    ///   [`MetadataServerProvider`](ms::MetadataServerProvider) created
+    ///   directly, as detecting GCP would require probing the metadata
+    ///   server over the network.
     ///
     /// * Otherwise, return None.
     ///
@@ -59,6 +242,11 @@ impl TokenProviderWrapper {
             .map(|provider| provider.map(CachedTokenProvider::wrap))
     }
 
+    /// See [`TokenProviderWrapperInner::from_value`]
+    pub fn from_value(value: serde_json::Value) -> Result<Self, Error> {
+        TokenProviderWrapperInner::from_value(value).map(CachedTokenProvider::wrap)
+    }
+
     /// Gets the kind of token provider
     pub fn kind(&self) -> &'static str {
         self.inner().kind()
@@ -73,93 +261,138 @@ impl TokenProviderWrapper {
     pub fn is_end_user_credentials_provider(&self) -> bool {
         self.inner().is_end_user_credentials_provider()
     }
+    pub fn is_impersonated_service_account_provider(&self) -> bool {
+        self.inner().is_impersonated_service_account_provider()
+    }
 }
 
 /// Wrapper around the different providers that are supported. Implements both `TokenProvider` and `IdTokenProvider`.
 /// Should not be used directly as it is not cached. Use `TokenProviderWrapper` instead.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum TokenProviderWrapperInner {
     EndUser(eu::EndUserCredentialsInner),
     Metadata(ms::MetadataServerProviderInner),
     ServiceAccount(sa::ServiceAccountProviderInner),
+    Impersonated(Box<isa::ImpersonatedServiceAccountProviderInner<TokenProviderWrapper>>),
 }
 
 impl TokenProviderWrapperInner {
-    /// Get a `TokenProvider` following the "Google Default Credentials" flow.
-    /// Returns a uncached token provider, use `TokenProviderWrapper::get_default_provider`
-    /// instead.
-    pub fn get_default_provider() -> Result<Option<Self>, Error> {
+    /// Creates a provider that impersonates a service account with the
+    /// tokens of the source credentials
+    fn impersonated(info: isa::ImpersonatedServiceAccountInfo) -> Result<Self, Error> {
+        let target_principal = info.target_principal()?.to_owned();
+
+        let universe_domain = match &info.source_credentials {
+            isa::SourceCredentials::ServiceAccount(sa_info) => sa_info.universe_domain(),
+            isa::SourceCredentials::EndUser(_) => DEFAULT_UNIVERSE_DOMAIN,
+        }
+        .to_owned();
+
+        let source = match info.source_credentials {
+            isa::SourceCredentials::ServiceAccount(sa_info) => {
+                Self::ServiceAccount(sa::ServiceAccountProviderInner::new(sa_info)?)
+            }
+            isa::SourceCredentials::EndUser(eu_info) => {
+                Self::EndUser(eu::EndUserCredentialsInner::new(eu_info))
+            }
+        };
+
+        let mut provider = isa::ImpersonatedServiceAccountProviderInner::new(
+            CachedTokenProvider::wrap(source),
+            target_principal,
+            info.delegates,
+        )
+        .with_universe_domain(universe_domain);
+
+        if let Some(project) = info.quota_project_id {
+            provider = provider.with_quota_project_id(project);
+        }
+
+        Ok(Self::Impersonated(Box::new(provider)))
+    }
+
+    /// Creates the provider for the value of `GOOGLE_APPLICATION_CREDENTIALS`,
+    /// separate from [`Self::get_default_provider`] so that it can be tested
+    /// without modifying the process environment. Some CI systems put the
+    /// credentials JSON directly in the variable rather than writing it to
+    /// disk, so if it looks like JSON it is deserialized directly instead of
+    /// being read as a path
+    fn from_credentials_var(cred_var: std::ffi::OsString) -> Result<Self, Error> {
         use std::{fs::read_to_string, path::PathBuf};
 
-        // If the environment variable is present, try to open it as a
-        // Service Account.
-        if let Some(cred_path) = std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
-            let key_data = match read_to_string(&cred_path) {
-                Ok(kd) => kd,
-                Err(e) => {
-                    return Err(Error::InvalidCredentials {
-                        file: cred_path.into(),
-                        error: Box::new(Error::Io(e)),
-                    });
-                }
-            };
+        let inline_json = cred_var
+            .to_str()
+            .filter(|value| value.trim_start().starts_with('{'))
+            .map(String::from);
 
-            let sa_info = match sa::ServiceAccountInfo::deserialize(key_data) {
-                Ok(si) => si,
+        let (cred_path, key_data): (PathBuf, _) = match inline_json {
+            // Don't put the credentials themselves in errors
+            Some(json) => (INLINE_CREDENTIALS.into(), json),
+            None => match read_to_string(&cred_var) {
+                Ok(kd) => (cred_var.into(), kd),
                 Err(e) => {
                     return Err(Error::InvalidCredentials {
-                        file: cred_path.into(),
-                        error: Box::new(e),
+                        file: cred_var.into(),
+                        error: Box::new(Error::Io(e)),
                     });
                 }
-            };
+            },
+        };
 
-            return Ok(Some(TokenProviderWrapperInner::ServiceAccount(
-                sa::ServiceAccountProviderInner::new(sa_info).map_err(|e| {
-                    Error::InvalidCredentials {
-                        file: cred_path.into(),
-                        error: Box::new(e),
-                    }
-                })?,
-            )));
+        if is_impersonated_service_account(&key_data) {
+            return isa::ImpersonatedServiceAccountInfo::deserialize(key_data)
+                .and_then(Self::impersonated)
+                .map_err(|e| Error::InvalidCredentials {
+                    file: cred_path,
+                    error: Box::new(e),
+                });
         }
 
-        /// Get the path to the gcloud `application_default_credentials.json`
-        /// file. This function respects the `CLOUDSDK_CONFIG` environment
-        /// variable. If unset, it looks in the platform-specific gcloud
-        /// configuration directories
-        fn gcloud_config_file() -> Option<PathBuf> {
-            let cred_file = "application_default_credentials.json";
-
-            // If the user has set CLOUDSDK_CONFIG, that overrides the default directory.
-            if let Some(override_dir) = std::env::var_os("CLOUDSDK_CONFIG") {
-                let mut pb = PathBuf::from(override_dir);
-                pb.push(cred_file);
-                return Some(pb);
+        let sa_info = match sa::ServiceAccountInfo::deserialize(key_data) {
+            Ok(si) => si,
+            Err(e) => {
+                return Err(Error::InvalidCredentials {
+                    file: cred_path,
+                    error: Box::new(e),
+                });
             }
+        };
 
-            // Otherwise, use the default for the platform.
-            // * Windows - %APPDATA%/gcloud/<file>
-            // * Unix - $HOME/.config/gcloud/<file>
-            if cfg!(windows) {
-                std::env::var_os("APPDATA").map(PathBuf::from)
-            } else {
-                std::env::var_os("HOME").map(|pb| {
-                    let mut pb = PathBuf::from(pb);
-                    pb.push(".config");
-                    pb
-                })
-            }
-            .map(|mut bd| {
-                bd.push("gcloud");
-                bd.push(cred_file);
-                bd
-            })
+        Ok(TokenProviderWrapperInner::ServiceAccount(
+            sa::ServiceAccountProviderInner::new(sa_info).map_err(|e| {
+                Error::InvalidCredentials {
+                    file: cred_path,
+                    error: Box::new(e),
+                }
+            })?,
+        ))
+    }
+
+    /// Get a `TokenProvider` following the "Google Default Credentials" flow.
+    /// Returns a uncached token provider, use `TokenProviderWrapper::get_default_provider`
+    /// instead.
+    pub fn get_default_provider() -> Result<Option<Self>, Error> {
+        use std::fs::read_to_string;
+
+        // If the environment variable is present, try to open it as a
+        // Service Account
+        if let Some(cred_var) = std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            return Self::from_credentials_var(cred_var).map(Some);
         }
 
-        if let Some(gcloud_file) = gcloud_config_file() {
+        if let Some(gcloud_file) = default_credentials_path() {
             match read_to_string(&gcloud_file) {
                 Ok(json_data) => {
+                    if is_impersonated_service_account(&json_data) {
+                        return isa::ImpersonatedServiceAccountInfo::deserialize(json_data)
+                            .and_then(Self::impersonated)
+                            .map(Some)
+                            .map_err(|e| Error::InvalidCredentials {
+                                file: gcloud_file,
+                                error: Box::new(e),
+                            });
+                    }
+
                     let end_user_credentials = eu::EndUserCredentialsInfo::deserialize(json_data)
                         .map_err(|e| Error::InvalidCredentials {
                         file: gcloud_file,
@@ -181,33 +414,51 @@ impl TokenProviderWrapperInner {
             }
         }
 
-        // Finally, if we are on GCP, use the metadata server. If we're not on
-        // GCP, this will just fail to read the file.
-        if let Ok(full_name) = read_to_string("/sys/class/dmi/id/product_name") {
-            // The product name can annoyingly include a newline...
-            let trimmed = full_name.trim();
-            match trimmed {
-                // This matches the Golang client. If new products
-                // add additional values, this will need to be updated.
-                "Google" | "Google Compute Engine" => {
-                    return Ok(Some(TokenProviderWrapperInner::Metadata(
-                        ms::MetadataServerProviderInner::new(None),
-                    )));
-                }
-                _ => {}
-            }
+        // Finally, if we are on GCP, use the metadata server
+        if detect_environment().is_some() {
+            return Ok(Some(TokenProviderWrapperInner::Metadata(
+                ms::MetadataServerProviderInner::new(None),
+            )));
         }
 
         // None of our checks worked. Give up.
         Ok(None)
     }
 
+    /// Creates a provider from already parsed credentials JSON, eg. as
+    /// returned by a secrets API, depending on its `type`, ie.
+    /// `service_account`, `authorized_user` or `impersonated_service_account`.
+    ///
+    /// Unlike `GOOGLE_APPLICATION_CREDENTIALS`, which is assumed to be a
+    /// service account, credentials of any other or no type return
+    /// [`Error::UnsupportedCredentials`].
+    pub fn from_value(value: serde_json::Value) -> Result<Self, Error> {
+        let cred_type = value
+            .get("type")
+            .and_then(|cred_type| cred_type.as_str())
+            .map(String::from);
+
+        match cred_type.as_deref() {
+            Some("service_account") => Ok(Self::ServiceAccount(
+                sa::ServiceAccountProviderInner::new(sa::ServiceAccountInfo::from_value(value)?)?,
+            )),
+            Some("authorized_user") => Ok(Self::EndUser(eu::EndUserCredentialsInner::new(
+                eu::EndUserCredentialsInfo::from_value(value)?,
+            ))),
+            Some("impersonated_service_account") => {
+                Self::impersonated(isa::ImpersonatedServiceAccountInfo::from_value(value)?)
+            }
+            _ => Err(Error::UnsupportedCredentials(cred_type)),
+        }
+    }
+
     /// Gets the kind of token provider
     pub fn kind(&self) -> &'static str {
         match self {
             Self::EndUser(_) => "End User",
             Self::Metadata(_) => "Metadata Server",
             Self::ServiceAccount(_) => "Service Account",
+            Self::Impersonated(_) => "Impersonated Service Account",
         }
     }
 
@@ -220,9 +471,30 @@ impl TokenProviderWrapperInner {
     pub fn is_end_user_credentials_provider(&self) -> bool {
         matches!(self, TokenProviderWrapperInner::EndUser(_))
     }
+    pub fn is_impersonated_service_account_provider(&self) -> bool {
+        matches!(self, TokenProviderWrapperInner::Impersonated(_))
+    }
 }
 
 impl TokenProvider for TokenProviderWrapperInner {
+    fn supports_subject(&self) -> bool {
+        match self {
+            Self::EndUser(token_provider) => token_provider.supports_subject(),
+            Self::Metadata(token_provider) => token_provider.supports_subject(),
+            Self::ServiceAccount(token_provider) => token_provider.supports_subject(),
+            Self::Impersonated(token_provider) => token_provider.supports_subject(),
+        }
+    }
+
+    fn supports_id_tokens(&self) -> bool {
+        match self {
+            Self::EndUser(token_provider) => token_provider.supports_id_tokens(),
+            Self::Metadata(token_provider) => token_provider.supports_id_tokens(),
+            Self::ServiceAccount(token_provider) => token_provider.supports_id_tokens(),
+            Self::Impersonated(token_provider) => token_provider.supports_id_tokens(),
+        }
+    }
+
     fn get_token_with_subject<'a, S, I, T>(
         &self,
         subject: Option<T>,
@@ -241,6 +513,35 @@ impl TokenProvider for TokenProviderWrapperInner {
             Self::ServiceAccount(token_provider) => {
                 token_provider.get_token_with_subject(subject, scopes)
             }
+            Self::Impersonated(token_provider) => {
+                token_provider.get_token_with_subject(subject, scopes)
+            }
+        }
+    }
+
+    fn get_token_with_access_token<'a, S, I, R>(
+        &self,
+        scopes: I,
+        response: AccessTokenResponse<R>,
+    ) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        R: AsRef<[u8]>,
+    {
+        match self {
+            Self::EndUser(token_provider) => {
+                token_provider.get_token_with_access_token(scopes, response)
+            }
+            Self::Metadata(token_provider) => {
+                token_provider.get_token_with_access_token(scopes, response)
+            }
+            Self::ServiceAccount(token_provider) => {
+                token_provider.get_token_with_access_token(scopes, response)
+            }
+            Self::Impersonated(token_provider) => {
+                token_provider.get_token_with_access_token(scopes, response)
+            }
         }
     }
 
@@ -258,6 +559,9 @@ impl TokenProvider for TokenProviderWrapperInner {
             Self::ServiceAccount(token_provider) => {
                 token_provider.parse_token_response(hash, response)
             }
+            Self::Impersonated(token_provider) => {
+                token_provider.parse_token_response(hash, response)
+            }
         }
     }
 }
@@ -268,6 +572,7 @@ impl IdTokenProvider for TokenProviderWrapperInner {
             Self::EndUser(token_provider) => token_provider.get_id_token(audience),
             Self::Metadata(token_provider) => token_provider.get_id_token(audience),
             Self::ServiceAccount(token_provider) => token_provider.get_id_token(audience),
+            Self::Impersonated(token_provider) => token_provider.get_id_token(audience),
         }
     }
 
@@ -289,6 +594,9 @@ impl IdTokenProvider for TokenProviderWrapperInner {
             Self::ServiceAccount(token_provider) => {
                 token_provider.get_id_token_with_access_token(audience, response)
             }
+            Self::Impersonated(token_provider) => {
+                token_provider.get_id_token_with_access_token(audience, response)
+            }
         }
     }
 
@@ -308,19 +616,338 @@ impl IdTokenProvider for TokenProviderWrapperInner {
             Self::ServiceAccount(token_provider) => {
                 token_provider.parse_id_token_response(hash, response)
             }
+            Self::Impersonated(token_provider) => {
+                token_provider.parse_id_token_response(hash, response)
+            }
         }
     }
 }
 
-impl From<TokenResponse> for Token {
-    fn from(tr: TokenResponse) -> Self {
-        Self {
-            access_token: tr.access_token,
-            token_type: tr.token_type,
-            refresh_token: String::new(),
-            expires_in: Some(tr.expires_in),
-            expires_in_timestamp: std::time::SystemTime::now()
-                .checked_add(std::time::Duration::from_secs(tr.expires_in as u64)),
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsString;
+
+    /// A fake environment with the specified variables
+    fn env(
+        vars: &'static [(&'static str, &'static str)],
+    ) -> impl Fn(&str) -> Option<OsString> + Copy {
+        move |var: &str| {
+            vars.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| OsString::from(value))
+        }
+    }
+
+    #[test]
+    fn environments() {
+        let no_dmi = || None;
+
+        assert_eq!(environment(env(&[]), no_dmi), None);
+        assert_eq!(
+            environment(env(&[("K_SERVICE", "my-service")]), no_dmi),
+            Some(GcpEnvironment::CloudRun)
+        );
+        assert_eq!(
+            environment(env(&[("CLOUD_RUN_JOB", "my-job")]), no_dmi),
+            Some(GcpEnvironment::CloudRun)
+        );
+        assert_eq!(
+            environment(
+                env(&[("K_SERVICE", "my-function"), ("FUNCTION_TARGET", "entry")]),
+                no_dmi
+            ),
+            Some(GcpEnvironment::CloudFunctions)
+        );
+        assert_eq!(
+            environment(env(&[("GCE_METADATA_HOST", "localhost:8080")]), no_dmi),
+            Some(GcpEnvironment::ComputeEngine)
+        );
+        assert_eq!(environment(env(&[("K_SERVICE", "")]), no_dmi), None);
+
+        assert_eq!(
+            environment(env(&[]), || Some("Google Compute Engine\n".to_owned())),
+            Some(GcpEnvironment::ComputeEngine)
+        );
+        assert_eq!(
+            environment(env(&[]), || Some("VirtualBox\n".to_owned())),
+            None
+        );
+    }
+
+    #[test]
+    fn credentials_paths() {
+        use std::path::PathBuf;
+
+        let expected = |parts: &[&str]| parts.iter().collect::<PathBuf>();
+
+        let all = env(&[
+            ("CLOUDSDK_CONFIG", "/custom/config"),
+            ("APPDATA", "C:\\Users\\me\\AppData\\Roaming"),
+            ("HOME", "/home/me"),
+        ]);
+
+        // CLOUDSDK_CONFIG is the gcloud directory itself and takes
+        // precedence on every platform
+        for windows in [false, true] {
+            assert_eq!(
+                credentials_path(all, windows),
+                Some(expected(&[
+                    "/custom/config",
+                    "application_default_credentials.json"
+                ]))
+            );
+        }
+
+        let defaults = env(&[
+            ("APPDATA", "C:\\Users\\me\\AppData\\Roaming"),
+            ("HOME", "/home/me"),
+        ]);
+
+        assert_eq!(
+            credentials_path(defaults, true),
+            Some(expected(&[
+                "C:\\Users\\me\\AppData\\Roaming",
+                "gcloud",
+                "application_default_credentials.json"
+            ]))
+        );
+        assert_eq!(
+            credentials_path(defaults, false),
+            Some(expected(&[
+                "/home/me",
+                ".config",
+                "gcloud",
+                "application_default_credentials.json"
+            ]))
+        );
+
+        assert_eq!(credentials_path(env(&[]), true), None);
+        assert_eq!(credentials_path(env(&[]), false), None);
+    }
+
+    #[test]
+    fn expires_in() {
+        use crate::token_cache::CacheableToken;
+
+        // Google's token responses follow the same `expires_in` policy as the
+        // generic OAuth2 flows
+        let provider = ms::MetadataServerProviderInner::new(None);
+        let parse = |expires_in: i64| {
+            provider.parse_token_response(
+                0,
+                http::Response::new(format!(
+                    r#"{{"access_token":"access-token","expires_in":{}}}"#,
+                    expires_in
+                )),
+            )
+        };
+
+        assert!(parse(0).unwrap().has_expired());
+        assert!(parse(-1).unwrap().has_expired());
+        assert!(!parse(3599).unwrap().has_expired());
+        assert!(matches!(
+            parse(i64::MAX),
+            Err(Error::InvalidExpiresIn(i64::MAX))
+        ));
+    }
+
+    /// Creates a request with each of the uncached providers and standalone
+    /// request builders, which set the common headers themselves
+    fn builder_requests() -> Vec<http::Request<Vec<u8>>> {
+        let info = ServiceAccountInfo::deserialize(include_str!("../tests/svc_key.json")).unwrap();
+        let service_account = sa::ServiceAccountProviderInner::new(info).unwrap();
+        let metadata = ms::MetadataServerProviderInner::new(None);
+        let source_token = Token::new_bearer("source-token", std::time::Duration::from_secs(3599));
+
+        let token_request = |token_or_request| match token_or_request {
+            TokenOrRequest::Request { request, .. } => request,
+            other => panic!("expected a request, got {:?}", other),
+        };
+
+        vec![
+            token_request(service_account.get_token(&["scope"]).unwrap()),
+            token_request(metadata.get_token(&["scope"]).unwrap()),
+            metadata.universe_domain_request().unwrap(),
+            metadata.email_request().unwrap(),
+            metadata.scopes_request().unwrap(),
+            sign::sign_jwt_request(
+                &source_token,
+                DEFAULT_UNIVERSE_DOMAIN,
+                "signer@project.iam.gserviceaccount.com",
+                &serde_json::json!({}),
+            )
+            .unwrap(),
+            sign::sign_blob_request(
+                &source_token,
+                DEFAULT_UNIVERSE_DOMAIN,
+                "signer@project.iam.gserviceaccount.com",
+                b"blob",
+            )
+            .unwrap(),
+            google_jwks_request().unwrap(),
+            revoke_request("token", GOOGLE_REVOKE_URL).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn user_agent() {
+        for request in builder_requests() {
+            assert_eq!(
+                request.headers()[http::header::USER_AGENT],
+                crate::token_cache::DEFAULT_USER_AGENT,
+                "{}",
+                request.uri()
+            );
         }
     }
+
+    #[test]
+    fn accept_encoding() {
+        for request in builder_requests() {
+            assert_eq!(
+                request.headers()[http::header::ACCEPT_ENCODING],
+                "identity",
+                "{}",
+                request.uri()
+            );
+        }
+    }
+
+    #[test]
+    fn capabilities() {
+        let info = ServiceAccountInfo::deserialize(include_str!("../tests/svc_key.json")).unwrap();
+
+        let provider = CachedTokenProvider::wrap(TokenProviderWrapperInner::ServiceAccount(
+            sa::ServiceAccountProviderInner::new(info.clone()).unwrap(),
+        ));
+        assert!(provider.supports_subject());
+        assert!(provider.supports_id_tokens());
+
+        let provider = CachedTokenProvider::wrap(TokenProviderWrapperInner::Metadata(
+            ms::MetadataServerProviderInner::new(None),
+        ));
+        assert!(!provider.supports_subject());
+        assert!(provider.supports_id_tokens());
+
+        let source = ServiceAccountProvider::new(info).unwrap();
+        let provider = DownscopedTokenProvider::new(
+            source,
+            CredentialAccessBoundary {
+                access_boundary_rules: Vec::new(),
+            },
+        );
+        assert!(!provider.supports_subject());
+        assert!(!provider.supports_id_tokens());
+    }
+
+    #[test]
+    fn impersonated_default_provider() {
+        let mut info: serde_json::Value = serde_json::json!({
+            "type": "impersonated_service_account",
+            "delegates": [],
+            "quota_project_id": "quota-project",
+            "service_account_impersonation_url": "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/target@project.iam.gserviceaccount.com:generateAccessToken",
+        });
+        info["source_credentials"] =
+            serde_json::from_str(include_str!("../tests/svc_key.json")).unwrap();
+        let info = info.to_string();
+
+        assert!(is_impersonated_service_account(&info));
+        assert!(!is_impersonated_service_account(include_str!(
+            "../tests/svc_key.json"
+        )));
+
+        let provider = CachedTokenProvider::wrap(
+            TokenProviderWrapperInner::impersonated(
+                isa::ImpersonatedServiceAccountInfo::deserialize(info).unwrap(),
+            )
+            .unwrap(),
+        );
+        assert!(provider.is_impersonated_service_account_provider());
+
+        let scopes = ["https://www.googleapis.com/auth/devstorage.read_only"];
+
+        // The source service account token is requested first...
+        let request = match provider.get_token(&scopes).unwrap() {
+            TokenOrRequest::AccessTokenRequest { request, .. } => request,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(request.uri(), "https://accounts.google.com/o/oauth2/token");
+
+        // ...and then exchanged for the impersonated token
+        let request = provider
+            .get_token_with_access_token(
+                &scopes,
+                http::Response::new(
+                    r#"{"access_token":"source-token","token_type":"Bearer","expires_in":3599}"#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            request.uri(),
+            "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/target@project.iam.gserviceaccount.com:generateAccessToken"
+        );
+        assert_eq!(request.headers()[USER_PROJECT_HEADER], "quota-project");
+    }
+
+    #[test]
+    fn inline_credentials() {
+        let provider = TokenProviderWrapperInner::from_credentials_var(
+            include_str!("../tests/svc_key.json").into(),
+        )
+        .unwrap();
+        assert!(matches!(
+            provider,
+            TokenProviderWrapperInner::ServiceAccount(_)
+        ));
+
+        match TokenProviderWrapperInner::from_credentials_var(r#"{"type":"service_account"#.into())
+        {
+            Err(Error::InvalidCredentials { file, error }) => {
+                assert_eq!(file.to_str(), Some(INLINE_CREDENTIALS));
+                assert!(matches!(*error, Error::Json(_)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_value() {
+        let sa: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/svc_key.json")).unwrap();
+        let provider = TokenProviderWrapper::from_value(sa.clone()).unwrap();
+        assert!(provider.is_service_account_provider());
+
+        let provider = TokenProviderWrapper::from_value(serde_json::json!({
+            "type": "authorized_user",
+            "client_id": "client",
+            "client_secret": "secret",
+            "refresh_token": "refresh",
+        }))
+        .unwrap();
+        assert!(provider.is_end_user_credentials_provider());
+
+        let provider = TokenProviderWrapper::from_value(serde_json::json!({
+            "type": "impersonated_service_account",
+            "delegates": [],
+            "service_account_impersonation_url": "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/target@project.iam.gserviceaccount.com:generateAccessToken",
+            "source_credentials": sa,
+        }))
+        .unwrap();
+        assert!(provider.is_impersonated_service_account_provider());
+
+        assert!(matches!(
+            TokenProviderWrapper::from_value(serde_json::json!({ "type": "external_account" })),
+            Err(Error::UnsupportedCredentials(Some(cred_type))) if cred_type == "external_account"
+        ));
+        assert!(matches!(
+            TokenProviderWrapper::from_value(serde_json::json!({})),
+            Err(Error::UnsupportedCredentials(None))
+        ));
+        assert!(matches!(
+            TokenProviderWrapper::from_value(serde_json::json!({ "type": "service_account" })),
+            Err(Error::Json(_))
+        ));
+    }
 }
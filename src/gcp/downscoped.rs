@@ -0,0 +1,319 @@
+use super::DEFAULT_UNIVERSE_DOMAIN;
+use crate::{
+    error::{self, Error},
+    id_token::AccessTokenResponse,
+    oauth2::TokenResponse,
+    token::{RequestReason, Token, TokenOrRequest, TokenProvider},
+    token_cache::{hash_scopes, CachedTokenProvider},
+};
+
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// A [Credential Access Boundary](https://cloud.google.com/iam/docs/downscoping-short-lived-credentials#define-boundary)
+/// that restricts the resources and permissions a downscoped token has access
+/// to.
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialAccessBoundary {
+    /// The rules of the boundary, a downscoped token has access to the union
+    /// of the rules
+    pub access_boundary_rules: Vec<AccessBoundaryRule>,
+}
+
+/// A single rule in a [`CredentialAccessBoundary`]
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessBoundaryRule {
+    /// The full resource name of the resource the rule applies to, eg.
+    /// `//storage.googleapis.com/projects/_/buckets/my-bucket`
+    pub available_resource: String,
+    /// The IAM roles that define the upper bound of the available permissions,
+    /// eg. `inRole:roles/storage.objectViewer`
+    pub available_permissions: Vec<String>,
+    /// An optional condition that further restricts the permissions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_condition: Option<AvailabilityCondition>,
+}
+
+/// A [CEL](https://cloud.google.com/iam/docs/conditions-overview#cel) condition
+/// that restricts an [`AccessBoundaryRule`]
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct AvailabilityCondition {
+    /// The condition expression, eg. `resource.name.startsWith('projects/_/buckets/my-bucket/objects/prefix')`
+    pub expression: String,
+    /// An optional short title for the condition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// An optional description of the condition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Provides [downscoped](https://cloud.google.com/iam/docs/downscoping-short-lived-credentials)
+/// tokens, ie. tokens of another token source that are restricted by a
+/// [`CredentialAccessBoundary`].
+/// Caches tokens internally.
+pub type DownscopedTokenProvider<P> = CachedTokenProvider<DownscopedTokenProviderInner<P>>;
+impl<P> DownscopedTokenProvider<P> {
+    pub fn new(source: P, boundary: CredentialAccessBoundary) -> Self {
+        CachedTokenProvider::wrap(DownscopedTokenProviderInner::new(source, boundary))
+    }
+
+    /// See [`DownscopedTokenProviderInner::with_universe_domain`]
+    pub fn with_universe_domain(mut self, universe_domain: impl Into<String>) -> Self {
+        self.inner_mut().universe_domain = universe_domain.into();
+        self
+    }
+}
+
+/// Provides downscoped tokens. Should not be used directly as it is not
+/// cached. Use `DownscopedTokenProvider` instead.
+///
+/// The source token provider should usually be cached as well, otherwise a new
+/// source token is requested every time a downscoped token is needed.
+#[derive(Debug, Clone)]
+pub struct DownscopedTokenProviderInner<P> {
+    source: P,
+    boundary: CredentialAccessBoundary,
+    universe_domain: String,
+}
+
+impl<P> DownscopedTokenProviderInner<P> {
+    pub fn new(source: P, boundary: CredentialAccessBoundary) -> Self {
+        Self {
+            source,
+            boundary,
+            universe_domain: DEFAULT_UNIVERSE_DOMAIN.to_owned(),
+        }
+    }
+
+    /// Overrides the universe of the Security Token Service the source tokens
+    /// are exchanged with, which defaults to [`DEFAULT_UNIVERSE_DOMAIN`]
+    pub fn with_universe_domain(mut self, universe_domain: impl Into<String>) -> Self {
+        self.universe_domain = universe_domain.into();
+        self
+    }
+
+    /// Gets the universe of the Security Token Service
+    pub fn universe_domain(&self) -> &str {
+        &self.universe_domain
+    }
+
+    /// The token endpoint of the Security Token Service
+    fn sts_url(&self) -> String {
+        format!("https://sts.{}/v1/token", self.universe_domain)
+    }
+
+    /// Gets the token provider that source tokens are retrieved from
+    pub fn source(&self) -> &P {
+        &self.source
+    }
+
+    /// Exchanges the source token for a downscoped one via the
+    /// [Security Token Service](https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token)
+    fn prepare_token_request(&self, source_token: &Token) -> Result<http::Request<Vec<u8>>, Error> {
+        let options = serde_json::to_string(&serde_json::json!({
+            "accessBoundary": &self.boundary,
+        }))?;
+
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", GRANT_TYPE)
+            .append_pair("subject_token_type", ACCESS_TOKEN_TYPE)
+            .append_pair("requested_token_type", ACCESS_TOKEN_TYPE)
+            .append_pair("subject_token", &source_token.access_token)
+            .append_pair("options", &options)
+            .finish();
+
+        let body = Vec::from(body);
+
+        let request = crate::token::request_builder()
+            .method("POST")
+            .uri(self.sts_url())
+            .header(
+                http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .body(body)?;
+
+        Ok(request)
+    }
+}
+
+impl<P> TokenProvider for DownscopedTokenProviderInner<P>
+where
+    P: TokenProvider,
+{
+    /// The scopes are used when requesting the source token, the downscoped
+    /// token is restricted further by the access boundary.
+    fn get_token_with_subject<'a, S, I, T>(
+        &self,
+        subject: Option<T>,
+        scopes: I,
+    ) -> Result<TokenOrRequest, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        T: Into<String>,
+    {
+        let scope_hash = hash_scopes(&scopes);
+
+        // We can only support subject being none
+        if subject.is_some() {
+            return Err(Error::Auth(error::AuthError::unsupported(
+                "Downscoped tokens do not support jwt subjects",
+            )));
+        }
+
+        match self.source.get_token(scopes)? {
+            TokenOrRequest::Token(source_token) => Ok(TokenOrRequest::Request {
+                request: self.prepare_token_request(&source_token)?,
+                reason: RequestReason::ParametersChanged,
+                scope_hash,
+            }),
+            TokenOrRequest::Request {
+                request, reason, ..
+            } => Ok(TokenOrRequest::AccessTokenRequest {
+                request,
+                reason,
+                scope_hash,
+            }),
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                Err(Error::Auth(error::AuthError::unsupported(
+                    "Source token providers that need an intermediate access token are not supported",
+                )))
+            }
+        }
+    }
+
+    fn get_token_with_access_token<'a, S, I, R>(
+        &self,
+        scopes: I,
+        response: AccessTokenResponse<R>,
+    ) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        R: AsRef<[u8]>,
+    {
+        // Let the source provider parse (and potentially cache) its token
+        let source_token = self
+            .source
+            .parse_token_response(hash_scopes(&scopes), response)?;
+
+        self.prepare_token_request(&source_token)
+    }
+
+    fn parse_token_response<S>(
+        &self,
+        _hash: u64,
+        response: http::Response<S>,
+    ) -> Result<Token, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let (parts, body) = response.into_parts();
+
+        if !parts.status.is_success() {
+            return Err(error::from_response(&parts, body.as_ref()));
+        }
+
+        let token_res: TokenResponse = serde_json::from_slice(body.as_ref())?;
+
+        let token = token_res.into_token(None)?;
+
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gcp::{ServiceAccountInfo, ServiceAccountProvider};
+
+    fn boundary() -> CredentialAccessBoundary {
+        CredentialAccessBoundary {
+            access_boundary_rules: vec![AccessBoundaryRule {
+                available_resource: "//storage.googleapis.com/projects/_/buckets/my-bucket"
+                    .to_owned(),
+                available_permissions: vec!["inRole:roles/storage.objectViewer".to_owned()],
+                availability_condition: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn downscoped_token() {
+        let info =
+            ServiceAccountInfo::deserialize(include_str!("../../tests/svc_key.json")).unwrap();
+        let source = ServiceAccountProvider::new(info).unwrap();
+        let provider = DownscopedTokenProvider::new(source, boundary());
+
+        let scopes = ["https://www.googleapis.com/auth/cloud-platform"];
+
+        // We don't have a source token yet, so that needs to be requested first
+        let request = match provider.get_token(&scopes).unwrap() {
+            TokenOrRequest::AccessTokenRequest { request, .. } => request,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(request.uri(), "https://accounts.google.com/o/oauth2/token");
+
+        let source_response = http::Response::new(
+            r#"{"access_token":"source-token","token_type":"Bearer","expires_in":3599}"#,
+        );
+        let request = provider
+            .get_token_with_access_token(&scopes, source_response)
+            .unwrap();
+        assert_eq!(request.uri(), "https://sts.googleapis.com/v1/token");
+
+        let body: std::collections::HashMap<String, String> =
+            url::form_urlencoded::parse(request.body())
+                .into_owned()
+                .collect();
+        assert_eq!(body["grant_type"], GRANT_TYPE);
+        assert_eq!(body["subject_token"], "source-token");
+
+        let options: serde_json::Value = serde_json::from_str(&body["options"]).unwrap();
+        assert_eq!(
+            options["accessBoundary"]["accessBoundaryRules"][0]["availablePermissions"][0],
+            "inRole:roles/storage.objectViewer"
+        );
+
+        // The source token is now cached, so the downscoped token can be
+        // requested directly
+        let scope_hash = match provider.get_token(&scopes).unwrap() {
+            TokenOrRequest::Request {
+                request,
+                scope_hash,
+                ..
+            } => {
+                assert_eq!(request.uri(), "https://sts.googleapis.com/v1/token");
+                scope_hash
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let response = http::Response::new(
+            r#"{"access_token":"downscoped-token","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3599}"#,
+        );
+        let token = provider.parse_token_response(scope_hash, response).unwrap();
+        assert_eq!(token.access_token, "downscoped-token");
+
+        assert!(matches!(
+            provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(token) if token.access_token == "downscoped-token"
+        ));
+    }
+
+    #[test]
+    fn universe_domain() {
+        let provider = DownscopedTokenProviderInner::new((), boundary());
+        assert_eq!(provider.universe_domain(), DEFAULT_UNIVERSE_DOMAIN);
+
+        let source_token = Token::new_bearer("source-token", std::time::Duration::from_secs(3599));
+        let provider = provider.with_universe_domain("example-universe.goog");
+        let request = provider.prepare_token_request(&source_token).unwrap();
+        assert_eq!(request.uri(), "https://sts.example-universe.goog/v1/token");
+    }
+}
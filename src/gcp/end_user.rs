@@ -1,14 +1,17 @@
-use super::TokenResponse;
 use crate::{
     error::{self, Error},
     id_token::{
         AccessTokenResponse, IdTokenOrRequest, IdTokenProvider, IdTokenRequest, IdTokenResponse,
     },
+    oauth2::refresh_token::{RefreshTokenInfo, RefreshTokenProviderInner},
     token::{RequestReason, Token, TokenOrRequest, TokenProvider},
-    token_cache::CachedTokenProvider,
+    token_cache::{hash_scopes, hash_str, CachedTokenProvider},
     IdToken,
 };
 
+/// The token endpoint the refresh token is exchanged at
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
 /// Provides tokens using
 /// [default application credentials](https://cloud.google.com/sdk/gcloud/reference/auth/application-default)
 /// Caches tokens internally.
@@ -17,21 +20,35 @@ impl EndUserCredentials {
     pub fn new(info: EndUserCredentialsInfo) -> Self {
         CachedTokenProvider::wrap(EndUserCredentialsInner::new(info))
     }
+
+    /// See [`EndUserCredentialsInner::with_quota_project_id`]
+    pub fn with_quota_project_id(mut self, project: impl Into<String>) -> Self {
+        self.inner_mut().quota_project_id = Some(project.into());
+        self
+    }
 }
 
 /// Provides tokens using
 /// [default application credentials](https://cloud.google.com/sdk/gcloud/reference/auth/application-default)
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct EndUserCredentialsInfo {
-    /// The OAuth2 client_id
+    /// The `OAuth2` `client_id`
     pub client_id: String,
-    /// The OAuth2 client_secret
+    /// The `OAuth2` `client_secret`
     pub client_secret: String,
-    /// The OAuth2 refresh_token
+    /// The `OAuth2` `refresh_token`
     pub refresh_token: String,
-    /// The client type (the value must be authorized_user)
+    /// The client type (the value must be `authorized_user`)
     #[serde(rename = "type")]
     pub client_type: String,
+    /// The project that quota and billing is attributed to, if it isn't the
+    /// project of the `OAuth2` client
+    #[serde(default)]
+    pub quota_project_id: Option<String>,
+    /// The URI tokens are requested from, only present in some credentials,
+    /// defaults to <https://oauth2.googleapis.com/token>
+    #[serde(default)]
+    pub token_uri: Option<String>,
 }
 
 impl EndUserCredentialsInfo {
@@ -47,13 +64,22 @@ impl EndUserCredentialsInfo {
         let account_info: Self = serde_json::from_slice(slice)?;
         Ok(account_info)
     }
+
+    /// Deserializes the `EndUserCredentials` from already parsed JSON, eg. as
+    /// returned by a secrets API
+    pub fn from_value(value: serde_json::Value) -> Result<Self, Error> {
+        Ok(serde_json::from_value(value)?)
+    }
 }
 
 /// A token provider for
 /// [default application credentials](https://cloud.google.com/sdk/gcloud/reference/auth/application-default)
 /// Should not be used directly as it is not cached. Use `EndUserCredentials` instead.
+#[derive(Clone)]
 pub struct EndUserCredentialsInner {
-    info: EndUserCredentialsInfo,
+    /// The generic refresh token grant, pointed at Google's token endpoint
+    refresh: RefreshTokenProviderInner,
+    quota_project_id: Option<String>,
 }
 
 impl std::fmt::Debug for EndUserCredentialsInner {
@@ -65,7 +91,33 @@ impl std::fmt::Debug for EndUserCredentialsInner {
 
 impl EndUserCredentialsInner {
     pub fn new(info: EndUserCredentialsInfo) -> Self {
-        Self { info }
+        Self {
+            refresh: RefreshTokenProviderInner::new(RefreshTokenInfo {
+                token_url: info.token_uri.unwrap_or_else(|| TOKEN_URL.to_owned()),
+                client_id: info.client_id,
+                client_secret: info.client_secret,
+                refresh_token: info.refresh_token,
+            }),
+            quota_project_id: info.quota_project_id,
+        }
+    }
+
+    /// Sets the project that quota and billing is attributed to, via the
+    /// `x-goog-user-project` header, which some APIs require for end user
+    /// credentials. Defaults to the `quota_project_id` of the credentials, if
+    /// any.
+    ///
+    /// The header is added to the requests created by this provider, but
+    /// since it applies to all API requests, you will typically need to add
+    /// it to your own requests as well, see [`Self::quota_project_id`].
+    pub fn with_quota_project_id(mut self, project: impl Into<String>) -> Self {
+        self.quota_project_id = Some(project.into());
+        self
+    }
+
+    /// Gets the project that quota and billing is attributed to, if set
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
     }
 }
 
@@ -83,33 +135,18 @@ impl EndUserCredentialsInner {
         // (i.e., POST our client data as a refresh_token request to
         // the /token endpoint).
         // The response will include both a access token and a id token
-        let url = "https://oauth2.googleapis.com/token";
-
-        // Build up the parameters as a form encoded string.
-        let body = url::form_urlencoded::Serializer::new(String::new())
-            .append_pair("client_id", &self.info.client_id)
-            .append_pair("client_secret", &self.info.client_secret)
-            .append_pair("grant_type", "refresh_token")
-            .append_pair("refresh_token", &self.info.refresh_token)
-            .finish();
-
-        let body = Vec::from(body);
-
-        let request = http::Request::builder()
-            .method("POST")
-            .uri(url)
-            .header(
-                http::header::CONTENT_TYPE,
-                "application/x-www-form-urlencoded",
-            )
-            .header(http::header::CONTENT_LENGTH, body.len())
-            .body(body)?;
+        let mut request = self.refresh.prepare_token_request()?;
+        super::set_user_project(&mut request, self.quota_project_id())?;
 
         Ok(request)
     }
 }
 
 impl TokenProvider for EndUserCredentialsInner {
+    fn supports_id_tokens(&self) -> bool {
+        true
+    }
+
     fn get_token_with_subject<'a, S, I, T>(
         &self,
         subject: Option<T>,
@@ -119,21 +156,20 @@ impl TokenProvider for EndUserCredentialsInner {
         // application-default login` will get the
         // https://www.googleapis.com/auth/cloud-platform which
         // includes all *GCP* APIs.
-        _scopes: I,
+        scopes: I,
     ) -> Result<TokenOrRequest, Error>
     where
         S: AsRef<str> + 'a,
-        I: IntoIterator<Item = &'a S>,
+        I: IntoIterator<Item = &'a S> + Clone,
         T: Into<String>,
     {
+        let scope_hash = hash_scopes(&scopes);
+
         // We can only support subject being none
         if subject.is_some() {
-            return Err(Error::Auth(error::AuthError {
-                error: Some("Unsupported".to_string()),
-                error_description: Some(
-                    "ADC / User tokens do not support jwt subjects".to_string(),
-                ),
-            }));
+            return Err(Error::Auth(error::AuthError::unsupported(
+                "ADC / User tokens do not support jwt subjects",
+            )));
         }
 
         let request = self.prepare_token_request()?;
@@ -141,45 +177,33 @@ impl TokenProvider for EndUserCredentialsInner {
         Ok(TokenOrRequest::Request {
             request,
             reason: RequestReason::ParametersChanged,
-            scope_hash: 0,
+            scope_hash,
         })
     }
 
     fn parse_token_response<S>(
         &self,
-        _hash: u64,
+        hash: u64,
         response: http::Response<S>,
     ) -> Result<Token, Error>
     where
         S: AsRef<[u8]>,
     {
-        let (parts, body) = response.into_parts();
-
-        if !parts.status.is_success() {
-            return Err(Error::HttpStatus(parts.status));
-        }
-
-        // Deserialize our response, or fail.
-        let token_res: TokenResponse = serde_json::from_slice(body.as_ref())?;
-
-        // TODO(boulos): The response also includes the set of scopes
-        // (as "scope") that we're granted. We could check that
-        // cloud-platform is in it.
-
-        // Convert it into our output.
-        let token: Token = token_res.into();
-        Ok(token)
+        // The response also includes the set of scopes (as "scope") that
+        // we're granted, which is exposed as Token::granted_scopes so callers
+        // can check that eg. cloud-platform is in it, see Token::missing_scopes
+        self.refresh.parse_token_response(hash, response)
     }
 }
 
 impl IdTokenProvider for EndUserCredentialsInner {
-    fn get_id_token(&self, _audience: &str) -> Result<IdTokenOrRequest, Error> {
+    fn get_id_token(&self, audience: &str) -> Result<IdTokenOrRequest, Error> {
         let request = self.prepare_token_request()?;
 
         Ok(IdTokenOrRequest::IdTokenRequest {
             request,
             reason: RequestReason::ParametersChanged,
-            audience_hash: 0,
+            audience_hash: hash_str(audience),
         })
     }
 
@@ -193,12 +217,9 @@ impl IdTokenProvider for EndUserCredentialsInner {
     {
         // ID token via access token is not supported with user credentials
         // The token is fetched via the same token request as the access token
-        Err(Error::Auth(error::AuthError {
-            error: Some("Unsupported".to_string()),
-            error_description: Some(
-                "User credentials id tokens via access token not supported".to_string(),
-            ),
-        }))
+        Err(Error::Auth(error::AuthError::unsupported(
+            "User credentials id tokens via access token not supported",
+        )))
     }
 
     fn parse_id_token_response<S>(
@@ -212,20 +233,7 @@ impl IdTokenProvider for EndUserCredentialsInner {
         let (parts, body) = response.into_parts();
 
         if !parts.status.is_success() {
-            let body_bytes = body.as_ref();
-
-            if parts
-                .headers
-                .get(http::header::CONTENT_TYPE)
-                .and_then(|ct| ct.to_str().ok())
-                == Some("application/json; charset=utf-8")
-            {
-                if let Ok(auth_error) = serde_json::from_slice::<error::AuthError>(body_bytes) {
-                    return Err(Error::Auth(auth_error));
-                }
-            }
-
-            return Err(Error::HttpStatus(parts.status));
+            return Err(error::from_response(&parts, body.as_ref()));
         }
 
         let token_res: IdTokenResponseBody = serde_json::from_slice(body.as_ref())?;
@@ -246,6 +254,8 @@ mod test {
             client_secret: "TOP_SECRET".into(),
             refresh_token: "REFRESH_TOKEN".into(),
             client_type: "authorized_user".into(),
+            quota_project_id: None,
+            token_uri: None,
         });
 
         // End-user credentials don't let you override scopes.
@@ -257,6 +267,9 @@ mod test {
 
         match token_or_req {
             TokenOrRequest::Token(_) => panic!("Shouldn't have gotten a token"),
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                panic!("Shouldn't have gotten an access token request")
+            }
             TokenOrRequest::Request { request, .. } => {
                 // Should be the Google oauth2 API
                 assert_eq!(request.uri().host(), Some("oauth2.googleapis.com"));
@@ -265,4 +278,49 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn quota_project() {
+        let info = EndUserCredentialsInfo::deserialize(
+            r#"{"client_id":"client","client_secret":"secret","refresh_token":"refresh","type":"authorized_user","quota_project_id":"quota-project"}"#,
+        )
+        .unwrap();
+
+        let provider = EndUserCredentials::new(info.clone());
+        assert_eq!(provider.inner().quota_project_id(), Some("quota-project"));
+
+        let request = match provider.get_token(&[""]).unwrap() {
+            TokenOrRequest::Request { request, .. } => request,
+            _ => panic!("should have gotten a request"),
+        };
+        assert_eq!(request.headers()["x-goog-user-project"], "quota-project");
+
+        let provider = EndUserCredentials::new(EndUserCredentialsInfo {
+            quota_project_id: None,
+            ..info
+        });
+
+        let request = match provider.get_token(&[""]).unwrap() {
+            TokenOrRequest::Request { request, .. } => request,
+            _ => panic!("should have gotten a request"),
+        };
+        assert!(request.headers().get("x-goog-user-project").is_none());
+    }
+
+    #[test]
+    fn token_uri() {
+        let info = EndUserCredentialsInfo::deserialize(
+            r#"{"client_id":"client","client_secret":"secret","refresh_token":"refresh","type":"authorized_user","token_uri":"https://oauth2-private.p.googleapis.com/token"}"#,
+        )
+        .unwrap();
+
+        let request = match EndUserCredentials::new(info).get_token(&[""]).unwrap() {
+            TokenOrRequest::Request { request, .. } => request,
+            _ => panic!("should have gotten a request"),
+        };
+        assert_eq!(
+            request.uri(),
+            "https://oauth2-private.p.googleapis.com/token"
+        );
+    }
 }
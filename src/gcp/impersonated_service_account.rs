@@ -0,0 +1,788 @@
+use std::{convert::TryInto, time::SystemTime};
+
+use super::{EndUserCredentialsInfo, ServiceAccountInfo, DEFAULT_UNIVERSE_DOMAIN};
+use crate::{
+    error::{self, Error},
+    id_token::{
+        AccessTokenResponse, IdTokenOrRequest, IdTokenProvider, IdTokenRequest, IdTokenResponse,
+    },
+    token::{RequestReason, Token, TokenOrRequest, TokenProvider},
+    token_cache::{hash_scopes, hash_str, CachedTokenProvider},
+    IdToken,
+};
+
+/// The scope the source token needs to be able to impersonate a service account
+const SOURCE_SCOPES: &[&str] = &["https://www.googleapis.com/auth/cloud-platform"];
+
+/// Minimal parts needed from a gcloud `impersonated_service_account`
+/// credentials file, as created by eg.
+/// `gcloud auth application-default login --impersonate-service-account=<email>`
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ImpersonatedServiceAccountInfo {
+    /// The credentials used to retrieve the source tokens
+    pub source_credentials: SourceCredentials,
+    /// The URL of the `generateAccessToken` endpoint of the impersonated
+    /// service account, eg. `https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/<email>:generateAccessToken`
+    pub service_account_impersonation_url: String,
+    /// The chain of delegate service accounts, see
+    /// [`ImpersonatedServiceAccountProviderInner::new`]
+    #[serde(default)]
+    pub delegates: Vec<String>,
+    /// The project that quota and billing is attributed to, see
+    /// [`ImpersonatedServiceAccountProviderInner::with_quota_project_id`]
+    #[serde(default)]
+    pub quota_project_id: Option<String>,
+}
+
+/// The source credentials of an [`ImpersonatedServiceAccountInfo`]
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SourceCredentials {
+    ServiceAccount(ServiceAccountInfo),
+    EndUser(EndUserCredentialsInfo),
+}
+
+impl ImpersonatedServiceAccountInfo {
+    /// Deserializes the impersonated service account credentials from a byte
+    /// slice. This data is typically acquired by reading an
+    /// `application_default_credentials.json` file from disk.
+    pub fn deserialize<T>(key_data: T) -> Result<Self, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        let slice = key_data.as_ref();
+
+        let account_info: Self = serde_json::from_slice(slice)?;
+
+        // Validate the URL up front rather than on first use
+        account_info.target_principal()?;
+        Ok(account_info)
+    }
+
+    /// Deserializes the impersonated service account credentials from already
+    /// parsed JSON, eg. as returned by a secrets API
+    pub fn from_value(value: serde_json::Value) -> Result<Self, Error> {
+        let account_info: Self = serde_json::from_value(value)?;
+        account_info.target_principal()?;
+        Ok(account_info)
+    }
+
+    /// Gets the email of the impersonated service account from the
+    /// impersonation URL
+    pub fn target_principal(&self) -> Result<&str, Error> {
+        self.service_account_impersonation_url
+            .rsplit_once('/')
+            .and_then(|(_, method)| method.strip_suffix(":generateAccessToken"))
+            .filter(|email| !email.is_empty())
+            .ok_or_else(|| Error::InvalidUrl(self.service_account_impersonation_url.clone()))
+    }
+}
+
+/// Provides tokens for a service account by
+/// [impersonating](https://cloud.google.com/iam/docs/create-short-lived-credentials-direct)
+/// it with the tokens of another token source, which must have the
+/// `roles/iam.serviceAccountTokenCreator` role on the target service account.
+/// Caches tokens internally.
+pub type ImpersonatedServiceAccountProvider<P> =
+    CachedTokenProvider<ImpersonatedServiceAccountProviderInner<P>>;
+impl<P> ImpersonatedServiceAccountProvider<P> {
+    pub fn new(source: P, target_principal: impl Into<String>, delegates: Vec<String>) -> Self {
+        CachedTokenProvider::wrap(ImpersonatedServiceAccountProviderInner::new(
+            source,
+            target_principal,
+            delegates,
+        ))
+    }
+
+    /// See [`ImpersonatedServiceAccountProviderInner::with_quota_project_id`]
+    pub fn with_quota_project_id(mut self, project: impl Into<String>) -> Self {
+        self.inner_mut().quota_project_id = Some(project.into());
+        self
+    }
+
+    /// See [`ImpersonatedServiceAccountProviderInner::with_include_email`]
+    pub fn with_include_email(mut self, include_email: bool) -> Self {
+        self.inner_mut().include_email = include_email;
+        self
+    }
+
+    /// See [`ImpersonatedServiceAccountProviderInner::with_universe_domain`]
+    pub fn with_universe_domain(mut self, universe_domain: impl Into<String>) -> Self {
+        self.inner_mut().universe_domain = universe_domain.into();
+        self
+    }
+}
+
+/// Provides tokens for an impersonated service account. Should not be used
+/// directly as it is not cached. Use `ImpersonatedServiceAccountProvider`
+/// instead.
+///
+/// The source token provider should usually be cached as well, otherwise a new
+/// source token is requested every time a token is needed.
+#[derive(Debug, Clone)]
+pub struct ImpersonatedServiceAccountProviderInner<P> {
+    source: P,
+    target_principal: String,
+    delegates: Vec<String>,
+    quota_project_id: Option<String>,
+    include_email: bool,
+    universe_domain: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AccessTokenResponseBody {
+    /// The actual token
+    access_token: String,
+    /// The RFC 3339 timestamp at which the token expires
+    expire_time: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct IdTokenResponseBody {
+    /// The actual token
+    token: String,
+}
+
+impl<P> ImpersonatedServiceAccountProviderInner<P> {
+    /// Creates a provider that impersonates `target_principal` with the tokens
+    /// of `source`.
+    ///
+    /// `delegates` is the optional chain of intermediate service accounts, in
+    /// the `projects/-/serviceAccounts/{email}` form, that is used to
+    /// [delegate](https://cloud.google.com/iam/docs/create-short-lived-credentials-delegated)
+    /// from the source to the target. Each account in the chain must be
+    /// granted the `roles/iam.serviceAccountTokenCreator` role on the next
+    /// account in the chain, so the order matters.
+    pub fn new(source: P, target_principal: impl Into<String>, delegates: Vec<String>) -> Self {
+        Self {
+            source,
+            target_principal: target_principal.into(),
+            delegates,
+            quota_project_id: None,
+            include_email: true,
+            universe_domain: DEFAULT_UNIVERSE_DOMAIN.to_owned(),
+        }
+    }
+
+    /// Sets the project that quota and billing of the IAM credentials
+    /// requests is attributed to, via the `x-goog-user-project` header.
+    ///
+    /// Since the header applies to all API requests, you will typically need
+    /// to add it to your own requests as well, see [`Self::quota_project_id`].
+    pub fn with_quota_project_id(mut self, project: impl Into<String>) -> Self {
+        self.quota_project_id = Some(project.into());
+        self
+    }
+
+    /// Gets the project that quota and billing is attributed to, if set
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+
+    /// Sets whether the id tokens include the `email` and `email_verified`
+    /// claims of the service account. Defaults to `true`, but some relying
+    /// parties reject tokens with an email, or the tokens can be kept smaller.
+    pub fn with_include_email(mut self, include_email: bool) -> Self {
+        self.include_email = include_email;
+        self
+    }
+
+    /// Overrides the universe of the IAM credentials API the tokens are
+    /// requested from, which defaults to [`DEFAULT_UNIVERSE_DOMAIN`]
+    pub fn with_universe_domain(mut self, universe_domain: impl Into<String>) -> Self {
+        self.universe_domain = universe_domain.into();
+        self
+    }
+
+    /// Gets the universe of the IAM credentials API
+    pub fn universe_domain(&self) -> &str {
+        &self.universe_domain
+    }
+
+    /// Gets the token provider that source tokens are retrieved from
+    pub fn source(&self) -> &P {
+        &self.source
+    }
+
+    /// Gets the email of the service account that is impersonated
+    pub fn target_principal(&self) -> &str {
+        &self.target_principal
+    }
+
+    /// Gets the delegate chain used to impersonate the target service account
+    pub fn delegates(&self) -> &[String] {
+        &self.delegates
+    }
+
+    fn prepare_request(
+        &self,
+        method: &str,
+        source_token: Token,
+        mut body: serde_json::Value,
+    ) -> Result<http::Request<Vec<u8>>, Error> {
+        if !self.delegates.is_empty() {
+            body["delegates"] = serde_json::json!(self.delegates);
+        }
+
+        let json_body = serde_json::to_vec(&body)?;
+        let token_header_value: http::HeaderValue = source_token.try_into()?;
+
+        let mut request = crate::token::request_builder()
+            .method("POST")
+            .uri(format!(
+                "{}/{}:{}",
+                super::iam_credentials_url(&self.universe_domain),
+                self.target_principal,
+                method
+            ))
+            .header(
+                http::header::CONTENT_TYPE,
+                "application/json; charset=utf-8",
+            )
+            .header(http::header::CONTENT_LENGTH, json_body.len())
+            .header(http::header::AUTHORIZATION, token_header_value)
+            .body(json_body)?;
+
+        super::set_user_project(&mut request, self.quota_project_id())?;
+
+        Ok(request)
+    }
+
+    /// See <https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateAccessToken>
+    fn prepare_access_token_request<'a, S, I>(
+        &self,
+        source_token: Token,
+        scopes: I,
+    ) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S>,
+    {
+        let scopes: Vec<_> = scopes.into_iter().map(|s| s.as_ref()).collect();
+
+        self.prepare_request(
+            "generateAccessToken",
+            source_token,
+            serde_json::json!({
+                "scope": scopes,
+            }),
+        )
+    }
+
+    /// See <https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateIdToken>
+    fn prepare_id_token_request(
+        &self,
+        source_token: Token,
+        audience: &str,
+    ) -> Result<IdTokenRequest, Error> {
+        self.prepare_request(
+            "generateIdToken",
+            source_token,
+            serde_json::json!({
+                "audience": audience,
+                "includeEmail": self.include_email,
+            }),
+        )
+    }
+}
+
+impl<P> ImpersonatedServiceAccountProviderInner<P>
+where
+    P: TokenProvider,
+{
+    /// Gets a token from the source provider, or the request to retrieve one
+    fn get_source_token(&self) -> Result<TokenOrRequest, Error> {
+        match self.source.get_token(SOURCE_SCOPES)? {
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                Err(Error::Auth(error::AuthError::unsupported(
+                    "Source token providers that need an intermediate access token are not supported",
+                )))
+            }
+            token_or_request => Ok(token_or_request),
+        }
+    }
+
+    /// Lets the source provider parse (and potentially cache) its token
+    fn parse_source_token_response<S>(
+        &self,
+        response: AccessTokenResponse<S>,
+    ) -> Result<Token, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        self.source
+            .parse_token_response(hash_scopes(&SOURCE_SCOPES.iter()), response)
+    }
+}
+
+impl<P> TokenProvider for ImpersonatedServiceAccountProviderInner<P>
+where
+    P: TokenProvider,
+{
+    fn supports_id_tokens(&self) -> bool {
+        true
+    }
+
+    fn get_token_with_subject<'a, S, I, T>(
+        &self,
+        subject: Option<T>,
+        scopes: I,
+    ) -> Result<TokenOrRequest, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        T: Into<String>,
+    {
+        let scope_hash = hash_scopes(&scopes);
+
+        // We can only support subject being none
+        if subject.is_some() {
+            return Err(Error::Auth(error::AuthError::unsupported(
+                "Impersonated service account tokens do not support jwt subjects",
+            )));
+        }
+
+        match self.get_source_token()? {
+            TokenOrRequest::Token(source_token) => Ok(TokenOrRequest::Request {
+                request: self.prepare_access_token_request(source_token, scopes)?,
+                reason: RequestReason::ParametersChanged,
+                scope_hash,
+            }),
+            TokenOrRequest::Request {
+                request, reason, ..
+            } => Ok(TokenOrRequest::AccessTokenRequest {
+                request,
+                reason,
+                scope_hash,
+            }),
+            // Source providers that need an access token themselves are
+            // rejected by `get_source_token`
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                unreachable!("nested access token requests are not supported")
+            }
+        }
+    }
+
+    fn get_token_with_access_token<'a, S, I, R>(
+        &self,
+        scopes: I,
+        response: AccessTokenResponse<R>,
+    ) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        R: AsRef<[u8]>,
+    {
+        let source_token = self.parse_source_token_response(response)?;
+        self.prepare_access_token_request(source_token, scopes)
+    }
+
+    fn parse_token_response<S>(
+        &self,
+        _hash: u64,
+        response: http::Response<S>,
+    ) -> Result<Token, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let (parts, body) = response.into_parts();
+
+        if !parts.status.is_success() {
+            return Err(error::from_response(&parts, body.as_ref()));
+        }
+
+        let token_res: AccessTokenResponseBody = serde_json::from_slice(body.as_ref())?;
+        let expires_in_timestamp =
+            parse_rfc3339(&token_res.expire_time).ok_or(Error::InvalidTokenFormat)?;
+
+        Ok(Token {
+            access_token: token_res.access_token,
+            refresh_token: String::new(),
+            token_type: "Bearer".to_owned(),
+            expires_in: expires_in_timestamp
+                .duration_since(SystemTime::now())
+                .ok()
+                .map(|d| d.as_secs() as i64),
+            expires_in_timestamp: Some(expires_in_timestamp),
+            granted_scopes: Vec::new(),
+        })
+    }
+}
+
+impl<P> IdTokenProvider for ImpersonatedServiceAccountProviderInner<P>
+where
+    P: TokenProvider,
+{
+    fn get_id_token(&self, audience: &str) -> Result<IdTokenOrRequest, Error> {
+        match self.get_source_token()? {
+            TokenOrRequest::Token(source_token) => Ok(IdTokenOrRequest::IdTokenRequest {
+                request: self.prepare_id_token_request(source_token, audience)?,
+                reason: RequestReason::ParametersChanged,
+                audience_hash: hash_str(audience),
+            }),
+            TokenOrRequest::Request {
+                request, reason, ..
+            } => Ok(IdTokenOrRequest::AccessTokenRequest {
+                request,
+                reason,
+                audience_hash: hash_str(audience),
+            }),
+            // Source providers that need an access token themselves are
+            // rejected by `get_source_token`
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                unreachable!("nested access token requests are not supported")
+            }
+        }
+    }
+
+    fn get_id_token_with_access_token<S>(
+        &self,
+        audience: &str,
+        response: AccessTokenResponse<S>,
+    ) -> Result<IdTokenRequest, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let source_token = self.parse_source_token_response(response)?;
+        self.prepare_id_token_request(source_token, audience)
+    }
+
+    fn parse_id_token_response<S>(
+        &self,
+        _hash: u64,
+        response: IdTokenResponse<S>,
+    ) -> Result<IdToken, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let (parts, body) = response.into_parts();
+
+        if !parts.status.is_success() {
+            return Err(error::from_response(&parts, body.as_ref()));
+        }
+
+        let token_res: IdTokenResponseBody = serde_json::from_slice(body.as_ref())?;
+        let token = IdToken::new(token_res.token)?;
+
+        Ok(token)
+    }
+}
+
+/// Parses an RFC 3339 UTC timestamp, eg. `2014-10-02T15:01:23.045123456Z`, as
+/// returned by the IAM credentials API
+fn parse_rfc3339(timestamp: &str) -> Option<SystemTime> {
+    let timestamp = timestamp.strip_suffix('Z')?;
+    let (date, time) = timestamp.split_once('T')?;
+
+    let mut date = date.splitn(3, '-').map(|s| s.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    // Fractional seconds are ignored
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|s| s.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // Days since the epoch for the civil date, see
+    // <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days: u64 = (era * 146_097 + day_of_era - 719_468).try_into().ok()?;
+
+    SystemTime::UNIX_EPOCH.checked_add(std::time::Duration::from_secs(
+        days * 86400 + hour * 3600 + minute * 60 + second,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gcp::{ServiceAccountInfo, ServiceAccountProvider};
+
+    const TARGET: &str = "target@project.iam.gserviceaccount.com";
+
+    fn iam_credentials_url() -> String {
+        crate::gcp::iam_credentials_url(DEFAULT_UNIVERSE_DOMAIN)
+    }
+
+    fn provider() -> ImpersonatedServiceAccountProvider<ServiceAccountProvider> {
+        let info =
+            ServiceAccountInfo::deserialize(include_str!("../../tests/svc_key.json")).unwrap();
+        let source = ServiceAccountProvider::new(info).unwrap();
+        ImpersonatedServiceAccountProvider::new(source, TARGET, Vec::new())
+    }
+
+    fn source_response() -> http::Response<&'static str> {
+        http::Response::new(
+            r#"{"access_token":"source-token","token_type":"Bearer","expires_in":3599}"#,
+        )
+    }
+
+    #[test]
+    fn impersonated_info() {
+        let sa_key: serde_json::Value =
+            serde_json::from_str(include_str!("../../tests/svc_key.json")).unwrap();
+        let info = serde_json::json!({
+            "type": "impersonated_service_account",
+            "delegates": ["projects/-/serviceAccounts/delegate@project.iam.gserviceaccount.com"],
+            "service_account_impersonation_url": format!("{}/{}:generateAccessToken", iam_credentials_url(), TARGET),
+            "source_credentials": sa_key,
+        });
+
+        let info = ImpersonatedServiceAccountInfo::deserialize(info.to_string()).unwrap();
+        assert_eq!(info.target_principal().unwrap(), TARGET);
+        assert_eq!(info.delegates.len(), 1);
+        assert!(matches!(
+            info.source_credentials,
+            SourceCredentials::ServiceAccount(_)
+        ));
+
+        let info = serde_json::json!({
+            "type": "impersonated_service_account",
+            "service_account_impersonation_url": format!("{}/{}:generateAccessToken", iam_credentials_url(), TARGET),
+            "source_credentials": {
+                "client_id": "client-id",
+                "client_secret": "client-secret",
+                "refresh_token": "refresh-token",
+                "type": "authorized_user",
+            },
+        });
+
+        let info = ImpersonatedServiceAccountInfo::deserialize(info.to_string()).unwrap();
+        assert!(info.delegates.is_empty());
+        assert!(matches!(
+            info.source_credentials,
+            SourceCredentials::EndUser(_)
+        ));
+
+        let info = serde_json::json!({
+            "type": "impersonated_service_account",
+            "service_account_impersonation_url": "https://example.com/nope",
+            "source_credentials": sa_key,
+        });
+        assert!(matches!(
+            ImpersonatedServiceAccountInfo::deserialize(info.to_string()),
+            Err(Error::InvalidUrl(url)) if url == "https://example.com/nope"
+        ));
+    }
+
+    #[test]
+    fn rfc3339() {
+        let secs = |ts| {
+            parse_rfc3339(ts)
+                .unwrap()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+
+        assert_eq!(secs("2014-10-02T15:01:23Z"), 1_412_262_083);
+        assert_eq!(secs("2014-10-02T15:01:23.045123456Z"), 1_412_262_083);
+        assert_eq!(secs("2000-02-29T00:00:00Z"), 951_782_400);
+        assert!(parse_rfc3339("2014-10-02T15:01:23+01:00").is_none());
+        assert!(parse_rfc3339("2014-13-02T15:01:23Z").is_none());
+    }
+
+    #[test]
+    fn impersonated_access_token() {
+        let provider = provider();
+        let scopes = ["https://www.googleapis.com/auth/devstorage.read_only"];
+
+        // We don't have a source token yet, so that needs to be requested first
+        let request = match provider.get_token(&scopes).unwrap() {
+            TokenOrRequest::AccessTokenRequest { request, .. } => request,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(request.uri(), "https://accounts.google.com/o/oauth2/token");
+
+        let request = provider
+            .get_token_with_access_token(&scopes, source_response())
+            .unwrap();
+        assert_eq!(
+            request.uri().to_string(),
+            format!("{}/{}:generateAccessToken", iam_credentials_url(), TARGET)
+        );
+        assert_eq!(
+            request.headers()[http::header::AUTHORIZATION],
+            "Bearer source-token"
+        );
+
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(body["scope"], serde_json::json!(scopes));
+        assert!(body.get("delegates").is_none());
+
+        // The source token is now cached, so the token can be requested directly
+        let scope_hash = match provider.get_token(&scopes).unwrap() {
+            TokenOrRequest::Request { scope_hash, .. } => scope_hash,
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let response = http::Response::new(
+            r#"{"accessToken":"impersonated-token","expireTime":"2114-10-02T15:01:23Z"}"#,
+        );
+        let token = provider.parse_token_response(scope_hash, response).unwrap();
+        assert_eq!(token.access_token, "impersonated-token");
+        assert_eq!(token.token_type, "Bearer");
+        assert_eq!(
+            token.expires_in_timestamp,
+            parse_rfc3339("2114-10-02T15:01:23Z")
+        );
+
+        assert!(matches!(
+            provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(token) if token.access_token == "impersonated-token"
+        ));
+    }
+
+    #[test]
+    fn impersonated_id_token() {
+        let provider = provider();
+
+        let request = match provider.get_id_token("my-audience").unwrap() {
+            IdTokenOrRequest::AccessTokenRequest { request, .. } => request,
+            _ => panic!("expected an access token request"),
+        };
+        assert_eq!(request.uri(), "https://accounts.google.com/o/oauth2/token");
+
+        let request = provider
+            .get_id_token_with_access_token("my-audience", source_response())
+            .unwrap();
+        assert_eq!(
+            request.uri().to_string(),
+            format!("{}/{}:generateIdToken", iam_credentials_url(), TARGET)
+        );
+
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(body["audience"], "my-audience");
+    }
+
+    #[test]
+    fn error_response() {
+        let provider = provider();
+        let response = || {
+            http::Response::builder()
+                .status(http::StatusCode::FORBIDDEN)
+                .header(http::header::CONTENT_TYPE, "application/json; charset=UTF-8")
+                .body(
+                    r#"{"error":{"code":403,"message":"Permission 'iam.serviceAccounts.getAccessToken' denied","status":"PERMISSION_DENIED"}}"#,
+                )
+                .unwrap()
+        };
+        let is_denied = |err: &error::AuthError| {
+            err.error.as_deref() == Some("PERMISSION_DENIED")
+                && err
+                    .error_description
+                    .as_deref()
+                    .is_some_and(|desc| desc.starts_with("Permission"))
+        };
+
+        assert!(matches!(
+            provider.parse_token_response(0, response()),
+            Err(Error::Auth(err)) if is_denied(&err)
+        ));
+        assert!(matches!(
+            provider.parse_id_token_response(0, response()),
+            Err(Error::Auth(err)) if is_denied(&err)
+        ));
+    }
+
+    #[test]
+    fn universe_domain() {
+        let provider = provider();
+        assert_eq!(provider.inner().universe_domain(), DEFAULT_UNIVERSE_DOMAIN);
+
+        let provider = provider.with_universe_domain("example-universe.goog");
+        let request = provider
+            .get_token_with_access_token(&["scope"], source_response())
+            .unwrap();
+        assert_eq!(
+            request.uri().to_string(),
+            format!(
+                "https://iamcredentials.example-universe.goog/v1/projects/-/serviceAccounts/{}:generateAccessToken",
+                TARGET
+            )
+        );
+    }
+
+    #[test]
+    fn request_id() {
+        use crate::token_cache::REQUEST_ID_HEADER;
+
+        let new_provider = || provider().with_request_id("abcd").unwrap();
+
+        // Both the request for the source token and the impersonation request
+        // carry the request id
+        let provider = new_provider();
+        match provider.get_token(&["scope"]).unwrap() {
+            TokenOrRequest::AccessTokenRequest { request, .. } => {
+                assert_eq!(request.headers()[REQUEST_ID_HEADER], "abcd");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let request = provider
+            .get_token_with_access_token(&["scope"], source_response())
+            .unwrap();
+        assert_eq!(request.headers()[REQUEST_ID_HEADER], "abcd");
+
+        let provider = new_provider();
+        match provider.get_id_token("my-audience").unwrap() {
+            IdTokenOrRequest::AccessTokenRequest { request, .. } => {
+                assert_eq!(request.headers()[REQUEST_ID_HEADER], "abcd");
+            }
+            _ => panic!("expected an access token request"),
+        }
+        let request = provider
+            .get_id_token_with_access_token("my-audience", source_response())
+            .unwrap();
+        assert_eq!(request.headers()[REQUEST_ID_HEADER], "abcd");
+    }
+
+    #[test]
+    fn delegate_chain() {
+        let info =
+            ServiceAccountInfo::deserialize(include_str!("../../tests/svc_key.json")).unwrap();
+        let source = ServiceAccountProvider::new(info).unwrap();
+        let delegates = vec![
+            "projects/-/serviceAccounts/first@project.iam.gserviceaccount.com".to_owned(),
+            "projects/-/serviceAccounts/second@project.iam.gserviceaccount.com".to_owned(),
+        ];
+        let provider = ImpersonatedServiceAccountProvider::new(source, TARGET, delegates);
+
+        let request = provider
+            .get_token_with_access_token(&["scope"], source_response())
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(
+            body["delegates"],
+            serde_json::json!([
+                "projects/-/serviceAccounts/first@project.iam.gserviceaccount.com",
+                "projects/-/serviceAccounts/second@project.iam.gserviceaccount.com",
+            ])
+        );
+
+        let request = provider
+            .get_id_token_with_access_token("my-audience", source_response())
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(
+            body["delegates"][0],
+            "projects/-/serviceAccounts/first@project.iam.gserviceaccount.com"
+        );
+        assert_eq!(
+            body["delegates"][1],
+            "projects/-/serviceAccounts/second@project.iam.gserviceaccount.com"
+        );
+    }
+}
@@ -1,14 +1,133 @@
-use super::TokenResponse;
+use super::DEFAULT_UNIVERSE_DOMAIN;
 use crate::{
     error::{self, Error},
     id_token::{IdTokenOrRequest, IdTokenProvider},
+    oauth2::TokenResponse,
     token::{RequestReason, Token, TokenOrRequest, TokenProvider},
-    token_cache::CachedTokenProvider,
+    token_cache::{hash_scopes, hash_str, CachedTokenProvider},
     IdToken,
 };
+use std::time::Duration;
+
+/// The host of the metadata server if it is not overridden by the
+/// `GCE_METADATA_HOST` or `GCE_METADATA_IP` environment variables
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+const METADATA_PATH: &str = "computeMetadata/v1/instance/service-accounts";
+const UNIVERSE_DOMAIN_PATH: &str = "computeMetadata/v1/universe/universe-domain";
+
+/// A suggested schedule for retrying requests to the metadata server, which
+/// occasionally responds with a 500 or 503, or is briefly unreachable, eg.
+/// right after an instance has started.
+///
+/// Since this crate doesn't send requests itself, it is up to the caller to
+/// retry, eg.
+///
+/// ```no_run
+/// # use tame_oauth::gcp::metadata_server::RetryPolicy;
+/// # fn send() -> Result<(), tame_oauth::Error> { Ok(()) }
+/// let policy = RetryPolicy::default();
+/// let mut attempt = 1;
+///
+/// loop {
+///     match send() {
+///         Ok(token) => break token,
+///         Err(err) => match policy.retry_after(&err, attempt) {
+///             Some(delay) => std::thread::sleep(delay),
+///             None => panic!("failed to get token: {}", err),
+///         },
+///     }
+///
+///     attempt += 1;
+/// }
+/// ```
+///
+/// Failures to reach the metadata server are reported by your HTTP client,
+/// and should be retried with the same [`Self::delay`] schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// 5 attempts, with an exponential backoff starting at 100ms
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy with the specified maximum number of attempts,
+    /// including the first one, and the default delays
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Self::default()
+        }
+    }
 
-const METADATA_URL: &str =
-    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts";
+    /// Sets the delay before the first retry, which doubles for each
+    /// subsequent retry. Defaults to 100ms.
+    pub fn with_initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    /// Sets the maximum delay between retries. Defaults to 5s.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Gets the maximum number of attempts, including the first one
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Gets the delay before retrying after the specified failed attempt,
+    /// starting at 1, or `None` if no more attempts should be made
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt >= self.max_attempts {
+            return None;
+        }
+
+        let delay = self
+            .initial_delay
+            .checked_mul(1 << (attempt - 1).min(31))
+            .unwrap_or(self.max_delay);
+        Some(delay.min(self.max_delay))
+    }
+
+    /// Gets the delay before retrying after the specified failed attempt,
+    /// starting at 1, or `None` if the error is not
+    /// [retryable](Error::is_retryable) or no more attempts should be made
+    pub fn retry_after(&self, error: &Error, attempt: u32) -> Option<Duration> {
+        if !error.is_retryable() {
+            return None;
+        }
+
+        self.delay(attempt)
+    }
+}
+
+/// The [format](https://cloud.google.com/compute/docs/instances/verifying-instance-identity#token_format)
+/// of the id tokens requested from the metadata server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdTokenFormat {
+    /// Only the standard claims
+    #[default]
+    Standard,
+    /// Includes the project and instance claims in the `google` claim
+    Full,
+    /// Like [`Self::Full`], but also includes the license codes of the
+    /// instance's image
+    FullWithLicenses,
+}
 
 /// [Provides tokens](https://cloud.google.com/compute/docs/instances/verifying-instance-identity)
 /// using the metadata server accessible when running from within GCP.
@@ -18,25 +137,220 @@ impl MetadataServerProvider {
     pub fn new(account_name: Option<String>) -> Self {
         CachedTokenProvider::wrap(MetadataServerProviderInner::new(account_name))
     }
+
+    /// Creates a provider that doesn't cache tokens, so that every token is
+    /// requested from the metadata server. The metadata server caches tokens
+    /// itself and is local and fast, so this costs little, and avoids using
+    /// a token from the in-process cache that the metadata server has
+    /// already rotated.
+    pub fn uncached(account_name: Option<String>) -> MetadataServerProviderInner {
+        MetadataServerProviderInner::new(account_name)
+    }
+
+    /// See [`MetadataServerProviderInner::with_host`]
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.inner_mut().host = host.into();
+        self
+    }
+
+    /// See [`MetadataServerProviderInner::with_universe_domain`]
+    pub fn with_universe_domain(mut self, universe_domain: impl Into<String>) -> Self {
+        self.inner_mut().universe_domain = universe_domain.into();
+        self
+    }
+
+    /// See [`MetadataServerProviderInner::with_id_token_format`]
+    pub fn with_id_token_format(mut self, format: IdTokenFormat) -> Self {
+        self.inner_mut().id_token_format = format;
+        self
+    }
 }
 
 /// [Provides tokens](https://cloud.google.com/compute/docs/instances/verifying-instance-identity)
 /// using the metadata server accessible when running from within GCP. Should not be used directly as it
 /// is not cached. Use `MetadataServerProvider` instead.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MetadataServerProviderInner {
     account_name: String,
+    host: String,
+    universe_domain: String,
+    id_token_format: IdTokenFormat,
 }
 
 impl MetadataServerProviderInner {
+    /// Creates a provider for the specified service account, or the default
+    /// service account if not specified.
+    ///
+    /// On instances with multiple attached service accounts, the account can
+    /// be selected by either its email or its numeric unique id. The account
+    /// is percent encoded when building request URLs, so any characters that
+    /// are not URL safe don't need to be escaped by the caller.
+    ///
+    /// The metadata server is reached at the host specified by the
+    /// `GCE_METADATA_HOST` or `GCE_METADATA_IP` environment variables, the
+    /// same as the gcloud SDK, or `metadata.google.internal` if neither are
+    /// set.
     pub fn new(account_name: Option<String>) -> Self {
+        let host = ["GCE_METADATA_HOST", "GCE_METADATA_IP"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|host| !host.is_empty()))
+            .unwrap_or_else(|| DEFAULT_METADATA_HOST.to_owned());
+
         Self {
             account_name: account_name.unwrap_or_else(|| "default".into()),
+            host,
+            universe_domain: DEFAULT_UNIVERSE_DOMAIN.to_owned(),
+            id_token_format: IdTokenFormat::default(),
         }
     }
+
+    /// Overrides the host, and optionally port, the metadata server is
+    /// reached at, eg. `169.254.169.254` or `localhost:8080` for a local
+    /// emulator
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// Gets the host the metadata server is reached at
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Overrides the domain of the APIs the tokens are for, which defaults to
+    /// [`DEFAULT_UNIVERSE_DOMAIN`], eg. with the domain from a
+    /// [`Self::universe_domain_request`]. The tokens are always retrieved from
+    /// the metadata server itself, but requests that use them to reach Google
+    /// APIs need to go to this universe, eg. [`super::sign`] requests, or
+    /// [`super::ImpersonatedServiceAccountProvider::with_universe_domain`]
+    /// when impersonating with the tokens of this provider.
+    pub fn with_universe_domain(mut self, universe_domain: impl Into<String>) -> Self {
+        self.universe_domain = universe_domain.into();
+        self
+    }
+
+    /// Gets the domain of the APIs the tokens are for
+    pub fn universe_domain(&self) -> &str {
+        &self.universe_domain
+    }
+
+    /// Sets the format of the requested id tokens, eg. [`IdTokenFormat::Full`]
+    /// to include the project and instance claims that IAP and some
+    /// verification flows expect. Defaults to [`IdTokenFormat::Standard`].
+    pub fn with_id_token_format(mut self, format: IdTokenFormat) -> Self {
+        self.id_token_format = format;
+        self
+    }
+
+    /// Gets the format of the requested id tokens
+    pub fn id_token_format(&self) -> IdTokenFormat {
+        self.id_token_format
+    }
+
+    /// Creates a request for the universe domain of the environment, which is
+    /// `googleapis.com` unless running in eg. Google Distributed Cloud
+    pub fn universe_domain_request(&self) -> Result<http::Request<Vec<u8>>, Error> {
+        get_request(format!("http://{}/{}", self.host, UNIVERSE_DOMAIN_PATH))
+    }
+
+    /// Once a response has been received for a [`Self::universe_domain_request`],
+    /// call this method to get the universe domain.
+    pub fn parse_universe_domain_response<S>(
+        &self,
+        response: http::Response<S>,
+    ) -> Result<String, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let universe_domain = parse_metadata_response(response)?;
+        Ok(universe_domain.trim().to_owned())
+    }
+
+    /// The base URL of the service account attributes
+    fn base_url(&self) -> String {
+        format!(
+            "http://{}/{}/{}",
+            self.host,
+            METADATA_PATH,
+            encode_path_segment(&self.account_name)
+        )
+    }
+
+    /// Creates a request for the email address of the service account
+    pub fn email_request(&self) -> Result<http::Request<Vec<u8>>, Error> {
+        self.metadata_request("email")
+    }
+
+    /// Once a response has been received for an [`Self::email_request`],
+    /// call this method to get the email address of the service account.
+    pub fn parse_email_response<S>(&self, response: http::Response<S>) -> Result<String, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let email = parse_metadata_response(response)?;
+        Ok(email.trim().to_owned())
+    }
+
+    /// Creates a request for the scopes that are available to the service account
+    pub fn scopes_request(&self) -> Result<http::Request<Vec<u8>>, Error> {
+        self.metadata_request("scopes")
+    }
+
+    /// Once a response has been received for a [`Self::scopes_request`],
+    /// call this method to get the list of scopes available to the service
+    /// account.
+    pub fn parse_scopes_response<S>(
+        &self,
+        response: http::Response<S>,
+    ) -> Result<Vec<String>, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let scopes = parse_metadata_response(response)?;
+        Ok(scopes
+            .lines()
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect())
+    }
+
+    /// Creates a request for an attribute of the service account, eg.
+    /// `computeMetadata/v1/instance/service-accounts/<name or id>/email`
+    fn metadata_request(&self, attribute: &str) -> Result<http::Request<Vec<u8>>, Error> {
+        get_request(format!("{}/{}", self.base_url(), attribute))
+    }
+}
+
+/// Creates a GET request for a metadata server URL
+fn get_request(url: String) -> Result<http::Request<Vec<u8>>, Error> {
+    let request = crate::token::request_builder()
+        .method("GET")
+        .uri(url)
+        .header("Metadata-Flavor", "Google")
+        .body(Vec::new())?;
+
+    Ok(request)
+}
+
+/// The metadata server returns attributes as plain text
+fn parse_metadata_response<S>(response: http::Response<S>) -> Result<String, Error>
+where
+    S: AsRef<[u8]>,
+{
+    let (parts, body) = response.into_parts();
+
+    if !parts.status.is_success() {
+        return Err(error::from_response(&parts, body.as_ref()));
+    }
+
+    Ok(String::from_utf8_lossy(body.as_ref()).into_owned())
 }
 
 impl TokenProvider for MetadataServerProviderInner {
+    fn supports_id_tokens(&self) -> bool {
+        true
+    }
+
     fn get_token_with_subject<'a, S, I, T>(
         &self,
         subject: Option<T>,
@@ -44,22 +358,21 @@ impl TokenProvider for MetadataServerProviderInner {
     ) -> Result<TokenOrRequest, Error>
     where
         S: AsRef<str> + 'a,
-        I: IntoIterator<Item = &'a S>,
+        I: IntoIterator<Item = &'a S> + Clone,
         T: Into<String>,
     {
+        let scope_hash = hash_scopes(&scopes);
+
         // We can only support subject being none
         if subject.is_some() {
-            return Err(Error::Auth(error::AuthError {
-                error: Some("Unsupported".to_string()),
-                error_description: Some(
-                    "Metadata server tokens do not support jwt subjects".to_string(),
-                ),
-            }));
+            return Err(Error::Auth(error::AuthError::unsupported(
+                "Metadata server tokens do not support jwt subjects",
+            )));
         }
 
         // Regardless of GCE or GAE, the token_uri is
         // `computeMetadata/v1/instance/service-accounts/<name or id>/token`.
-        let mut url = format!("{}/{}/token", METADATA_URL, self.account_name);
+        let mut url = format!("{}/token", self.base_url());
 
         // Merge all the scopes into a single string.
         let scopes_str = scopes
@@ -74,7 +387,7 @@ impl TokenProvider for MetadataServerProviderInner {
             url.push_str(&scopes_str);
         }
 
-        let request = http::Request::builder()
+        let request = crate::token::request_builder()
             .method("GET")
             .uri(url)
             // To get responses from GCE, we must pass along the
@@ -85,10 +398,14 @@ impl TokenProvider for MetadataServerProviderInner {
         Ok(TokenOrRequest::Request {
             request,
             reason: RequestReason::ParametersChanged,
-            scope_hash: 0,
+            scope_hash,
         })
     }
 
+    /// Parses the token from the metadata server's response. The metadata
+    /// server occasionally responds with a 500 or 503, which are returned as
+    /// an [`Error::HttpStatus`] that is [retryable](Error::is_retryable), see
+    /// [`RetryPolicy`].
     fn parse_token_response<S>(
         &self,
         _hash: u64,
@@ -100,26 +417,34 @@ impl TokenProvider for MetadataServerProviderInner {
         let (parts, body) = response.into_parts();
 
         if !parts.status.is_success() {
-            return Err(Error::HttpStatus(parts.status));
+            return Err(error::from_response(&parts, body.as_ref()));
         }
-
-        // Deserialize our response, or fail.
         let token_res: TokenResponse = serde_json::from_slice(body.as_ref())?;
-
-        // Convert it into our output.
-        let token: Token = token_res.into();
+        let token = token_res.into_token(None)?;
         Ok(token)
     }
 }
 
 impl IdTokenProvider for MetadataServerProviderInner {
     fn get_id_token(&self, audience: &str) -> Result<IdTokenOrRequest, error::Error> {
-        let url = format!(
-            "{}/{}/identity?audience={}",
-            METADATA_URL, self.account_name, audience,
-        );
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        query.append_pair("audience", audience);
 
-        let request = http::Request::builder()
+        match self.id_token_format {
+            IdTokenFormat::Standard => {}
+            IdTokenFormat::Full => {
+                query.append_pair("format", "full");
+            }
+            IdTokenFormat::FullWithLicenses => {
+                query
+                    .append_pair("format", "full")
+                    .append_pair("licenses", "TRUE");
+            }
+        }
+
+        let url = format!("{}/identity?{}", self.base_url(), query.finish());
+
+        let request = crate::token::request_builder()
             .method("GET")
             .uri(url)
             .header("Metadata-Flavor", "Google")
@@ -128,7 +453,7 @@ impl IdTokenProvider for MetadataServerProviderInner {
         Ok(IdTokenOrRequest::IdTokenRequest {
             request,
             reason: RequestReason::ParametersChanged,
-            audience_hash: 0,
+            audience_hash: hash_str(audience),
         })
     }
 
@@ -143,7 +468,7 @@ impl IdTokenProvider for MetadataServerProviderInner {
         let (parts, body) = response.into_parts();
 
         if !parts.status.is_success() {
-            return Err(Error::HttpStatus(parts.status));
+            return Err(error::from_response(&parts, body.as_ref()));
         }
 
         let token = IdToken::new(String::from_utf8_lossy(body.as_ref()).into_owned())?;
@@ -161,13 +486,29 @@ impl IdTokenProvider for MetadataServerProviderInner {
     {
         // ID token via access token is not supported in the metadata service
         // The token can be fetched directly via the metadataservice.
-        Err(Error::Auth(error::AuthError {
-            error: Some("Unsupported".to_string()),
-            error_description: Some(
-                "Metadata server id tokens via access token not supported".to_string(),
-            ),
-        }))
+        Err(Error::Auth(error::AuthError::unsupported(
+            "Metadata server id tokens via access token not supported",
+        )))
+    }
+}
+
+/// Percent encodes everything in a path segment except for unreserved
+/// characters and `@`, which is commonly found in service account emails
+fn encode_path_segment(segment: &str) -> String {
+    use std::fmt::Write;
+
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+                encoded.push(byte as char);
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
     }
+    encoded
 }
 
 #[cfg(test)]
@@ -186,6 +527,9 @@ mod test {
 
         match token_or_req {
             TokenOrRequest::Token(_) => panic!("Shouldn't have gotten a token"),
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                panic!("Shouldn't have gotten an access token request")
+            }
             TokenOrRequest::Request { request, .. } => {
                 // Should be the metadata server
                 assert_eq!(request.uri().host(), Some("metadata.google.internal"));
@@ -207,6 +551,9 @@ mod test {
 
         match token_or_req {
             TokenOrRequest::Token(_) => panic!("Shouldn't have gotten a token"),
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                panic!("Shouldn't have gotten an access token request")
+            }
             TokenOrRequest::Request { request, .. } => {
                 // Should be the metadata server
                 assert_eq!(request.uri().host(), Some("metadata.google.internal"));
@@ -225,6 +572,227 @@ mod test {
         }
     }
 
+    #[test]
+    fn uncached() {
+        let provider = MetadataServerProvider::uncached(None);
+        let response = || {
+            http::Response::new(
+                r#"{"access_token":"access-token","token_type":"Bearer","expires_in":3599}"#,
+            )
+        };
+
+        let cached = MetadataServerProvider::new(None);
+        let hash = match cached.get_token(&["scope"]).unwrap() {
+            TokenOrRequest::Request { scope_hash, .. } => scope_hash,
+            _ => panic!("expected a request"),
+        };
+        cached.parse_token_response(hash, response()).unwrap();
+        assert!(matches!(
+            cached.get_token(&["scope"]).unwrap(),
+            TokenOrRequest::Token(_)
+        ));
+
+        // The uncached provider requests a new token every time
+        provider.parse_token_response(hash, response()).unwrap();
+        assert!(matches!(
+            provider.get_token(&["scope"]).unwrap(),
+            TokenOrRequest::Request { .. }
+        ));
+    }
+
+    #[test]
+    fn metadata_introspection() {
+        let provider = MetadataServerProviderInner::new(None);
+
+        let request = provider.email_request().unwrap();
+        assert_eq!(
+            request.uri().path(),
+            "/computeMetadata/v1/instance/service-accounts/default/email"
+        );
+        assert_eq!(request.headers()["Metadata-Flavor"], "Google");
+
+        let email = provider
+            .parse_email_response(http::Response::new("sa@project.iam.gserviceaccount.com\n"))
+            .unwrap();
+        assert_eq!(email, "sa@project.iam.gserviceaccount.com");
+
+        let request = provider.scopes_request().unwrap();
+        assert_eq!(
+            request.uri().path(),
+            "/computeMetadata/v1/instance/service-accounts/default/scopes"
+        );
+
+        let scopes = provider
+            .parse_scopes_response(http::Response::new(
+                "https://www.googleapis.com/auth/cloud-platform\nhttps://www.googleapis.com/auth/userinfo.email\n",
+            ))
+            .unwrap();
+        assert_eq!(
+            scopes,
+            [
+                "https://www.googleapis.com/auth/cloud-platform",
+                "https://www.googleapis.com/auth/userinfo.email"
+            ]
+        );
+
+        let response = http::Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .body("")
+            .unwrap();
+        assert!(matches!(
+            provider.parse_scopes_response(response),
+            Err(Error::HttpStatus {
+                status: http::StatusCode::NOT_FOUND,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn metadata_host() {
+        let provider = MetadataServerProvider::new(None).with_host("localhost:8080");
+
+        let request = match provider.get_token(&["scope1"]).unwrap() {
+            TokenOrRequest::Request { request, .. } => request,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            request.uri(),
+            "http://localhost:8080/computeMetadata/v1/instance/service-accounts/default/token?scopes=scope1"
+        );
+
+        let request = match provider.get_id_token("my-audience").unwrap() {
+            IdTokenOrRequest::IdTokenRequest { request, .. } => request,
+            _ => panic!("expected an id token request"),
+        };
+        assert_eq!(
+            request.uri(),
+            "http://localhost:8080/computeMetadata/v1/instance/service-accounts/default/identity?audience=my-audience"
+        );
+    }
+
+    #[test]
+    fn universe_domain() {
+        let provider = MetadataServerProvider::new(None).with_host("localhost:8080");
+        assert_eq!(provider.inner().universe_domain(), DEFAULT_UNIVERSE_DOMAIN);
+
+        let request = provider.inner().universe_domain_request().unwrap();
+        assert_eq!(
+            request.uri(),
+            "http://localhost:8080/computeMetadata/v1/universe/universe-domain"
+        );
+        assert_eq!(request.headers()["Metadata-Flavor"], "Google");
+
+        let universe_domain = provider
+            .inner()
+            .parse_universe_domain_response(http::Response::new("example-universe.goog\n"))
+            .unwrap();
+        assert_eq!(universe_domain, "example-universe.goog");
+
+        let provider = provider.with_universe_domain(universe_domain);
+        assert_eq!(provider.inner().universe_domain(), "example-universe.goog");
+
+        // Requests made with the tokens of the provider go to its universe
+        let request = crate::gcp::sign::sign_blob_request(
+            &Token::new_bearer("metadata-token", Duration::from_secs(3599)),
+            provider.inner().universe_domain(),
+            "default@project.iam.gserviceaccount.com",
+            b"blob",
+        )
+        .unwrap();
+        assert_eq!(
+            request.uri().host(),
+            Some("iamcredentials.example-universe.goog")
+        );
+    }
+
+    #[test]
+    fn account_name() {
+        let uri = |account: &str| {
+            let provider = MetadataServerProvider::new(Some(account.to_owned()));
+            provider.inner().email_request().unwrap().uri().to_string()
+        };
+
+        assert_eq!(
+            uri("1234567890"),
+            "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/1234567890/email"
+        );
+        assert_eq!(
+            uri("my-sa@my-project.iam.gserviceaccount.com"),
+            "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/my-sa@my-project.iam.gserviceaccount.com/email"
+        );
+        assert_eq!(
+            uri("weird+sa/../?x=1 #@example.com"),
+            "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/weird%2Bsa%2F..%2F%3Fx%3D1%20%23@example.com/email"
+        );
+    }
+
+    #[test]
+    fn id_token_format() {
+        let uri = |provider: &MetadataServerProvider| match provider
+            .get_id_token("https://example.com")
+            .unwrap()
+        {
+            IdTokenOrRequest::IdTokenRequest { request, .. } => request.uri().to_string(),
+            _ => panic!("expected an id token request"),
+        };
+
+        let provider = MetadataServerProvider::new(None);
+        assert_eq!(provider.inner().id_token_format(), IdTokenFormat::Standard);
+        assert_eq!(
+            uri(&provider),
+            "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/identity?audience=https%3A%2F%2Fexample.com"
+        );
+
+        let provider = MetadataServerProvider::new(None).with_id_token_format(IdTokenFormat::Full);
+        assert!(uri(&provider).ends_with("?audience=https%3A%2F%2Fexample.com&format=full"));
+
+        let provider =
+            MetadataServerProvider::new(None).with_id_token_format(IdTokenFormat::FullWithLicenses);
+        assert!(uri(&provider)
+            .ends_with("?audience=https%3A%2F%2Fexample.com&format=full&licenses=TRUE"));
+    }
+
+    #[test]
+    fn retry_policy() {
+        let provider = MetadataServerProvider::new(None);
+        let policy = RetryPolicy::default();
+
+        let delays: Vec<_> = (1..=5).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(200)),
+                Some(Duration::from_millis(400)),
+                Some(Duration::from_millis(800)),
+                None,
+            ]
+        );
+
+        let status = |status| {
+            provider
+                .parse_token_response(
+                    0,
+                    http::Response::builder().status(status).body("").unwrap(),
+                )
+                .unwrap_err()
+        };
+
+        for transient in [500, 503] {
+            assert_eq!(
+                policy.retry_after(&status(transient), 1),
+                Some(Duration::from_millis(100))
+            );
+        }
+        assert_eq!(policy.retry_after(&status(404), 1), None);
+
+        let policy = RetryPolicy::new(20).with_max_delay(Duration::from_secs(1));
+        assert_eq!(policy.delay(19), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(20), None);
+        assert_eq!(policy.max_attempts(), 20);
+    }
+
     #[test]
     fn wrapper_dispatch() {
         // Wrap the metadata server provider.
@@ -240,6 +808,9 @@ mod test {
 
         match token_or_req {
             TokenOrRequest::Token(_) => panic!("Shouldn't have gotten a token"),
+            TokenOrRequest::AccessTokenRequest { .. } => {
+                panic!("Shouldn't have gotten an access token request")
+            }
             TokenOrRequest::Request { request, .. } => {
                 // Should be the metadata server
                 assert_eq!(request.uri().host(), Some("metadata.google.internal"));
@@ -0,0 +1,239 @@
+//! Signs JWTs and blobs with the key of a service account via the
+//! [IAM credentials API](https://cloud.google.com/iam/docs/reference/credentials/rest),
+//! for environments where the private key of the service account isn't
+//! available, eg. when using the metadata server or workload identity.
+//!
+//! The source token used to authorize the requests needs the
+//! `https://www.googleapis.com/auth/cloud-platform` scope and the
+//! `roles/iam.serviceAccountTokenCreator` role on the service account.
+//!
+//! The requests are sent to the IAM credentials API of the specified universe,
+//! which is [`DEFAULT_UNIVERSE_DOMAIN`](super::DEFAULT_UNIVERSE_DOMAIN) unless
+//! running in eg. Google Distributed Cloud, where it is available from
+//! [`MetadataServerProviderInner::universe_domain`](super::metadata_server::MetadataServerProviderInner::universe_domain)
+//! or [`ServiceAccountInfo::universe_domain`](super::ServiceAccountInfo::universe_domain).
+
+use crate::{
+    error::{self, Error},
+    token::Token,
+};
+use std::convert::TryInto;
+
+/// A JWT signed by the IAM credentials API
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedJwt {
+    /// The id of the service account key used to sign the JWT
+    pub key_id: String,
+    /// The signed JWT
+    pub signed_jwt: String,
+}
+
+/// A blob signed by the IAM credentials API
+#[derive(Debug, Clone)]
+pub struct SignedBlob {
+    /// The id of the service account key used to sign the blob
+    pub key_id: String,
+    /// The signature of the blob
+    pub signed_blob: Vec<u8>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SignBlobResponseBody {
+    key_id: String,
+    signed_blob: String,
+}
+
+/// Creates a POST request to the specified method of the service account
+fn prepare_request(
+    source_token: &Token,
+    universe_domain: &str,
+    service_account: &str,
+    method: &str,
+    body: &serde_json::Value,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let json_body = serde_json::to_vec(body)?;
+    let token_header_value: http::HeaderValue = source_token.clone().try_into()?;
+
+    let request = crate::token::request_builder()
+        .method("POST")
+        .uri(format!(
+            "{}/{}:{}",
+            super::iam_credentials_url(universe_domain),
+            service_account,
+            method
+        ))
+        .header(
+            http::header::CONTENT_TYPE,
+            "application/json; charset=utf-8",
+        )
+        .header(http::header::CONTENT_LENGTH, json_body.len())
+        .header(http::header::AUTHORIZATION, token_header_value)
+        .body(json_body)?;
+
+    Ok(request)
+}
+
+/// Creates a request that signs a JWT with the specified claims with a key of
+/// the service account, authorized by the source token.
+///
+/// See <https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/signJwt>
+pub fn sign_jwt_request<T>(
+    source_token: &Token,
+    universe_domain: &str,
+    service_account: &str,
+    claims: &T,
+) -> Result<http::Request<Vec<u8>>, Error>
+where
+    T: serde::Serialize,
+{
+    let payload = serde_json::to_string(claims)?;
+
+    prepare_request(
+        source_token,
+        universe_domain,
+        service_account,
+        "signJwt",
+        &serde_json::json!({ "payload": payload }),
+    )
+}
+
+/// Parses the response to a [`sign_jwt_request`]
+pub fn parse_sign_jwt_response<S>(response: http::Response<S>) -> Result<SignedJwt, Error>
+where
+    S: AsRef<[u8]>,
+{
+    let (parts, body) = response.into_parts();
+
+    if !parts.status.is_success() {
+        return Err(error::from_response(&parts, body.as_ref()));
+    }
+
+    Ok(serde_json::from_slice(body.as_ref())?)
+}
+
+/// Creates a request that signs the blob with a key of the service account,
+/// authorized by the source token.
+///
+/// See <https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/signBlob>
+pub fn sign_blob_request(
+    source_token: &Token,
+    universe_domain: &str,
+    service_account: &str,
+    blob: &[u8],
+) -> Result<http::Request<Vec<u8>>, Error> {
+    prepare_request(
+        source_token,
+        universe_domain,
+        service_account,
+        "signBlob",
+        &serde_json::json!({ "payload": data_encoding::BASE64.encode(blob) }),
+    )
+}
+
+/// Parses the response to a [`sign_blob_request`]
+pub fn parse_sign_blob_response<S>(response: http::Response<S>) -> Result<SignedBlob, Error>
+where
+    S: AsRef<[u8]>,
+{
+    let (parts, body) = response.into_parts();
+
+    if !parts.status.is_success() {
+        return Err(error::from_response(&parts, body.as_ref()));
+    }
+
+    let body: SignBlobResponseBody = serde_json::from_slice(body.as_ref())?;
+
+    Ok(SignedBlob {
+        key_id: body.key_id,
+        signed_blob: data_encoding::BASE64.decode(body.signed_blob.as_bytes())?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gcp::DEFAULT_UNIVERSE_DOMAIN;
+
+    const SERVICE_ACCOUNT: &str = "signer@project.iam.gserviceaccount.com";
+
+    fn source_token() -> Token {
+        Token {
+            access_token: "source-token".into(),
+            refresh_token: String::new(),
+            token_type: "Bearer".into(),
+            expires_in: Some(3599),
+            expires_in_timestamp: None,
+            granted_scopes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn sign_jwt() {
+        let request = sign_jwt_request(
+            &source_token(),
+            DEFAULT_UNIVERSE_DOMAIN,
+            SERVICE_ACCOUNT,
+            &serde_json::json!({ "sub": SERVICE_ACCOUNT }),
+        )
+        .unwrap();
+
+        assert_eq!(
+            request.uri(),
+            "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/signer@project.iam.gserviceaccount.com:signJwt"
+        );
+        assert_eq!(
+            request.headers()[http::header::AUTHORIZATION],
+            "Bearer source-token"
+        );
+
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(
+            body["payload"],
+            r#"{"sub":"signer@project.iam.gserviceaccount.com"}"#
+        );
+
+        let signed = parse_sign_jwt_response(http::Response::new(
+            r#"{"keyId":"key-id","signedJwt":"header.claims.signature"}"#,
+        ))
+        .unwrap();
+        assert_eq!(signed.key_id, "key-id");
+        assert_eq!(signed.signed_jwt, "header.claims.signature");
+    }
+
+    #[test]
+    fn sign_blob() {
+        let request = sign_blob_request(
+            &source_token(),
+            "example-universe.goog",
+            SERVICE_ACCOUNT,
+            b"blob",
+        )
+        .unwrap();
+
+        assert_eq!(
+            request.uri(),
+            "https://iamcredentials.example-universe.goog/v1/projects/-/serviceAccounts/signer@project.iam.gserviceaccount.com:signBlob"
+        );
+
+        let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+        assert_eq!(body["payload"], "YmxvYg==");
+
+        let signed = parse_sign_blob_response(http::Response::new(
+            r#"{"keyId":"key-id","signedBlob":"c2lnbmF0dXJl"}"#,
+        ))
+        .unwrap();
+        assert_eq!(signed.key_id, "key-id");
+        assert_eq!(signed.signed_blob, b"signature");
+
+        let response = http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body("")
+            .unwrap();
+        assert!(matches!(
+            parse_sign_blob_response(response),
+            Err(Error::HttpStatus { status, .. }) if status == http::StatusCode::FORBIDDEN
+        ));
+    }
+}
@@ -1,9 +1,21 @@
 use std::time::SystemTime;
 
-use crate::{token::RequestReason, token_cache::CacheableToken, Error};
+#[cfg(feature = "jwt")]
+use crate::jwt::{self, JwkSet};
+use crate::{
+    token::{requests_eq, RequestReason},
+    token_cache::CacheableToken,
+    Error,
+};
 
 /// Represents a id token as returned by `OAuth2` servers.
-#[derive(Clone, PartialEq, Eq, Debug)]
+///
+/// The token can be serialized, eg. to cache it on disk across restarts.
+/// When it is deserialized, the expiration is decoded from the token itself
+/// again rather than trusting the serialized one, so deserializing fails if
+/// the token isn't a JWT with an `exp` claim.
+#[derive(Clone, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "SerializedIdToken")]
 pub struct IdToken {
     pub token: String,
     pub expiration: SystemTime,
@@ -12,35 +24,233 @@ pub struct IdToken {
 impl IdToken {
     pub fn new(token: String) -> Result<IdToken, Error> {
         // Extract the exp claim from the token, so we can know if the token is expired or not.
-        let claims = token.split('.').nth(1).ok_or(Error::InvalidTokenFormat)?;
+        let claims: TokenClaims = decode_claims(&token)?;
+        let expiration = claims
+            .exp
+            .and_then(|exp| SystemTime::UNIX_EPOCH.checked_add(std::time::Duration::from_secs(exp)))
+            .ok_or(Error::InvalidTokenFormat)?;
 
-        let decoded = data_encoding::BASE64URL_NOPAD.decode(claims.as_bytes())?;
-        let claims: TokenClaims = serde_json::from_slice(&decoded)?;
+        Ok(Self { token, expiration })
+    }
 
-        Ok(Self {
-            token,
-            expiration: SystemTime::UNIX_EPOCH
-                .checked_add(std::time::Duration::from_secs(claims.exp))
-                .unwrap_or(SystemTime::UNIX_EPOCH),
-        })
+    /// Creates a token with the specified expiration without decoding it,
+    /// mainly useful for test fixtures and mock providers
+    pub fn from_parts(token: impl Into<String>, expiration: SystemTime) -> Self {
+        Self {
+            token: token.into(),
+            expiration,
+        }
+    }
+
+    /// Decodes the claims of the token.
+    ///
+    /// Note that this does **not** verify the signature of the token, use
+    /// [`IdToken::verify`] if the token was received from an untrusted source.
+    pub fn claims(&self) -> Result<IdTokenClaims, Error> {
+        decode_claims(&self.token)
     }
 }
 
+/// The serialized form of an [`IdToken`], only the token itself is used, the
+/// serialized `expiration` is ignored
+#[derive(serde::Deserialize)]
+struct SerializedIdToken {
+    token: String,
+}
+
+impl std::convert::TryFrom<SerializedIdToken> for IdToken {
+    type Error = Error;
+
+    fn try_from(serialized: SerializedIdToken) -> Result<Self, Error> {
+        IdToken::new(serialized.token)
+    }
+}
+
+/// Decodes the claims, ie. the second segment, of a JWT. JWTs are supposed to
+/// be encoded without padding, but some issuers add it anyway, so it's
+/// stripped before decoding.
+fn decode_claims<T: serde::de::DeserializeOwned>(token: &str) -> Result<T, Error> {
+    let claims = token.split('.').nth(1).ok_or(Error::InvalidTokenFormat)?;
+
+    let decoded = data_encoding::BASE64URL_NOPAD.decode(claims.trim_end_matches('=').as_bytes())?;
+    Ok(serde_json::from_slice(&decoded)?)
+}
+
+/// The [claims](https://developers.google.com/identity/openid-connect/openid-connect#an-id-tokens-payload)
+/// of an id token
+#[derive(serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct IdTokenClaims {
+    /// The issuer, eg. `https://accounts.google.com`
+    pub iss: String,
+    /// The audience the token was created for
+    pub aud: String,
+    /// The unique id of the account the token was created for
+    pub sub: String,
+    /// The time the token expires, in seconds since the epoch
+    pub exp: u64,
+    /// The time the token was issued, in seconds since the epoch
+    pub iat: u64,
+    /// The email of the account, only present if requested when the token
+    /// was created
+    pub email: Option<String>,
+    /// Whether the email has been verified by Google
+    pub email_verified: Option<bool>,
+    /// The authorized party, ie. the client the token was issued to, if it
+    /// differs from the audience
+    pub azp: Option<String>,
+    /// Any other claims in the token, eg. `hd`
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl IdTokenClaims {
+    /// Decodes the Compute Engine instance claims from the `google` claim,
+    /// which the metadata server only includes if the token was requested
+    /// with the `Full` or `FullWithLicenses` `IdTokenFormat`, eg. to attest
+    /// which instance the token was created on.
+    ///
+    /// Returns `None` if the token has no instance claims.
+    pub fn compute_engine(&self) -> Result<Option<ComputeEngineClaims>, Error> {
+        match self
+            .extra
+            .get("google")
+            .and_then(|google| google.get("compute_engine"))
+        {
+            Some(claims) => Ok(Some(serde_json::from_value(claims.clone())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The [instance claims](https://cloud.google.com/compute/docs/instances/verifying-instance-identity#payload)
+/// of an id token created by the Compute Engine metadata server
+#[derive(serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ComputeEngineClaims {
+    /// The id of the project the instance is in
+    pub project_id: String,
+    /// The number of the project the instance is in
+    pub project_number: u64,
+    /// The zone the instance is in, eg. `us-central1-a`
+    pub zone: String,
+    /// The unique id of the instance
+    pub instance_id: String,
+    /// The name of the instance
+    pub instance_name: String,
+    /// The time the instance was created, in seconds since the epoch
+    pub instance_creation_timestamp: u64,
+    /// The license codes of the instance's image, only present if requested
+    /// with the `FullWithLicenses` `IdTokenFormat`
+    #[serde(default)]
+    pub license_id: Vec<String>,
+}
+
 impl CacheableToken for IdToken {
-    /// Returns true if token is expired.
     #[inline]
     fn has_expired(&self) -> bool {
+        self.expires_at()
+            .is_none_or(|expires_at| expires_at <= SystemTime::now())
+    }
+
+    #[inline]
+    fn expires_at(&self) -> Option<SystemTime> {
         if self.token.is_empty() {
-            return true;
+            return None;
+        }
+
+        Some(self.expiration)
+    }
+}
+
+/// The URL of the [JWK set](https://tools.ietf.org/html/rfc7517#section-5)
+/// Google signs id tokens with
+#[cfg(feature = "gcp")]
+pub const GOOGLE_JWKS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+
+/// The issuers of id tokens signed by Google
+#[cfg(feature = "jwt")]
+const GOOGLE_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
+
+/// Creates a request for the keys Google signs id tokens with. The response
+/// can be deserialized with [`JwkSet::parse_response`], and the keys should be
+/// cached for its [`JwkSet::max_age`].
+#[cfg(feature = "gcp")]
+pub fn google_jwks_request() -> Result<http::Request<Vec<u8>>, Error> {
+    JwkSet::fetch_request(GOOGLE_JWKS_URL)
+}
+
+#[cfg(feature = "jwt")]
+impl IdToken {
+    /// Verifies that the token is signed by one of the keys in the set, as
+    /// identified by the `kid` in its header, and that it was issued by
+    /// Google for the specified audience and hasn't expired, returning its
+    /// claims.
+    ///
+    /// Only RS256 signed tokens, as issued by Google's token endpoints and
+    /// the metadata server, are supported. If the token is signed with a key
+    /// that isn't in the set, [`Error::UnknownKeyId`] is returned, which
+    /// might mean the keys have been rotated and need to be retrieved again
+    /// with [`google_jwks_request`].
+    pub fn verify(&self, keys: &JwkSet, audience: &str) -> Result<IdTokenClaims, Error> {
+        self.verify_claims(keys, audience, None)
+    }
+
+    /// Like [`IdToken::verify`], but also verifies that the token was issued
+    /// to the specified authorized party, ie. that its `azp` claim is the
+    /// expected client id, which Google recommends checking in addition to
+    /// the audience, eg. for tokens from Identity-Aware Proxy
+    pub fn verify_with_azp(
+        &self,
+        keys: &JwkSet,
+        audience: &str,
+        authorized_party: &str,
+    ) -> Result<IdTokenClaims, Error> {
+        self.verify_claims(keys, audience, Some(authorized_party))
+    }
+
+    fn verify_claims(
+        &self,
+        keys: &JwkSet,
+        audience: &str,
+        authorized_party: Option<&str>,
+    ) -> Result<IdTokenClaims, Error> {
+        let header = jwt::decode_header(&self.token)?;
+        let key = keys.key_for(&header)?;
+
+        let (n, e) = key.rsa_components()?;
+        let (_, claims): (_, IdTokenClaims) = jwt::decode(
+            &self.token,
+            &jwt::VerifyingKey::RsaComponents { n: &n, e: &e },
+            &[jwt::Algorithm::RS256],
+        )?;
+
+        if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+            return Err(Error::InvalidClaim("iss"));
+        }
+
+        if claims.aud != audience {
+            return Err(Error::InvalidClaim("aud"));
+        }
+
+        if authorized_party.is_some() && claims.azp.as_deref() != authorized_party {
+            return Err(Error::InvalidClaim("azp"));
         }
 
-        self.expiration <= SystemTime::now()
+        let now = crate::jwt::unix_timestamp(SystemTime::now())?;
+        if claims.exp <= now {
+            return Err(Error::InvalidClaim("exp"));
+        }
+
+        Ok(claims)
     }
 }
 
 /// Either a valid token, or an HTTP request. With some token sources, two different
 /// HTTP requests needs to be performed, one to get an access token and one to get
 /// the actual id token.
+///
+/// Two requests are equal if their method, URI, version, headers and body are
+/// equal, which allows asserting the whole shape of a request in tests.
+#[derive(Debug)]
 pub enum IdTokenOrRequest {
     AccessTokenRequest {
         request: AccessTokenRequest,
@@ -55,6 +265,41 @@ pub enum IdTokenOrRequest {
     IdToken(IdToken),
 }
 
+impl PartialEq for IdTokenOrRequest {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::IdToken(a), Self::IdToken(b)) => a == b,
+            (
+                Self::AccessTokenRequest {
+                    request: a,
+                    reason: a_reason,
+                    audience_hash: a_hash,
+                },
+                Self::AccessTokenRequest {
+                    request: b,
+                    reason: b_reason,
+                    audience_hash: b_hash,
+                },
+            )
+            | (
+                Self::IdTokenRequest {
+                    request: a,
+                    reason: a_reason,
+                    audience_hash: a_hash,
+                },
+                Self::IdTokenRequest {
+                    request: b,
+                    reason: b_reason,
+                    audience_hash: b_hash,
+                },
+            ) => a_reason == b_reason && a_hash == b_hash && requests_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for IdTokenOrRequest {}
+
 pub type IdTokenRequest = http::Request<Vec<u8>>;
 pub type AccessTokenRequest = http::Request<Vec<u8>>;
 
@@ -87,16 +332,138 @@ pub trait IdTokenProvider {
         S: AsRef<[u8]>;
 }
 
+/// Gets an id token for the specified audience from the provider, calling
+/// `send` with each request needed to retrieve a new token if it doesn't have
+/// a valid cached token, and passing the responses back to the provider.
+///
+/// This is the [`IdTokenProvider`] counterpart of
+/// [`drive_token`](crate::drive_token).
+pub fn drive_id_token<P, F>(provider: &P, audience: &str, mut send: F) -> Result<IdToken, Error>
+where
+    P: IdTokenProvider,
+    F: FnMut(http::Request<Vec<u8>>) -> Result<http::Response<Vec<u8>>, Error>,
+{
+    let (request, audience_hash) = match provider.get_id_token(audience)? {
+        IdTokenOrRequest::IdToken(token) => return Ok(token),
+        IdTokenOrRequest::IdTokenRequest {
+            request,
+            audience_hash,
+            ..
+        } => (request, audience_hash),
+        IdTokenOrRequest::AccessTokenRequest {
+            request,
+            audience_hash,
+            ..
+        } => {
+            let response = send(request)?;
+            let request = provider.get_id_token_with_access_token(audience, response)?;
+            (request, audience_hash)
+        }
+    };
+
+    let response = send(request)?;
+    provider.parse_id_token_response(audience_hash, response)
+}
+
 #[derive(serde::Deserialize, Debug)]
 struct TokenClaims {
-    exp: u64,
+    exp: Option<u64>,
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::SystemTime;
 
-    use super::IdToken;
+    use super::{IdToken, IdTokenClaims};
+    use crate::Error;
+
+    #[test]
+    fn drive_id_token() {
+        use super::{IdTokenOrRequest, IdTokenProvider};
+
+        /// An id token provider that needs an access token first
+        struct TwoStepProvider;
+
+        impl IdTokenProvider for TwoStepProvider {
+            fn get_id_token(&self, _audience: &str) -> Result<IdTokenOrRequest, Error> {
+                Ok(IdTokenOrRequest::AccessTokenRequest {
+                    request: http::Request::get("https://example.com/access").body(Vec::new())?,
+                    reason: crate::token::RequestReason::ParametersChanged,
+                    audience_hash: 7,
+                })
+            }
+
+            fn get_id_token_with_access_token<S>(
+                &self,
+                audience: &str,
+                response: http::Response<S>,
+            ) -> Result<http::Request<Vec<u8>>, Error>
+            where
+                S: AsRef<[u8]>,
+            {
+                assert_eq!(audience, "my-aud");
+                Ok(http::Request::post("https://example.com/id-token")
+                    .body(response.body().as_ref().to_vec())?)
+            }
+
+            fn parse_id_token_response<S>(
+                &self,
+                hash: u64,
+                response: http::Response<S>,
+            ) -> Result<IdToken, Error>
+            where
+                S: AsRef<[u8]>,
+            {
+                assert_eq!(hash, 7);
+                Ok(IdToken::from_parts(
+                    std::str::from_utf8(response.body().as_ref()).unwrap(),
+                    SystemTime::UNIX_EPOCH,
+                ))
+            }
+        }
+
+        let mut sent = Vec::new();
+        let token = super::drive_id_token(&TwoStepProvider, "my-aud", |request| {
+            sent.push(request.uri().to_string());
+            let body = if request.uri().path() == "/access" {
+                b"access-token".to_vec()
+            } else {
+                [b"id-token-for-".as_slice(), request.body()].concat()
+            };
+            Ok(http::Response::new(body))
+        })
+        .unwrap();
+
+        assert_eq!(token.token, "id-token-for-access-token");
+        assert_eq!(
+            sent,
+            ["https://example.com/access", "https://example.com/id-token"]
+        );
+    }
+
+    #[test]
+    fn invalid_exp() {
+        let token = |claims: &str| {
+            format!(
+                "eyJhbGciOiJSUzI1NiJ9.{}.c2ln",
+                data_encoding::BASE64URL_NOPAD.encode(claims.as_bytes())
+            )
+        };
+
+        assert!(matches!(
+            IdToken::new(token(r#"{"aud":"my-aud"}"#)),
+            Err(Error::InvalidTokenFormat)
+        ));
+        assert!(matches!(
+            IdToken::new(token(&format!(r#"{{"exp":{}}}"#, u64::MAX))),
+            Err(Error::InvalidTokenFormat)
+        ));
+        assert!(matches!(
+            IdToken::new("not-a-jwt".to_owned()),
+            Err(Error::InvalidTokenFormat)
+        ));
+        assert!(IdToken::new(token(r#"{"exp":1676641773}"#)).is_ok());
+    }
 
     #[test]
     fn test_decode_jwt() {
@@ -136,5 +503,225 @@ mod tests {
                 .as_secs(),
             1676641773
         );
+
+        let claims = id_token.claims().unwrap();
+        assert_eq!(claims.iss, "https://accounts.google.com");
+        assert_eq!(claims.aud, "my-aud");
+        assert_eq!(claims.sub, "1234");
+        assert_eq!(claims.exp, 1676641773);
+        assert_eq!(claims.iat, 1676638173);
+        assert_eq!(claims.email.as_deref(), Some("test@example.com"));
+        assert_eq!(claims.email_verified, Some(true));
+        assert_eq!(claims.azp.as_deref(), Some("123"));
+        assert_eq!(claims.extra["key"], "~~~?");
+    }
+
+    #[test]
+    fn compute_engine_claims() {
+        let claims = |json: serde_json::Value| -> IdTokenClaims {
+            let mut claims = serde_json::json!({
+                "iss": "https://accounts.google.com",
+                "aud": "my-aud",
+                "sub": "1234",
+                "exp": 1676641773,
+                "iat": 1676638173,
+            });
+            claims["google"] = json;
+            serde_json::from_value(claims).unwrap()
+        };
+
+        let ce = claims(serde_json::json!({
+            "compute_engine": {
+                "project_id": "my-project",
+                "project_number": 739419398126u64,
+                "zone": "us-west1-a",
+                "instance_id": "152986662232938449",
+                "instance_name": "my-instance",
+                "instance_creation_timestamp": 1496269007,
+                "license_id": ["1000204"],
+            }
+        }))
+        .compute_engine()
+        .unwrap()
+        .unwrap();
+        assert_eq!(ce.project_id, "my-project");
+        assert_eq!(ce.project_number, 739419398126);
+        assert_eq!(ce.zone, "us-west1-a");
+        assert_eq!(ce.instance_id, "152986662232938449");
+        assert_eq!(ce.instance_name, "my-instance");
+        assert_eq!(ce.instance_creation_timestamp, 1496269007);
+        assert_eq!(ce.license_id, ["1000204"]);
+
+        let standard: IdTokenClaims = serde_json::from_value(serde_json::json!({
+            "iss": "https://accounts.google.com",
+            "aud": "my-aud",
+            "sub": "1234",
+            "exp": 1676641773,
+            "iat": 1676638173,
+        }))
+        .unwrap();
+        assert!(standard.compute_engine().unwrap().is_none());
+
+        assert!(matches!(
+            claims(serde_json::json!({ "compute_engine": { "zone": 1 } })).compute_engine(),
+            Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let token = |exp: u64| {
+            format!(
+                "eyJhbGciOiJSUzI1NiJ9.{}.c2ln",
+                data_encoding::BASE64URL_NOPAD.encode(format!(r#"{{"exp":{}}}"#, exp).as_bytes())
+            )
+        };
+
+        let id_token = IdToken::new(token(1676641773)).unwrap();
+        let serialized = serde_json::to_string(&id_token).unwrap();
+        let deserialized: IdToken = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, id_token);
+
+        // A tampered expiration is replaced by the one in the token
+        let mut tampered: serde_json::Value = serde_json::from_str(&serialized).unwrap();
+        tampered["expiration"] = serde_json::to_value(SystemTime::now()).unwrap();
+        let deserialized: IdToken = serde_json::from_value(tampered).unwrap();
+        assert_eq!(deserialized.expiration, id_token.expiration);
+
+        assert!(serde_json::from_str::<IdToken>(r#"{"token":"not-a-jwt"}"#).is_err());
+    }
+
+    #[test]
+    fn padded_claims() {
+        let claims = data_encoding::BASE64URL.encode(br#"{"exp": 1676641773}"#);
+        assert!(claims.ends_with('='));
+
+        let id_token = IdToken::new(format!("eyJhbGciOiJSUzI1NiJ9.{}.c2ln", claims)).unwrap();
+        assert_eq!(
+            id_token
+                .expiration
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            1676641773
+        );
+    }
+
+    #[cfg(feature = "jwt")]
+    mod verify {
+        use super::super::*;
+        use ring::signature;
+
+        const KID: &str = "test-key";
+
+        fn jwk_set(private_key: &[u8]) -> JwkSet {
+            let key_pair = signature::RsaKeyPair::from_pkcs8(private_key).unwrap();
+            let components = signature::RsaPublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+
+            let jwks = serde_json::json!({
+                "keys": [{
+                    "kty": "RSA",
+                    "alg": "RS256",
+                    "use": "sig",
+                    "kid": "other-key",
+                    "n": data_encoding::BASE64URL_NOPAD.encode(&components.e),
+                    "e": data_encoding::BASE64URL_NOPAD.encode(&components.e),
+                }, {
+                    "kty": "RSA",
+                    "alg": "RS256",
+                    "use": "sig",
+                    "kid": KID,
+                    "n": data_encoding::BASE64URL_NOPAD.encode(&components.n),
+                    "e": data_encoding::BASE64URL_NOPAD.encode(&components.e),
+                }]
+            });
+
+            JwkSet::parse_response(http::Response::new(jwks.to_string())).unwrap()
+        }
+
+        fn id_token(private_key: &[u8], kid: &str, claims: &serde_json::Value) -> IdToken {
+            let mut header = jwt::Header::new(jwt::Algorithm::RS256);
+            header.kid = Some(kid.to_owned());
+
+            let token = jwt::encode(&header, claims, jwt::Key::Pkcs8(private_key)).unwrap();
+            IdToken::new(token).unwrap()
+        }
+
+        #[test]
+        fn verify_id_token() {
+            let private_key = crate::jwt::test::test_key();
+            let keys = jwk_set(&private_key);
+
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let claims = serde_json::json!({
+                "iss": "https://accounts.google.com",
+                "aud": "my-aud",
+                "sub": "1234",
+                "email": "test@example.com",
+                "email_verified": true,
+                "iat": now,
+                "exp": now + 3600,
+            });
+
+            let verified = id_token(&private_key, KID, &claims)
+                .verify(&keys, "my-aud")
+                .unwrap();
+            assert_eq!(verified.sub, "1234");
+            assert_eq!(verified.email.as_deref(), Some("test@example.com"));
+            assert_eq!(verified.email_verified, Some(true));
+
+            assert!(matches!(
+                id_token(&private_key, KID, &claims).verify(&keys, "other-aud"),
+                Err(Error::InvalidClaim("aud"))
+            ));
+
+            // The token has no authorized party, so it can't match one
+            assert!(matches!(
+                id_token(&private_key, KID, &claims).verify_with_azp(&keys, "my-aud", "client"),
+                Err(Error::InvalidClaim("azp"))
+            ));
+
+            let mut with_azp = claims.clone();
+            with_azp["azp"] = "client".into();
+            let verified = id_token(&private_key, KID, &with_azp)
+                .verify_with_azp(&keys, "my-aud", "client")
+                .unwrap();
+            assert_eq!(verified.azp.as_deref(), Some("client"));
+            assert!(matches!(
+                id_token(&private_key, KID, &with_azp).verify_with_azp(
+                    &keys,
+                    "my-aud",
+                    "other-client"
+                ),
+                Err(Error::InvalidClaim("azp"))
+            ));
+
+            assert!(matches!(
+                id_token(&private_key, "unknown-key", &claims).verify(&keys, "my-aud"),
+                Err(Error::UnknownKeyId(Some(_)))
+            ));
+
+            assert!(matches!(
+                id_token(&private_key, "other-key", &claims).verify(&keys, "my-aud"),
+                Err(Error::SignatureInvalid)
+            ));
+
+            let mut bad_issuer = claims.clone();
+            bad_issuer["iss"] = "https://example.com".into();
+            assert!(matches!(
+                id_token(&private_key, KID, &bad_issuer).verify(&keys, "my-aud"),
+                Err(Error::InvalidClaim("iss"))
+            ));
+
+            let mut expired = claims;
+            expired["exp"] = (now - 60).into();
+            assert!(matches!(
+                id_token(&private_key, KID, &expired).verify(&keys, "my-aud"),
+                Err(Error::InvalidClaim("exp"))
+            ));
+        }
     }
 }
@@ -1,7 +1,11 @@
-use crate::Error;
+//! Minimal support for encoding (signing) and decoding (verifying)
+//! [JSON Web Tokens](https://jwt.io/)
+
+use crate::{error, Error};
 use ring::signature;
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 
+#[cfg(feature = "gcp")]
 #[derive(Serialize)]
 pub(crate) struct Claims {
     #[serde(rename = "iss")]
@@ -12,9 +16,15 @@ pub(crate) struct Claims {
     pub(crate) expiration: i64,
     #[serde(rename = "iat")]
     pub(crate) issued_at: i64,
+    #[serde(rename = "nbf", skip_serializing_if = "Option::is_none")]
+    pub(crate) not_before: Option<i64>,
     #[serde(rename = "sub")]
     pub(crate) subject: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub(crate) scope: String,
+    /// Additional claims, which must not include any of the above
+    #[serde(flatten)]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
 }
 
 /// A basic JWT header, the alg defaults to HS256 and typ is automatically
@@ -120,19 +130,229 @@ pub enum Key<'a> {
     Pkcs8(&'a [u8]),
 }
 
+/// The supported public key formats used to verify signatures, see the
+/// documentation for [`ring::signature::UnparsedPublicKey`] for more information
+pub enum VerifyingKey<'a> {
+    /// A DER encoded `RSAPublicKey` as described in [RFC 8017](https://tools.ietf.org/html/rfc8017#appendix-A.1.1)
+    RsaDer(&'a [u8]),
+    /// The big-endian encoded modulus and exponent of an RSA public key, as
+    /// found in the `n` and `e` members of a JWK
+    RsaComponents { n: &'a [u8], e: &'a [u8] },
+}
+
+/// A [JSON Web Key Set](https://tools.ietf.org/html/rfc7517#section-5), eg.
+/// the public keys an identity provider signs its tokens with
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+    /// How long the key set can be cached before it should be retrieved
+    /// again, as specified by the `Cache-Control` header of the response, if
+    /// any
+    #[serde(skip)]
+    pub max_age: Option<std::time::Duration>,
+}
+
+impl JwkSet {
+    /// Creates a request for the key set at the specified URL, eg.
+    /// <https://www.googleapis.com/oauth2/v3/certs> for the keys Google signs
+    /// id tokens with
+    pub fn fetch_request(url: &str) -> Result<http::Request<Vec<u8>>, Error> {
+        Ok(crate::token::request_builder()
+            .method("GET")
+            .uri(url)
+            .body(Vec::new())?)
+    }
+
+    /// Once a response has been received for a request for a key set, call
+    /// this method to deserialize it. The keys rotate, so the caller should
+    /// request the key set again once its [`JwkSet::max_age`] has passed.
+    pub fn parse_response<S>(response: http::Response<S>) -> Result<Self, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let (parts, body) = response.into_parts();
+
+        if !parts.status.is_success() {
+            return Err(error::from_response(&parts, body.as_ref()));
+        }
+
+        let mut key_set: Self = serde_json::from_slice(body.as_ref())?;
+        key_set.max_age = max_age(&parts.headers);
+        Ok(key_set)
+    }
+
+    /// Finds the key with the specified key id
+    pub fn key_by_id(&self, kid: &str) -> Option<&Jwk> {
+        self.keys.iter().find(|key| key.kid.as_deref() == Some(kid))
+    }
+
+    /// Finds the key to verify a JWT with the specified header, ie. the one
+    /// with the header's `kid`, checking that the key's `use` and `alg`, if
+    /// present, allow it to verify signatures with the header's algorithm
+    pub fn key_for(&self, header: &Header) -> Result<&Jwk, Error> {
+        let kid = header.kid.as_deref().ok_or(Error::UnknownKeyId(None))?;
+        let key = self
+            .key_by_id(kid)
+            .ok_or_else(|| Error::UnknownKeyId(Some(kid.to_owned())))?;
+
+        let use_matches = key
+            .key_use
+            .as_deref()
+            .is_none_or(|key_use| key_use == "sig");
+        let alg_matches = key.alg.as_deref().is_none_or(|alg| {
+            serde_json::to_value(header.alg)
+                .ok()
+                .is_some_and(|header_alg| header_alg == alg)
+        });
+
+        if use_matches && alg_matches {
+            Ok(key)
+        } else {
+            Err(Error::IncompatibleKey(kid.to_owned()))
+        }
+    }
+}
+
+/// Gets the remaining time a response can be cached for from its
+/// `Cache-Control` `max-age` directive, minus its `Age`, if any
+fn max_age(headers: &http::HeaderMap) -> Option<std::time::Duration> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let max_age = header(http::header::CACHE_CONTROL)?
+        .split(',')
+        .find_map(|directive| {
+            let (name, value) = directive.trim().split_once('=')?;
+            name.eq_ignore_ascii_case("max-age")
+                .then(|| value.trim_matches('"').parse::<u64>().ok())
+                .flatten()
+        })?;
+    let age = header(http::header::AGE)
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    Some(std::time::Duration::from_secs(max_age.saturating_sub(age)))
+}
+
+/// A [JSON Web Key](https://tools.ietf.org/html/rfc7517#section-4). Only the
+/// members needed to verify signatures with RSA keys are deserialized.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Jwk {
+    /// The key type, eg. `RSA`
+    pub kty: String,
+    /// The key id, matched against the `kid` in the header of a JWT
+    pub kid: Option<String>,
+    /// The algorithm the key is intended to be used with
+    pub alg: Option<String>,
+    /// What the key is intended to be used for, `sig` for verifying
+    /// signatures
+    #[serde(rename = "use")]
+    pub key_use: Option<String>,
+    /// The base64url encoded modulus of an RSA key
+    pub n: Option<String>,
+    /// The base64url encoded exponent of an RSA key
+    pub e: Option<String>,
+}
+
+impl Jwk {
+    /// Decodes the big-endian modulus and exponent of an RSA key, which can
+    /// be used as a [`VerifyingKey::RsaComponents`]
+    pub fn rsa_components(&self) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        match (self.kty.as_str(), &self.n, &self.e) {
+            ("RSA", Some(n), Some(e)) => Ok((
+                data_encoding::BASE64URL_NOPAD.decode(n.as_bytes())?,
+                data_encoding::BASE64URL_NOPAD.decode(e.as_bytes())?,
+            )),
+            _ => Err(Error::InvalidKeyFormat),
+        }
+    }
+}
+
+/// Gets the number of seconds from the UNIX epoch to `now`, as used in the
+/// `iat` and `exp` claims
+pub(crate) fn unix_timestamp(now: std::time::SystemTime) -> Result<u64, Error> {
+    now.duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .map_err(|_e| Error::ClockBeforeEpoch)
+}
+
 /// Serializes to JSON and encodes to base64
 pub fn to_jwt_part<T: Serialize>(input: &T) -> Result<String, Error> {
-    let json = serde_json::to_string(input)?;
-    Ok(data_encoding::BASE64URL_NOPAD.encode(json.as_bytes()))
+    let mut output = String::new();
+    append_jwt_part(input, &mut output)?;
+    Ok(output)
+}
+
+/// Like [`to_jwt_part`], but appends to `output`, which can be reused. The
+/// JSON is encoded as it is serialized, so large claims aren't held in memory
+/// twice. If serialization fails, `output` may contain part of the encoding.
+pub fn append_jwt_part<T: Serialize>(input: &T, output: &mut String) -> Result<(), Error> {
+    let encoding = data_encoding::BASE64URL_NOPAD;
+    let mut encoder = Base64Writer(encoding.new_encoder(output));
+    serde_json::to_writer(&mut encoder, input)?;
+    Ok(())
+}
+
+/// Adapts a base64 encoder to [`std::io::Write`], so JSON can be serialized
+/// directly into it. The encoding is finalized when it is dropped.
+struct Base64Writer<'a>(data_encoding::Encoder<'a>);
+
+impl std::io::Write for Base64Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.append(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Signs the payload of a JWT.
+///
+/// This is implemented for [`Key`], but can also be implemented for other
+/// types, eg. to delegate signing to an HSM or a cloud KMS so that the private
+/// key never has to be held in process memory.
+pub trait Signer {
+    /// Signs the input using the algorithm given, returning the raw
+    /// (ie. not base64 encoded) signature
+    fn sign(&self, input: &[u8], algorithm: Algorithm) -> Result<Vec<u8>, Error>;
+}
+
+impl Signer for Key<'_> {
+    fn sign(&self, input: &[u8], algorithm: Algorithm) -> Result<Vec<u8>, Error> {
+        sign_key(self, input, algorithm, &ring::rand::SystemRandom::new())
+    }
 }
 
-/// The actual RSA signing + encoding
+/// Signs the input with a key, using the specified source of randomness
+fn sign_key(
+    key: &Key<'_>,
+    input: &[u8],
+    algorithm: Algorithm,
+    rng: &dyn ring::rand::SecureRandom,
+) -> Result<Vec<u8>, Error> {
+    let alg: &'static dyn signature::RsaEncoding = match algorithm {
+        Algorithm::RS256 => &signature::RSA_PKCS1_SHA256,
+        Algorithm::RS384 => &signature::RSA_PKCS1_SHA384,
+        Algorithm::RS512 => &signature::RSA_PKCS1_SHA512,
+
+        Algorithm::PS256 => &signature::RSA_PSS_SHA256,
+        Algorithm::PS384 => &signature::RSA_PSS_SHA384,
+        Algorithm::PS512 => &signature::RSA_PSS_SHA512,
+        _ => return Err(Error::UnsupportedAlgorithm(algorithm)),
+    };
+
+    sign_rsa(alg, key, input, rng)
+}
+
+/// The actual RSA signing
 /// Taken from Ring doc <https://briansmith.org/rustdoc/ring/signature/index.html>
 fn sign_rsa(
     alg: &'static dyn signature::RsaEncoding,
-    key: Key<'_>,
-    signing_input: &str,
-) -> Result<String, Error> {
+    key: &Key<'_>,
+    signing_input: &[u8],
+    rng: &dyn ring::rand::SecureRandom,
+) -> Result<Vec<u8>, Error> {
     let key_pair = match key {
         Key::Pkcs8(bytes) => {
             signature::RsaKeyPair::from_pkcs8(bytes).map_err(Error::InvalidRsaKeyRejected)?
@@ -141,12 +361,11 @@ fn sign_rsa(
 
     let key_pair = std::sync::Arc::new(key_pair);
     let mut signature = vec![0; key_pair.public().modulus_len()];
-    let rng = ring::rand::SystemRandom::new();
     key_pair
-        .sign(alg, &rng, signing_input.as_bytes(), &mut signature)
+        .sign(alg, rng, signing_input, &mut signature)
         .map_err(Error::InvalidRsaKey)?;
 
-    Ok(data_encoding::BASE64_NOPAD.encode(&signature))
+    Ok(signature)
 }
 
 /// Take the payload of a JWT, sign it using the algorithm given and return
@@ -154,23 +373,435 @@ fn sign_rsa(
 ///
 /// Only use this function if you want to do something other than JWT.
 pub fn sign(signing_input: &str, key: Key<'_>, algorithm: Algorithm) -> Result<String, Error> {
-    match algorithm {
-        Algorithm::RS256 => sign_rsa(&signature::RSA_PKCS1_SHA256, key, signing_input),
-        Algorithm::RS384 => sign_rsa(&signature::RSA_PKCS1_SHA384, key, signing_input),
-        Algorithm::RS512 => sign_rsa(&signature::RSA_PKCS1_SHA512, key, signing_input),
+    sign_with_rng(
+        signing_input,
+        key,
+        algorithm,
+        &ring::rand::SystemRandom::new(),
+    )
+}
 
-        Algorithm::PS256 => sign_rsa(&signature::RSA_PSS_SHA256, key, signing_input),
-        Algorithm::PS384 => sign_rsa(&signature::RSA_PSS_SHA384, key, signing_input),
-        Algorithm::PS512 => sign_rsa(&signature::RSA_PSS_SHA512, key, signing_input),
-        _ => panic!("Unsupported algorithm {:?}", algorithm),
-    }
+/// Like [`sign`], but with the specified source of randomness, eg. to reuse a
+/// single [`SystemRandom`](ring::rand::SystemRandom) when signing many
+/// payloads, or to use a fixed source in deterministic tests. Only the PSS
+/// algorithms use randomness when signing.
+pub fn sign_with_rng(
+    signing_input: &str,
+    key: Key<'_>,
+    algorithm: Algorithm,
+    rng: &dyn ring::rand::SecureRandom,
+) -> Result<String, Error> {
+    let signature = sign_key(&key, signing_input.as_bytes(), algorithm, rng)?;
+    Ok(data_encoding::BASE64URL_NOPAD.encode(&signature))
 }
 
 pub fn encode<T: Serialize>(header: &Header, claims: &T, key: Key<'_>) -> Result<String, Error> {
-    let encoded_header = to_jwt_part(&header)?;
-    let encoded_claims = to_jwt_part(&claims)?;
-    let signing_input = [encoded_header.as_ref(), encoded_claims.as_ref()].join(".");
-    let signature = sign(&signing_input, key, header.alg)?;
+    encode_with_signer(header, claims, &key)
+}
+
+/// Like [`encode`], but the signing is done by the [`Signer`] given rather
+/// than a [`Key`]
+pub fn encode_with_signer<T: Serialize>(
+    header: &Header,
+    claims: &T,
+    signer: &dyn Signer,
+) -> Result<String, Error> {
+    let mut token = String::new();
+    append_jwt_part(&header, &mut token)?;
+    token.push('.');
+    append_jwt_part(&claims, &mut token)?;
+
+    let signature = signer.sign(token.as_bytes(), header.alg)?;
+    token.push('.');
+    data_encoding::BASE64URL_NOPAD.encode_append(&signature, &mut token);
+
+    Ok(token)
+}
+
+/// Decodes from base64 and deserializes from JSON
+fn from_jwt_part<T: DeserializeOwned>(input: &str) -> Result<T, Error> {
+    let json = data_encoding::BASE64URL_NOPAD.decode(input.as_bytes())?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Decodes the header of a JWT without verifying its signature, eg. to find
+/// the `kid` of the key needed to verify it
+pub fn decode_header(token: &str) -> Result<Header, Error> {
+    let encoded_header = token.split('.').next().ok_or(Error::InvalidTokenFormat)?;
+    from_jwt_part(encoded_header)
+}
+
+/// Verifies the signature of a JWT payload using the algorithm given
+fn verify(
+    signing_input: &str,
+    signature: &[u8],
+    key: &VerifyingKey<'_>,
+    algorithm: Algorithm,
+) -> Result<(), Error> {
+    let params = match algorithm {
+        Algorithm::RS256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+        Algorithm::RS384 => &signature::RSA_PKCS1_2048_8192_SHA384,
+        Algorithm::RS512 => &signature::RSA_PKCS1_2048_8192_SHA512,
 
-    Ok([signing_input, signature].join("."))
+        Algorithm::PS256 => &signature::RSA_PSS_2048_8192_SHA256,
+        Algorithm::PS384 => &signature::RSA_PSS_2048_8192_SHA384,
+        Algorithm::PS512 => &signature::RSA_PSS_2048_8192_SHA512,
+        _ => return Err(Error::UnsupportedAlgorithm(algorithm)),
+    };
+
+    match key {
+        VerifyingKey::RsaDer(der) => signature::UnparsedPublicKey::new(params, der)
+            .verify(signing_input.as_bytes(), signature),
+        VerifyingKey::RsaComponents { n, e } => signature::RsaPublicKeyComponents { n, e }.verify(
+            params,
+            signing_input.as_bytes(),
+            signature,
+        ),
+    }
+    .map_err(|_e| Error::SignatureInvalid)
+}
+
+/// Decodes a JWT, verifying its signature with the key given, and returns
+/// the deserialized header and claims.
+///
+/// The algorithm specified in the token's header must be one of the
+/// `algorithms` given, otherwise [`Error::SignatureInvalid`] is returned, the
+/// same as when the signature itself doesn't match.
+///
+/// Note that only the signature is verified, validating the claims themselves
+/// (eg. `exp` or `aud`) is left to the caller.
+pub fn decode<T: DeserializeOwned>(
+    token: &str,
+    key: &VerifyingKey<'_>,
+    algorithms: &[Algorithm],
+) -> Result<(Header, T), Error> {
+    let mut segments = token.split('.');
+    let (encoded_header, encoded_claims, encoded_signature) = match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+        _ => return Err(Error::InvalidTokenFormat),
+    };
+
+    let header: Header = from_jwt_part(encoded_header)?;
+    if !algorithms.contains(&header.alg) {
+        return Err(Error::SignatureInvalid);
+    }
+
+    let signing_input = &token[..encoded_header.len() + encoded_claims.len() + 1];
+    let signature = data_encoding::BASE64URL_NOPAD.decode(encoded_signature.as_bytes())?;
+    verify(signing_input, &signature, key, header.alg)?;
+
+    let claims = from_jwt_part(encoded_claims)?;
+    Ok((header, claims))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Gets the DER encoded private key from the test service account key
+    pub(crate) fn test_key() -> Vec<u8> {
+        let key: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/svc_key.json")).unwrap();
+        let pem = key["private_key"].as_str().unwrap();
+        let body = pem.split("-----").nth(2).unwrap();
+        let body: String = body.split_whitespace().collect();
+
+        data_encoding::BASE64.decode(body.as_bytes()).unwrap()
+    }
+
+    fn public_key(private_key: &[u8]) -> Vec<u8> {
+        use signature::KeyPair;
+
+        let key_pair = signature::RsaKeyPair::from_pkcs8(private_key).unwrap();
+        key_pair.public_key().as_ref().to_vec()
+    }
+
+    #[test]
+    fn encode_decode() {
+        let private_key = test_key();
+        let public_key = public_key(&private_key);
+
+        let claims = serde_json::json!({
+            "iss": "test@example.com",
+            "aud": "my-aud",
+            "exp": 1676641773,
+        });
+
+        let token = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            Key::Pkcs8(&private_key),
+        )
+        .unwrap();
+
+        let (header, decoded) = decode::<serde_json::Value>(
+            &token,
+            &VerifyingKey::RsaDer(&public_key),
+            &[Algorithm::RS256],
+        )
+        .unwrap();
+
+        assert_eq!(header, Header::new(Algorithm::RS256));
+        assert_eq!(decoded, claims);
+
+        // The algorithm in the header must be one of the allowed ones
+        assert!(matches!(
+            decode::<serde_json::Value>(
+                &token,
+                &VerifyingKey::RsaDer(&public_key),
+                &[Algorithm::PS256],
+            ),
+            Err(Error::SignatureInvalid)
+        ));
+    }
+
+    #[test]
+    fn clock_before_epoch() {
+        let epoch = std::time::SystemTime::UNIX_EPOCH;
+        assert_eq!(
+            unix_timestamp(epoch + std::time::Duration::from_secs(10)).unwrap(),
+            10
+        );
+        assert!(matches!(
+            unix_timestamp(epoch - std::time::Duration::from_secs(10)),
+            Err(Error::ClockBeforeEpoch)
+        ));
+    }
+
+    #[test]
+    fn sign_with_custom_rng() {
+        let private_key = test_key();
+        let public_key = public_key(&private_key);
+
+        let header = to_jwt_part(&Header::new(Algorithm::PS256)).unwrap();
+        let claims = to_jwt_part(&serde_json::json!({ "iss": "test@example.com" })).unwrap();
+        let signing_input = [header, claims].join(".");
+
+        let fixed = ring::test::rand::FixedByteRandom { byte: 0x42 };
+        let signature = sign_with_rng(
+            &signing_input,
+            Key::Pkcs8(&private_key),
+            Algorithm::PS256,
+            &fixed,
+        )
+        .unwrap();
+
+        // The salt of PSS signatures comes from the rng, so a fixed rng gives
+        // deterministic signatures, but they are still valid
+        assert_eq!(
+            signature,
+            sign_with_rng(
+                &signing_input,
+                Key::Pkcs8(&private_key),
+                Algorithm::PS256,
+                &fixed
+            )
+            .unwrap()
+        );
+        assert_ne!(
+            signature,
+            sign(&signing_input, Key::Pkcs8(&private_key), Algorithm::PS256).unwrap()
+        );
+
+        let token = [signing_input, signature].join(".");
+        decode::<serde_json::Value>(
+            &token,
+            &VerifyingKey::RsaDer(&public_key),
+            &[Algorithm::PS256],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn custom_signer() {
+        /// Signs with a plain key, but keeps track of how many times it was used
+        struct CountingSigner<'a> {
+            key: Key<'a>,
+            count: std::cell::Cell<u32>,
+        }
+
+        impl Signer for CountingSigner<'_> {
+            fn sign(&self, input: &[u8], algorithm: Algorithm) -> Result<Vec<u8>, Error> {
+                self.count.set(self.count.get() + 1);
+                self.key.sign(input, algorithm)
+            }
+        }
+
+        let private_key = test_key();
+        let public_key = public_key(&private_key);
+
+        let signer = CountingSigner {
+            key: Key::Pkcs8(&private_key),
+            count: std::cell::Cell::new(0),
+        };
+
+        let claims = serde_json::json!({ "sub": "user" });
+        let token = encode_with_signer(&Header::new(Algorithm::RS256), &claims, &signer).unwrap();
+        assert_eq!(signer.count.get(), 1);
+
+        // RSASSA-PKCS1-v1_5 is deterministic, so the output must be identical
+        // to signing with the key directly
+        let expected = encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            Key::Pkcs8(&private_key),
+        )
+        .unwrap();
+        assert_eq!(token, expected);
+
+        let (_, decoded) = decode::<serde_json::Value>(
+            &token,
+            &VerifyingKey::RsaDer(&public_key),
+            &[Algorithm::RS256],
+        )
+        .unwrap();
+        assert_eq!(decoded, claims);
+    }
+
+    #[test]
+    fn unsupported_algorithm() {
+        let private_key = test_key();
+
+        for alg in [Algorithm::HS256, Algorithm::ES384] {
+            assert!(matches!(
+                encode(&Header::new(alg), &serde_json::json!({}), Key::Pkcs8(&private_key)),
+                Err(Error::UnsupportedAlgorithm(a)) if a == alg
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let private_key = test_key();
+        let public_key = public_key(&private_key);
+
+        let token = encode(
+            &Header::new(Algorithm::RS256),
+            &serde_json::json!({ "sub": "user" }),
+            Key::Pkcs8(&private_key),
+        )
+        .unwrap();
+
+        let mut segments: Vec<_> = token.split('.').map(String::from).collect();
+        segments[1] = to_jwt_part(&serde_json::json!({ "sub": "admin" })).unwrap();
+        let tampered = segments.join(".");
+
+        assert!(matches!(
+            decode::<serde_json::Value>(
+                &tampered,
+                &VerifyingKey::RsaDer(&public_key),
+                &[Algorithm::RS256],
+            ),
+            Err(Error::SignatureInvalid)
+        ));
+
+        assert!(matches!(
+            decode::<serde_json::Value>(
+                "not.a-jwt",
+                &VerifyingKey::RsaDer(&public_key),
+                &[Algorithm::RS256],
+            ),
+            Err(Error::InvalidTokenFormat)
+        ));
+    }
+
+    #[test]
+    fn jwk_set() {
+        let body = r#"{"keys":[{"kty":"RSA","kid":"key-1","alg":"RS256","n":"AQAB","e":"AQAB"},{"kty":"RSA","kid":"key-2","n":"AQAB","e":"AQAB"}]}"#;
+
+        let url = "https://www.googleapis.com/oauth2/v3/certs";
+        let request = JwkSet::fetch_request(url).unwrap();
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(request.uri(), url);
+
+        let response = http::Response::builder()
+            .header(
+                http::header::CACHE_CONTROL,
+                "public, max-age=23225, must-revalidate, no-transform",
+            )
+            .header(http::header::AGE, "25")
+            .body(body)
+            .unwrap();
+        let key_set = JwkSet::parse_response(response).unwrap();
+
+        assert_eq!(key_set.max_age, Some(std::time::Duration::from_secs(23200)));
+        assert_eq!(
+            key_set.key_by_id("key-1").unwrap().alg.as_deref(),
+            Some("RS256")
+        );
+        assert!(key_set.key_by_id("key-2").unwrap().alg.is_none());
+        assert!(key_set.key_by_id("key-3").is_none());
+
+        let key_set = JwkSet::parse_response(http::Response::new(body)).unwrap();
+        assert!(key_set.max_age.is_none());
+    }
+
+    #[test]
+    fn key_for_header() {
+        let body = r#"{"keys":[
+            {"kty":"RSA","kid":"key-1","alg":"RS256","use":"sig","n":"AQAB","e":"AQAB"},
+            {"kty":"RSA","kid":"key-2","n":"AQAB","e":"AQAB"},
+            {"kty":"RSA","kid":"key-3","use":"enc","n":"AQAB","e":"AQAB"}
+        ]}"#;
+        let key_set = JwkSet::parse_response(http::Response::new(body)).unwrap();
+
+        let header = |alg, kid: Option<&str>| {
+            let mut header = Header::new(alg);
+            header.kid = kid.map(String::from);
+            header
+        };
+
+        let key = key_set
+            .key_for(&header(Algorithm::RS256, Some("key-1")))
+            .unwrap();
+        assert_eq!(key.kid.as_deref(), Some("key-1"));
+        let key = key_set
+            .key_for(&header(Algorithm::PS256, Some("key-2")))
+            .unwrap();
+        assert_eq!(key.kid.as_deref(), Some("key-2"));
+
+        assert!(matches!(
+            key_set.key_for(&header(Algorithm::RS256, Some("key-4"))),
+            Err(Error::UnknownKeyId(Some(kid))) if kid == "key-4"
+        ));
+        assert!(matches!(
+            key_set.key_for(&header(Algorithm::RS256, None)),
+            Err(Error::UnknownKeyId(None))
+        ));
+        assert!(matches!(
+            key_set.key_for(&header(Algorithm::RS384, Some("key-1"))),
+            Err(Error::IncompatibleKey(kid)) if kid == "key-1"
+        ));
+        assert!(matches!(
+            key_set.key_for(&header(Algorithm::RS256, Some("key-3"))),
+            Err(Error::IncompatibleKey(kid)) if kid == "key-3"
+        ));
+    }
+
+    #[test]
+    fn append_jwt_parts() {
+        let claims: serde_json::Map<String, serde_json::Value> = (0..1000)
+            .map(|i| (format!("claim-{}", i), "x".repeat(i % 7).into()))
+            .collect();
+
+        for claims in [
+            serde_json::json!({}),
+            serde_json::json!({ "a": 1 }),
+            serde_json::json!({ "ab": 1 }),
+            serde_json::Value::Object(claims),
+        ] {
+            let expected = data_encoding::BASE64URL_NOPAD
+                .encode(serde_json::to_string(&claims).unwrap().as_bytes());
+            assert_eq!(to_jwt_part(&claims).unwrap(), expected);
+
+            let mut output = "prefix.".to_owned();
+            append_jwt_part(&claims, &mut output).unwrap();
+            assert_eq!(output, format!("prefix.{}", expected));
+        }
+    }
 }
@@ -1,4 +1,6 @@
 #![doc = include_str!("../README.md")]
+// Newer toolchains have renamed or removed some of the standard lints below
+#![allow(renamed_and_removed_lints)]
 // BEGIN - Embark standard lints v6 for Rust 1.55+
 // do not change or add/remove here, but one can add exceptions after this section
 // for more info see: <https://github.com/EmbarkStudios/rust-ecosystem/issues/59>
@@ -79,14 +81,24 @@
 // END - Embark standard lints v6 for Rust 1.55+
 // crate-specific exceptions:
 
+#[cfg(feature = "async-provider")]
+pub mod async_provider;
 #[cfg(feature = "gcp")]
 pub mod gcp;
 #[cfg(feature = "jwt")]
-mod jwt;
+pub mod jwt;
+#[cfg(feature = "oauth2")]
+pub mod oauth2;
+#[cfg(feature = "reqwest-client")]
+pub mod reqwest_client;
 
 mod error;
 mod id_token;
 mod token;
 pub mod token_cache;
 
-pub use crate::{error::Error, id_token::IdToken, token::Token};
+pub use crate::{
+    error::Error,
+    id_token::{drive_id_token, ComputeEngineClaims, IdToken, IdTokenClaims},
+    token::{drive_token, Token},
+};
@@ -0,0 +1,220 @@
+//! Provides generic [OAuth 2.0](https://tools.ietf.org/html/rfc6749) flows
+//! that work with any compliant authorization server, not just Google's
+
+use crate::error::{self, Error};
+use std::time::{Duration, SystemTime};
+
+pub mod client_credentials;
+pub mod device;
+pub mod pkce;
+pub mod refresh_token;
+pub mod revoke;
+
+pub use crate::token::{drive_token, Token, TokenOrRequest, TokenProvider};
+pub use {
+    client_credentials::{ClientCredentialsInfo, ClientCredentialsProvider},
+    device::{DeviceAuthorization, DeviceFlowProvider, PollResult},
+    refresh_token::{RefreshTokenInfo, RefreshTokenProvider},
+    revoke::{parse_revoke_response, revoke_request},
+};
+
+/// The [successful response](https://tools.ietf.org/html/rfc6749#section-5.1)
+/// of a token endpoint, which Google's token endpoints and the metadata
+/// server return as well
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct TokenResponse {
+    /// The actual token
+    access_token: String,
+    /// The token type, the spec requires it, but some servers omit it, in
+    /// which case we assume Bearer
+    #[serde(default = "default_token_type")]
+    token_type: String,
+    /// The time until the token expires, optional per the spec
+    expires_in: Option<i64>,
+    /// A refresh token, only issued by some grants
+    refresh_token: Option<String>,
+    /// The space separated scopes that were granted, if they differ from the
+    /// requested scopes
+    scope: Option<String>,
+}
+
+fn default_token_type() -> String {
+    "Bearer".to_owned()
+}
+
+impl TokenResponse {
+    /// Converts the response into a token. Servers that aren't Google's
+    /// occasionally return nonsensical lifetimes, so an `expires_in` of zero
+    /// or less results in a token that is already expired, ie. it isn't
+    /// cached and a new one is requested the next time, while an `expires_in`
+    /// longer than `max_lifetime` is clamped to it.
+    pub(crate) fn into_token(self, max_lifetime: Option<Duration>) -> Result<Token, Error> {
+        let (expires_in, expires_in_timestamp) = match self.expires_in {
+            Some(expires_in) if expires_in <= 0 => (Some(expires_in), Some(SystemTime::now())),
+            Some(expires_in) => {
+                let lifetime = Duration::from_secs(expires_in.unsigned_abs());
+                let lifetime = max_lifetime.map_or(lifetime, |max| lifetime.min(max));
+                let timestamp = SystemTime::now()
+                    .checked_add(lifetime)
+                    .ok_or(Error::InvalidExpiresIn(expires_in))?;
+
+                (Some(lifetime.as_secs() as i64), Some(timestamp))
+            }
+            None => (None, None),
+        };
+
+        Ok(Token {
+            access_token: self.access_token,
+            token_type: self.token_type,
+            refresh_token: self.refresh_token.unwrap_or_default(),
+            expires_in,
+            expires_in_timestamp,
+            granted_scopes: self
+                .scope
+                .map(|scope| scope.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Creates a POST request with a form encoded body, which is how parameters
+/// are sent to token endpoints
+pub(crate) fn form_request(url: &str, body: String) -> Result<http::Request<Vec<u8>>, Error> {
+    let body = Vec::from(body);
+
+    let request = crate::token::request_builder()
+        .method("POST")
+        .uri(url)
+        .header(
+            http::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(body)?;
+
+    Ok(request)
+}
+
+/// Parses the response of a token endpoint. Unsuccessful responses are
+/// parsed as an [error response](https://tools.ietf.org/html/rfc6749#section-5.2)
+/// if possible.
+///
+/// Note that tokens without an `expires_in` are never cached, as there is no
+/// way to tell when they need to be refreshed. The lifetime of the token is
+/// limited to `max_lifetime`, if specified.
+pub(crate) fn parse_token_response<S>(
+    response: http::Response<S>,
+    max_lifetime: Option<Duration>,
+) -> Result<Token, Error>
+where
+    S: AsRef<[u8]>,
+{
+    let (parts, body) = response.into_parts();
+
+    if !parts.status.is_success() {
+        return Err(error::from_response(&parts, body.as_ref()));
+    }
+
+    let token_res: TokenResponse = serde_json::from_slice(body.as_ref())?;
+    token_res.into_token(max_lifetime)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_response() {
+        let token = parse_token_response(http::Response::new(
+            r#"{"access_token":"access-token","token_type":"bearer","refresh_token":"refresh-token","scope":"read write"}"#,
+        ), None)
+        .unwrap();
+
+        assert_eq!(token.access_token, "access-token");
+        assert_eq!(token.token_type, "bearer");
+        assert_eq!(token.refresh_token, "refresh-token");
+        assert_eq!(token.granted_scopes(), ["read", "write"]);
+        assert!(token.expiry_date().is_none());
+    }
+
+    #[test]
+    fn error_response() {
+        let response = http::Response::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header("x-request-id", "request-id")
+            .body(r#"{"error":"invalid_client","error_description":"Unknown client"}"#)
+            .unwrap();
+
+        match parse_token_response(response, None) {
+            Err(Error::Auth(err)) => {
+                assert_eq!(err.error.as_deref(), Some("invalid_client"));
+                assert_eq!(err.error_description.as_deref(), Some("Unknown client"));
+                assert_eq!(err.correlation_id.as_deref(), Some("request-id"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let response = http::Response::builder()
+            .status(http::StatusCode::BAD_GATEWAY)
+            .body("<html>bad gateway</html>")
+            .unwrap();
+
+        assert!(matches!(
+            parse_token_response(response, None),
+            Err(Error::HttpStatus { status, .. }) if status == http::StatusCode::BAD_GATEWAY
+        ));
+
+        // Only JSON responses are parsed as error responses
+        let response = http::Response::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .header(http::header::CONTENT_TYPE, "text/plain")
+            .body(r#"{"error":"invalid_client"}"#)
+            .unwrap();
+
+        assert!(matches!(
+            parse_token_response(response, None),
+            Err(Error::HttpStatus { status, .. }) if status == http::StatusCode::BAD_REQUEST
+        ));
+    }
+
+    #[test]
+    fn token_lifetime() {
+        use crate::token_cache::CacheableToken;
+
+        let parse = |expires_in: i64, max_lifetime: Option<Duration>| {
+            parse_token_response(
+                http::Response::new(format!(
+                    r#"{{"access_token":"access-token","expires_in":{}}}"#,
+                    expires_in
+                )),
+                max_lifetime,
+            )
+        };
+        let max_lifetime = Some(Duration::from_secs(3600));
+
+        // Zero and negative lifetimes mean the token needs to be refreshed
+        // immediately rather than being an error
+        for expires_in in [0, -1] {
+            let token = parse(expires_in, max_lifetime).unwrap();
+            assert_eq!(token.expires_in, Some(expires_in));
+            assert!(token.has_expired());
+        }
+
+        let token = parse(3599, max_lifetime).unwrap();
+        assert_eq!(token.expires_in, Some(3599));
+        assert_eq!(token.token_type, "Bearer");
+
+        let token = parse(100 * 365 * 24 * 60 * 60, max_lifetime).unwrap();
+        assert_eq!(token.expires_in, Some(3600));
+        assert!(token.time_until_expiry().unwrap() <= Duration::from_secs(3600));
+
+        // Without a maximum, an overflowing lifetime is still an error
+        let token = parse(i64::MAX, max_lifetime).unwrap();
+        assert_eq!(token.expires_in, Some(3600));
+        assert!(matches!(
+            parse(i64::MAX, None),
+            Err(Error::InvalidExpiresIn(i64::MAX))
+        ));
+    }
+}
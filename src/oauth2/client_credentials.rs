@@ -0,0 +1,242 @@
+use crate::{
+    error::{self, Error},
+    token::{RequestReason, Token, TokenOrRequest, TokenProvider},
+    token_cache::{hash_scopes, CachedTokenProvider},
+};
+use std::time::Duration;
+
+/// Provides tokens using the
+/// [client credentials grant](https://tools.ietf.org/html/rfc6749#section-4.4)
+/// of any `OAuth2` token endpoint. Caches tokens internally.
+pub type ClientCredentialsProvider = CachedTokenProvider<ClientCredentialsProviderInner>;
+impl ClientCredentialsProvider {
+    pub fn new(info: ClientCredentialsInfo) -> Self {
+        CachedTokenProvider::wrap(ClientCredentialsProviderInner::new(info))
+    }
+
+    /// See [`ClientCredentialsProviderInner::with_max_token_lifetime`]
+    pub fn with_max_token_lifetime(mut self, max_token_lifetime: Duration) -> Self {
+        self.inner_mut().max_token_lifetime = Some(max_token_lifetime);
+        self
+    }
+}
+
+/// The information needed to authenticate a client with the client
+/// credentials grant
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ClientCredentialsInfo {
+    /// The URL of the token endpoint
+    pub token_url: String,
+    /// The `OAuth2` `client_id`
+    pub client_id: String,
+    /// The `OAuth2` `client_secret`
+    pub client_secret: String,
+}
+
+/// A token provider for the
+/// [client credentials grant](https://tools.ietf.org/html/rfc6749#section-4.4).
+/// Should not be used directly as it is not cached. Use `ClientCredentialsProvider` instead.
+#[derive(Clone)]
+pub struct ClientCredentialsProviderInner {
+    info: ClientCredentialsInfo,
+    max_token_lifetime: Option<Duration>,
+}
+
+impl std::fmt::Debug for ClientCredentialsProviderInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCredentialsProviderInner")
+            .field("token_url", &self.info.token_url)
+            .field("client_id", &self.info.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientCredentialsProviderInner {
+    pub fn new(info: ClientCredentialsInfo) -> Self {
+        Self {
+            info,
+            max_token_lifetime: None,
+        }
+    }
+
+    /// Limits the lifetime of the returned tokens, regardless of the
+    /// `expires_in` returned by the server, eg. to defend against servers
+    /// that return lifetimes of many years. Unlimited by default.
+    ///
+    /// Note that a token with an `expires_in` of zero or less is always
+    /// treated as expired, so that a new one is requested the next time.
+    pub fn with_max_token_lifetime(mut self, max_token_lifetime: Duration) -> Self {
+        self.max_token_lifetime = Some(max_token_lifetime);
+        self
+    }
+
+    /// The maximum lifetime of the returned tokens, if limited
+    pub fn max_token_lifetime(&self) -> Option<Duration> {
+        self.max_token_lifetime
+    }
+
+    /// The information the provider authenticates with
+    pub fn info(&self) -> &ClientCredentialsInfo {
+        &self.info
+    }
+}
+
+impl TokenProvider for ClientCredentialsProviderInner {
+    /// Creates a request for a token with the specified scopes. The client
+    /// credentials are sent in the request body, which all servers are
+    /// required to support. If no scopes are specified, the server uses
+    /// its default scopes for the client.
+    fn get_token_with_subject<'a, S, I, T>(
+        &self,
+        subject: Option<T>,
+        scopes: I,
+    ) -> Result<TokenOrRequest, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        T: Into<String>,
+    {
+        let scope_hash = hash_scopes(&scopes);
+
+        if subject.is_some() {
+            return Err(Error::Auth(error::AuthError::unsupported(
+                "Client credentials do not support jwt subjects",
+            )));
+        }
+
+        let scopes = scopes
+            .into_iter()
+            .map(|s| s.as_ref())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut body = url::form_urlencoded::Serializer::new(String::new());
+        body.append_pair("grant_type", "client_credentials")
+            .append_pair("client_id", &self.info.client_id)
+            .append_pair("client_secret", &self.info.client_secret);
+
+        if !scopes.is_empty() {
+            body.append_pair("scope", &scopes);
+        }
+
+        let request = super::form_request(&self.info.token_url, body.finish())?;
+
+        Ok(TokenOrRequest::Request {
+            request,
+            reason: RequestReason::ParametersChanged,
+            scope_hash,
+        })
+    }
+
+    fn parse_token_response<S>(
+        &self,
+        _hash: u64,
+        response: http::Response<S>,
+    ) -> Result<Token, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        super::parse_token_response(response, self.max_token_lifetime)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn provider() -> ClientCredentialsProvider {
+        ClientCredentialsProvider::new(ClientCredentialsInfo {
+            token_url: "https://auth.example.com/oauth2/token".into(),
+            client_id: "client".into(),
+            client_secret: "TOP SECRET".into(),
+        })
+    }
+
+    #[test]
+    fn client_credentials() {
+        let provider = provider();
+
+        let request = match provider.get_token(&["read", "write"]).unwrap() {
+            TokenOrRequest::Request { request, .. } => request,
+            _ => panic!("should have gotten a request"),
+        };
+
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(request.uri(), "https://auth.example.com/oauth2/token");
+        assert_eq!(
+            request.headers()[http::header::CONTENT_TYPE],
+            "application/x-www-form-urlencoded"
+        );
+        assert_eq!(
+            std::str::from_utf8(request.body()).unwrap(),
+            "grant_type=client_credentials&client_id=client&client_secret=TOP+SECRET&scope=read+write"
+        );
+
+        let (hash, request) = match provider.get_token(&[] as &[&str]).unwrap() {
+            TokenOrRequest::Request {
+                request,
+                scope_hash,
+                ..
+            } => (scope_hash, request),
+            _ => panic!("should have gotten a request"),
+        };
+
+        assert_eq!(
+            std::str::from_utf8(request.body()).unwrap(),
+            "grant_type=client_credentials&client_id=client&client_secret=TOP+SECRET"
+        );
+
+        let token = provider
+            .parse_token_response(
+                hash,
+                http::Response::new(
+                    r#"{"access_token":"access-token","token_type":"Bearer","expires_in":3600}"#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(token.access_token, "access-token");
+
+        match provider.get_token(&[] as &[&str]).unwrap() {
+            TokenOrRequest::Token(cached) => assert_eq!(cached, token),
+            _ => panic!("should have gotten the cached token"),
+        }
+    }
+
+    #[test]
+    fn max_token_lifetime() {
+        let provider = provider().with_max_token_lifetime(Duration::from_secs(3600));
+        assert_eq!(
+            provider.inner().max_token_lifetime(),
+            Some(Duration::from_secs(3600))
+        );
+
+        let parse = |expires_in: i64| {
+            let hash = match provider.get_token(&["read"]).unwrap() {
+                TokenOrRequest::Request { scope_hash, .. } => scope_hash,
+                _ => panic!("should have gotten a request"),
+            };
+            provider
+                .parse_token_response(
+                    hash,
+                    http::Response::new(format!(
+                        r#"{{"access_token":"access-token","expires_in":{}}}"#,
+                        expires_in
+                    )),
+                )
+                .unwrap()
+        };
+
+        // Tokens that need to be refreshed immediately aren't returned from
+        // the cache
+        for expires_in in [0, -3600] {
+            assert_eq!(parse(expires_in).expires_in, Some(expires_in));
+        }
+
+        let token = parse(i64::MAX);
+        assert_eq!(token.expires_in, Some(3600));
+        assert!(matches!(
+            provider.get_token(&["read"]).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+    }
+}
@@ -0,0 +1,261 @@
+//! The [device authorization grant](https://tools.ietf.org/html/rfc8628),
+//! which lets users login on devices without a browser, or with limited
+//! input, by completing the login on another device.
+//!
+//! 1. Send the request from [`begin`](DeviceFlowProvider::begin) and
+//!    [parse](DeviceFlowProvider::parse_device_authorization) the response
+//! 1. Show the [`user_code`](DeviceAuthorization::user_code) and
+//!    [`verification_uri`](DeviceAuthorization::verification_uri) to the user
+//! 1. Every [`interval`](DeviceAuthorization::interval) seconds, send the
+//!    request from [`poll`](DeviceFlowProvider::poll) and
+//!    [parse](DeviceFlowProvider::parse_poll_response) the response, until
+//!    it is a token or an error
+
+use crate::{
+    error::{self, Error},
+    token::Token,
+};
+
+/// The number of seconds to wait between polls if the server doesn't specify
+/// an interval
+const DEFAULT_INTERVAL: u64 = 5;
+/// The number of seconds the polling interval increases each time the server
+/// asks to slow down
+pub const SLOW_DOWN_INCREMENT: u64 = 5;
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+/// The [response](https://tools.ietf.org/html/rfc8628#section-3.2) to a
+/// device authorization request
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct DeviceAuthorization {
+    /// The code used to poll for the token, this should not be shown to the
+    /// user
+    pub device_code: String,
+    /// The code the user enters at the verification URI
+    pub user_code: String,
+    /// The URI the user visits to login and enter the user code. Google calls
+    /// this `verification_url`
+    #[serde(alias = "verification_url")]
+    pub verification_uri: String,
+    /// A verification URI that includes the user code, so the user doesn't
+    /// need to enter it, eg. to show as a QR code
+    pub verification_uri_complete: Option<String>,
+    /// The number of seconds until the device and user codes expire
+    pub expires_in: u64,
+    /// The minimum number of seconds to wait between polls
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+/// The result of a poll for the token
+#[derive(Debug)]
+pub enum PollResult {
+    /// The user has logged in and approved the request
+    Token(Token),
+    /// The user hasn't completed the login yet, poll again after the interval
+    Pending,
+    /// The client is polling too often, poll again after increasing the
+    /// interval by [`SLOW_DOWN_INCREMENT`] seconds
+    SlowDown,
+}
+
+/// Builds and parses the requests for the
+/// [device authorization grant](https://tools.ietf.org/html/rfc8628)
+#[derive(Clone)]
+pub struct DeviceFlowProvider {
+    device_authorization_url: String,
+    token_url: String,
+    client_id: String,
+    client_secret: Option<String>,
+}
+
+impl std::fmt::Debug for DeviceFlowProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeviceFlowProvider")
+            .field("device_authorization_url", &self.device_authorization_url)
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeviceFlowProvider {
+    pub fn new(
+        device_authorization_url: impl Into<String>,
+        token_url: impl Into<String>,
+        client_id: impl Into<String>,
+    ) -> Self {
+        Self {
+            device_authorization_url: device_authorization_url.into(),
+            token_url: token_url.into(),
+            client_id: client_id.into(),
+            client_secret: None,
+        }
+    }
+
+    /// Sets the client secret, which some servers, eg. Google, require even
+    /// though device clients can't keep it secret
+    pub fn with_client_secret(mut self, client_secret: impl Into<String>) -> Self {
+        self.client_secret = Some(client_secret.into());
+        self
+    }
+
+    /// Appends the client credentials to a request body
+    fn append_client(&self, body: &mut url::form_urlencoded::Serializer<'_, String>) {
+        body.append_pair("client_id", &self.client_id);
+
+        if let Some(client_secret) = &self.client_secret {
+            body.append_pair("client_secret", client_secret);
+        }
+    }
+
+    /// Creates the request to the device authorization endpoint that starts
+    /// the flow for the specified scopes
+    pub fn begin<'a, S, I>(&self, scopes: I) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S>,
+    {
+        let scopes = scopes
+            .into_iter()
+            .map(|s| s.as_ref())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut body = url::form_urlencoded::Serializer::new(String::new());
+        self.append_client(&mut body);
+
+        if !scopes.is_empty() {
+            body.append_pair("scope", &scopes);
+        }
+
+        super::form_request(&self.device_authorization_url, body.finish())
+    }
+
+    /// Parses the response to the [`begin`](Self::begin) request
+    pub fn parse_device_authorization<S>(
+        &self,
+        response: http::Response<S>,
+    ) -> Result<DeviceAuthorization, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        let (parts, body) = response.into_parts();
+
+        if !parts.status.is_success() {
+            return Err(error::from_response(&parts, body.as_ref()));
+        }
+
+        Ok(serde_json::from_slice(body.as_ref())?)
+    }
+
+    /// Creates the request to the token endpoint that polls for the token
+    pub fn poll(&self, device_code: &str) -> Result<http::Request<Vec<u8>>, Error> {
+        let mut body = url::form_urlencoded::Serializer::new(String::new());
+        body.append_pair("grant_type", "urn:ietf:params:oauth:grant-type:device_code")
+            .append_pair("device_code", device_code);
+        self.append_client(&mut body);
+
+        super::form_request(&self.token_url, body.finish())
+    }
+
+    /// Parses the response to a [`poll`](Self::poll) request. The
+    /// `authorization_pending` and `slow_down` errors mean that polling should
+    /// continue, and are returned as [`PollResult::Pending`] and
+    /// [`PollResult::SlowDown`] respectively, all other errors, eg. the user
+    /// denying the request or the device code expiring, are returned as
+    /// [`Error::Auth`].
+    pub fn parse_poll_response<S>(&self, response: http::Response<S>) -> Result<PollResult, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        match super::parse_token_response(response, None) {
+            Ok(token) => Ok(PollResult::Token(token)),
+            Err(Error::Auth(err)) if err.error.as_deref() == Some("authorization_pending") => {
+                Ok(PollResult::Pending)
+            }
+            Err(Error::Auth(err)) if err.error.as_deref() == Some("slow_down") => {
+                Ok(PollResult::SlowDown)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error(status: u16, error: &str) -> http::Response<String> {
+        http::Response::builder()
+            .status(status)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(format!(r#"{{"error":"{}"}}"#, error))
+            .unwrap()
+    }
+
+    #[test]
+    fn device_flow() {
+        let provider = DeviceFlowProvider::new(
+            "https://oauth2.googleapis.com/device/code",
+            "https://oauth2.googleapis.com/token",
+            "client",
+        )
+        .with_client_secret("secret");
+
+        let request = provider.begin(&["email", "profile"]).unwrap();
+        assert_eq!(request.uri(), "https://oauth2.googleapis.com/device/code");
+        assert_eq!(
+            std::str::from_utf8(request.body()).unwrap(),
+            "client_id=client&client_secret=secret&scope=email+profile"
+        );
+
+        // Google's response uses verification_url and doesn't include a
+        // complete uri
+        let auth = provider
+            .parse_device_authorization(http::Response::new(
+                r#"{"device_code":"DEVICE","user_code":"ABC-DEF","verification_url":"https://www.google.com/device","expires_in":1800}"#,
+            ))
+            .unwrap();
+        assert_eq!(auth.device_code, "DEVICE");
+        assert_eq!(auth.user_code, "ABC-DEF");
+        assert_eq!(auth.verification_uri, "https://www.google.com/device");
+        assert_eq!(auth.interval, DEFAULT_INTERVAL);
+
+        let request = provider.poll(&auth.device_code).unwrap();
+        assert_eq!(request.uri(), "https://oauth2.googleapis.com/token");
+        assert_eq!(
+            std::str::from_utf8(request.body()).unwrap(),
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code&device_code=DEVICE&client_id=client&client_secret=secret"
+        );
+
+        assert!(matches!(
+            provider.parse_poll_response(error(428, "authorization_pending")),
+            Ok(PollResult::Pending)
+        ));
+        assert!(matches!(
+            provider.parse_poll_response(error(400, "slow_down")),
+            Ok(PollResult::SlowDown)
+        ));
+        assert!(matches!(
+            provider.parse_poll_response(error(403, "access_denied")),
+            Err(Error::Auth(err)) if err.error.as_deref() == Some("access_denied")
+        ));
+
+        match provider
+            .parse_poll_response(http::Response::new(
+                r#"{"access_token":"access-token","expires_in":3599,"refresh_token":"REFRESH_TOKEN"}"#,
+            ))
+            .unwrap()
+        {
+            PollResult::Token(token) => {
+                assert_eq!(token.access_token, "access-token");
+                assert_eq!(token.refresh_token, "REFRESH_TOKEN");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Helpers for the [authorization code grant](https://tools.ietf.org/html/rfc6749#section-4.1)
+//! with [PKCE](https://tools.ietf.org/html/rfc7636), which is used for
+//! interactive logins where the user is redirected to the authorization
+//! server and back.
+//!
+//! 1. [Generate](CodeVerifier::generate) a [`CodeVerifier`], which needs to
+//!    be kept until the code is exchanged
+//! 1. Redirect the user to the [authorization URL](AuthCodeRequest::authorize_url)
+//! 1. Once the user is redirected back to the `redirect_uri`, check that the
+//!    `state` matches, and [exchange](AuthCodeRequest::exchange_request) the
+//!    `code` for a token
+//! 1. [Parse](AuthCodeRequest::parse_token_response) the response
+
+use crate::{error::Error, token::Token};
+
+/// The minimum length of a code verifier
+const MIN_VERIFIER_LEN: usize = 43;
+/// The maximum length of a code verifier
+const MAX_VERIFIER_LEN: usize = 128;
+
+/// A [code verifier](https://tools.ietf.org/html/rfc7636#section-4.1), the
+/// secret that proves the token request comes from the same client as the
+/// authorization request
+#[derive(Clone)]
+pub struct CodeVerifier(String);
+
+impl std::fmt::Debug for CodeVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CodeVerifier").finish_non_exhaustive()
+    }
+}
+
+impl CodeVerifier {
+    /// Generates a new code verifier from 32 random bytes, which results in a
+    /// verifier of 43 characters
+    pub fn generate() -> Result<Self, Error> {
+        use ring::rand::SecureRandom;
+
+        let mut bytes = [0u8; 32];
+        ring::rand::SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(Error::Random)?;
+
+        Ok(Self(data_encoding::BASE64URL_NOPAD.encode(&bytes)))
+    }
+
+    /// Creates a code verifier from a previously generated verifier, eg. one
+    /// that was stored in a session while the user was logging in. Returns
+    /// `None` if the verifier isn't 43 to 128 characters long or contains
+    /// characters that are not allowed.
+    pub fn new(verifier: String) -> Option<Self> {
+        let valid = (MIN_VERIFIER_LEN..=MAX_VERIFIER_LEN).contains(&verifier.len())
+            && verifier
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'));
+
+        valid.then_some(Self(verifier))
+    }
+
+    /// The verifier, which is sent when exchanging the code
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The `S256` code challenge for the verifier, which is sent in the
+    /// authorization request
+    pub fn code_challenge_s256(&self) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, self.0.as_bytes());
+        data_encoding::BASE64URL_NOPAD.encode(digest.as_ref())
+    }
+}
+
+/// Whether the application needs to access the user's resources when they
+/// are not present, sent as the `access_type` of the authorization request.
+///
+/// This is a Google extension, other authorization servers generally ignore it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    /// Only an access token is issued, this is Google's default
+    Online,
+    /// A refresh token is issued in addition to the access token, which is
+    /// needed to get new access tokens without the user being present
+    Offline,
+}
+
+impl AccessType {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Offline => "offline",
+        }
+    }
+}
+
+/// Builds the requests for the authorization code grant with PKCE
+///
+/// Google only issues a refresh token if the authorization URL requests
+/// [offline access](AccessType::Offline), and only the first time the user
+/// grants access, unless the user is [prompted](Self::with_prompt) for
+/// `consent` again. So to reliably get a refresh token from Google use
+///
+/// ```
+/// # use tame_oauth::oauth2::pkce::{AccessType, AuthCodeRequest};
+/// let request = AuthCodeRequest::new("client-id", "http://127.0.0.1:8080/callback")
+///     .with_access_type(AccessType::Offline)
+///     .with_prompt("consent");
+/// ```
+#[derive(Clone)]
+pub struct AuthCodeRequest {
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    access_type: Option<AccessType>,
+    prompt: Option<String>,
+    login_hint: Option<String>,
+    include_granted_scopes: Option<bool>,
+}
+
+impl std::fmt::Debug for AuthCodeRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthCodeRequest")
+            .field("client_id", &self.client_id)
+            .field("redirect_uri", &self.redirect_uri)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuthCodeRequest {
+    /// Creates a builder for a public client, ie. one that can't keep a
+    /// client secret, such as a CLI or a desktop application
+    pub fn new(client_id: impl Into<String>, redirect_uri: impl Into<String>) -> Self {
+        Self {
+            client_id: client_id.into(),
+            client_secret: None,
+            redirect_uri: redirect_uri.into(),
+            access_type: None,
+            prompt: None,
+            login_hint: None,
+            include_granted_scopes: None,
+        }
+    }
+
+    /// Sets the client secret for confidential clients, which is sent when
+    /// exchanging the code
+    pub fn with_client_secret(mut self, client_secret: impl Into<String>) -> Self {
+        self.client_secret = Some(client_secret.into());
+        self
+    }
+
+    /// Sets the `access_type` of the authorization request. Google requires
+    /// [`AccessType::Offline`] to issue a refresh token. Not sent by default.
+    pub fn with_access_type(mut self, access_type: AccessType) -> Self {
+        self.access_type = Some(access_type);
+        self
+    }
+
+    /// Sets the space delimited list of `prompt`s to present to the user,
+    /// eg. `consent` or `select_account`. Not sent by default, in which case
+    /// the user is only prompted the first time access is requested.
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = Some(prompt.into());
+        self
+    }
+
+    /// Sets the `login_hint`, usually the email address of the user, which
+    /// the authorization server can use to prefill the login form or select
+    /// the right account
+    pub fn with_login_hint(mut self, login_hint: impl Into<String>) -> Self {
+        self.login_hint = Some(login_hint.into());
+        self
+    }
+
+    /// Sets `include_granted_scopes`, which enables incremental
+    /// authorization, ie. the token also grants the scopes the user has
+    /// previously granted to the application. Not sent by default.
+    pub fn with_include_granted_scopes(mut self, include: bool) -> Self {
+        self.include_granted_scopes = Some(include);
+        self
+    }
+
+    /// Creates the URL of the authorization endpoint at `auth_url` that the
+    /// user is sent to in order to login and grant the `scopes`. The `state`
+    /// is passed back unchanged in the redirect, and should be a random value
+    /// that is checked to prevent cross-site request forgery.
+    pub fn authorize_url<'a, S, I>(
+        &self,
+        auth_url: &str,
+        scopes: I,
+        state: &str,
+        verifier: &CodeVerifier,
+    ) -> String
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S>,
+    {
+        let scopes = scopes
+            .into_iter()
+            .map(|s| s.as_ref())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        query
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("scope", &scopes)
+            .append_pair("state", state)
+            .append_pair("code_challenge", &verifier.code_challenge_s256())
+            .append_pair("code_challenge_method", "S256");
+
+        if let Some(access_type) = self.access_type {
+            query.append_pair("access_type", access_type.as_str());
+        }
+        if let Some(prompt) = &self.prompt {
+            query.append_pair("prompt", prompt);
+        }
+        if let Some(login_hint) = &self.login_hint {
+            query.append_pair("login_hint", login_hint);
+        }
+        if let Some(include) = self.include_granted_scopes {
+            query.append_pair(
+                "include_granted_scopes",
+                if include { "true" } else { "false" },
+            );
+        }
+
+        let query = query.finish();
+
+        let separator = if auth_url.contains('?') { '&' } else { '?' };
+        format!("{}{}{}", auth_url, separator, query)
+    }
+
+    /// Creates the request to the token endpoint at `token_url` that
+    /// exchanges the `code` the user was redirected back with for a token
+    pub fn exchange_request(
+        &self,
+        token_url: &str,
+        code: &str,
+        verifier: &CodeVerifier,
+    ) -> Result<http::Request<Vec<u8>>, Error> {
+        let mut body = url::form_urlencoded::Serializer::new(String::new());
+        body.append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("client_id", &self.client_id)
+            .append_pair("code_verifier", verifier.as_str());
+
+        if let Some(client_secret) = &self.client_secret {
+            body.append_pair("client_secret", client_secret);
+        }
+
+        super::form_request(token_url, body.finish())
+    }
+
+    /// Parses the response to the [exchange request](Self::exchange_request).
+    /// The token includes a refresh token if the server issued one, which can
+    /// be used with a [`RefreshTokenProvider`](super::RefreshTokenProvider).
+    pub fn parse_token_response<S>(&self, response: http::Response<S>) -> Result<Token, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        super::parse_token_response(response, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn code_verifier() {
+        let verifier = CodeVerifier::generate().unwrap();
+        assert_eq!(verifier.as_str().len(), 43);
+        assert!(CodeVerifier::new(verifier.as_str().to_owned()).is_some());
+        assert_ne!(
+            verifier.as_str(),
+            CodeVerifier::generate().unwrap().as_str()
+        );
+
+        assert!(CodeVerifier::new("too-short".to_owned()).is_none());
+        assert!(CodeVerifier::new("a".repeat(129)).is_none());
+        assert!(CodeVerifier::new(format!("{}+", "a".repeat(43))).is_none());
+
+        // https://tools.ietf.org/html/rfc7636#appendix-B
+        let verifier =
+            CodeVerifier::new("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_owned()).unwrap();
+        assert_eq!(
+            verifier.code_challenge_s256(),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn auth_code() {
+        let verifier =
+            CodeVerifier::new("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_owned()).unwrap();
+        let builder = AuthCodeRequest::new("client", "http://127.0.0.1:8080/callback");
+
+        assert_eq!(
+            builder.authorize_url(
+                "https://idp.example.com/authorize",
+                &["openid", "email"],
+                "state",
+                &verifier
+            ),
+            "https://idp.example.com/authorize?response_type=code&client_id=client&redirect_uri=http%3A%2F%2F127.0.0.1%3A8080%2Fcallback&scope=openid+email&state=state&code_challenge=E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM&code_challenge_method=S256"
+        );
+
+        let request = builder
+            .with_client_secret("secret")
+            .exchange_request("https://idp.example.com/token", "CODE", &verifier)
+            .unwrap();
+
+        assert_eq!(request.uri(), "https://idp.example.com/token");
+        assert_eq!(
+            std::str::from_utf8(request.body()).unwrap(),
+            "grant_type=authorization_code&code=CODE&redirect_uri=http%3A%2F%2F127.0.0.1%3A8080%2Fcallback&client_id=client&code_verifier=dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk&client_secret=secret"
+        );
+    }
+
+    #[test]
+    fn authorize_url_parameters() {
+        let verifier =
+            CodeVerifier::new("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_owned()).unwrap();
+        let builder = AuthCodeRequest::new("client", "http://127.0.0.1:8080/callback")
+            .with_access_type(AccessType::Offline)
+            .with_prompt("consent select_account")
+            .with_login_hint("user@example.com")
+            .with_include_granted_scopes(true);
+
+        let url = builder.authorize_url(
+            "https://accounts.google.com/o/oauth2/v2/auth?hd=example.com",
+            &["openid"],
+            "state",
+            &verifier,
+        );
+
+        assert!(url.starts_with(
+            "https://accounts.google.com/o/oauth2/v2/auth?hd=example.com&response_type=code&"
+        ));
+        assert!(url.ends_with("&code_challenge_method=S256&access_type=offline&prompt=consent+select_account&login_hint=user%40example.com&include_granted_scopes=true"));
+    }
+}
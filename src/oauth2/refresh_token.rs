@@ -0,0 +1,182 @@
+use crate::{
+    error::{self, Error},
+    token::{RequestReason, Token, TokenOrRequest, TokenProvider},
+    token_cache::{hash_scopes, CachedTokenProvider},
+};
+use std::time::Duration;
+
+/// Provides tokens using the
+/// [refresh token grant](https://tools.ietf.org/html/rfc6749#section-6)
+/// of any `OAuth2` token endpoint. Caches tokens internally.
+pub type RefreshTokenProvider = CachedTokenProvider<RefreshTokenProviderInner>;
+impl RefreshTokenProvider {
+    pub fn new(info: RefreshTokenInfo) -> Self {
+        CachedTokenProvider::wrap(RefreshTokenProviderInner::new(info))
+    }
+
+    /// See [`RefreshTokenProviderInner::with_max_token_lifetime`]
+    pub fn with_max_token_lifetime(mut self, max_token_lifetime: Duration) -> Self {
+        self.inner_mut().max_token_lifetime = Some(max_token_lifetime);
+        self
+    }
+}
+
+/// The information needed to exchange a refresh token for access tokens
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct RefreshTokenInfo {
+    /// The URL of the token endpoint
+    pub token_url: String,
+    /// The `OAuth2` `client_id`
+    pub client_id: String,
+    /// The `OAuth2` `client_secret`
+    pub client_secret: String,
+    /// The `OAuth2` `refresh_token`
+    pub refresh_token: String,
+}
+
+/// A token provider for the
+/// [refresh token grant](https://tools.ietf.org/html/rfc6749#section-6).
+/// Should not be used directly as it is not cached. Use `RefreshTokenProvider` instead.
+///
+/// Some servers issue a new refresh token along with each access token, it is
+/// available as [`Token::refresh_token`], but the provider keeps using the
+/// refresh token it was created with.
+#[derive(Clone)]
+pub struct RefreshTokenProviderInner {
+    info: RefreshTokenInfo,
+    max_token_lifetime: Option<Duration>,
+}
+
+impl std::fmt::Debug for RefreshTokenProviderInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RefreshTokenProviderInner")
+            .field("token_url", &self.info.token_url)
+            .field("client_id", &self.info.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RefreshTokenProviderInner {
+    pub fn new(info: RefreshTokenInfo) -> Self {
+        Self {
+            info,
+            max_token_lifetime: None,
+        }
+    }
+
+    /// Limits the lifetime of the returned tokens, regardless of the
+    /// `expires_in` returned by the server, eg. to defend against servers
+    /// that return lifetimes of many years. Unlimited by default.
+    ///
+    /// Note that a token with an `expires_in` of zero or less is always
+    /// treated as expired, so that a new one is requested the next time.
+    pub fn with_max_token_lifetime(mut self, max_token_lifetime: Duration) -> Self {
+        self.max_token_lifetime = Some(max_token_lifetime);
+        self
+    }
+
+    /// The maximum lifetime of the returned tokens, if limited
+    pub fn max_token_lifetime(&self) -> Option<Duration> {
+        self.max_token_lifetime
+    }
+
+    /// The information the provider authenticates with
+    pub fn info(&self) -> &RefreshTokenInfo {
+        &self.info
+    }
+
+    /// Creates the request that exchanges the refresh token for a new access
+    /// token
+    pub(crate) fn prepare_token_request(&self) -> Result<http::Request<Vec<u8>>, Error> {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_id", &self.info.client_id)
+            .append_pair("client_secret", &self.info.client_secret)
+            .append_pair("grant_type", "refresh_token")
+            .append_pair("refresh_token", &self.info.refresh_token)
+            .finish();
+
+        super::form_request(&self.info.token_url, body)
+    }
+}
+
+impl TokenProvider for RefreshTokenProviderInner {
+    fn get_token_with_subject<'a, S, I, T>(
+        &self,
+        subject: Option<T>,
+        // A refresh token only has the scopes that were granted when the user
+        // authorized, so whatever scopes you're asking for, better have been
+        // handled then
+        scopes: I,
+    ) -> Result<TokenOrRequest, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        T: Into<String>,
+    {
+        let scope_hash = hash_scopes(&scopes);
+
+        if subject.is_some() {
+            return Err(Error::Auth(error::AuthError::unsupported(
+                "Refresh tokens do not support jwt subjects",
+            )));
+        }
+
+        let request = self.prepare_token_request()?;
+
+        Ok(TokenOrRequest::Request {
+            request,
+            reason: RequestReason::ParametersChanged,
+            scope_hash,
+        })
+    }
+
+    fn parse_token_response<S>(
+        &self,
+        _hash: u64,
+        response: http::Response<S>,
+    ) -> Result<Token, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        super::parse_token_response(response, self.max_token_lifetime)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn refresh_token() {
+        let provider = RefreshTokenProviderInner::new(RefreshTokenInfo {
+            token_url: "https://idp.example.com/token".into(),
+            client_id: "client".into(),
+            client_secret: "secret".into(),
+            refresh_token: "REFRESH_TOKEN".into(),
+        });
+
+        let request = match provider.get_token(&["ignored"]).unwrap() {
+            TokenOrRequest::Request { request, .. } => request,
+            _ => panic!("should have gotten a request"),
+        };
+
+        assert_eq!(request.uri(), "https://idp.example.com/token");
+        assert_eq!(
+            std::str::from_utf8(request.body()).unwrap(),
+            "client_id=client&client_secret=secret&grant_type=refresh_token&refresh_token=REFRESH_TOKEN"
+        );
+
+        let token = provider
+            .parse_token_response(
+                0,
+                http::Response::new(
+                    r#"{"access_token":"access-token","expires_in":3599,"refresh_token":"NEW_REFRESH_TOKEN"}"#,
+                ),
+            )
+            .unwrap();
+
+        assert_eq!(token.access_token, "access-token");
+        assert_eq!(token.token_type, "Bearer");
+        assert_eq!(token.refresh_token, "NEW_REFRESH_TOKEN");
+    }
+}
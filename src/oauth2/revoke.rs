@@ -0,0 +1,62 @@
+//! [Token revocation](https://tools.ietf.org/html/rfc7009), eg. to revoke a
+//! refresh token when a user logs out
+
+use crate::error::{self, Error};
+
+/// Google's token revocation endpoint
+pub const GOOGLE_REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+/// Creates a request that revokes the access or refresh token at the
+/// revocation endpoint at `revocation_url`. Revoking a refresh token
+/// typically also revokes the access tokens issued with it.
+pub fn revoke_request(token: &str, revocation_url: &str) -> Result<http::Request<Vec<u8>>, Error> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("token", token)
+        .finish();
+
+    super::form_request(revocation_url, body)
+}
+
+/// Parses the response to a [`revoke_request`]. The server responds with
+/// success if the token was revoked, or was already invalid.
+pub fn parse_revoke_response<S>(response: http::Response<S>) -> Result<(), Error>
+where
+    S: AsRef<[u8]>,
+{
+    let (parts, body) = response.into_parts();
+
+    if !parts.status.is_success() {
+        return Err(error::from_response(&parts, body.as_ref()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn revoke() {
+        let request = revoke_request("REFRESH/TOKEN", GOOGLE_REVOKE_URL).unwrap();
+
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(request.uri(), GOOGLE_REVOKE_URL);
+        assert_eq!(
+            std::str::from_utf8(request.body()).unwrap(),
+            "token=REFRESH%2FTOKEN"
+        );
+
+        assert!(parse_revoke_response(http::Response::new("")).is_ok());
+
+        let response = http::Response::builder()
+            .status(http::StatusCode::BAD_REQUEST)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(r#"{"error":"unsupported_token_type"}"#)
+            .unwrap();
+        assert!(matches!(
+            parse_revoke_response(response),
+            Err(Error::Auth(err)) if err.error.as_deref() == Some("unsupported_token_type")
+        ));
+    }
+}
@@ -0,0 +1,184 @@
+//! Optional integration with the [reqwest](https://docs.rs/reqwest) async HTTP
+//! client, which sends the requests created by token providers and passes the
+//! responses back to them, so that you don't have to write that boilerplate
+//! yourself.
+//!
+//! This is purely a convenience, all token providers can be used with any
+//! HTTP client, as shown in the examples.
+
+use crate::{
+    async_provider::{AsyncIdTokenProvider, AsyncTokenProvider},
+    id_token::IdTokenProvider,
+    token::TokenProvider,
+    Error, IdToken, Token,
+};
+use std::convert::TryFrom;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+/// Sends a request created by a token provider, returning the response so that
+/// it can be passed back to the token provider
+pub async fn send(
+    client: &reqwest::Client,
+    request: http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let request = reqwest::Request::try_from(request).map_err(|e| Error::Transport(Box::new(e)))?;
+    let response = client
+        .execute(request)
+        .await
+        .map_err(|e| Error::Transport(Box::new(e)))?;
+
+    let builder = response_builder(response.status(), response.version(), response.headers());
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| Error::Transport(Box::new(e)))?;
+
+    Ok(builder.body(body.to_vec())?)
+}
+
+/// Starts an `http` response with the status, version and headers of a
+/// response from either the async or the blocking reqwest client
+fn response_builder(
+    status: http::StatusCode,
+    version: http::Version,
+    headers: &http::HeaderMap,
+) -> http::response::Builder {
+    let mut builder = http::Response::builder().status(status).version(version);
+
+    if let Some(builder_headers) = builder.headers_mut() {
+        builder_headers.extend(headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    builder
+}
+
+impl crate::async_provider::HttpClient for reqwest::Client {
+    type Body = Vec<u8>;
+
+    async fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Self::Body>, Error> {
+        send(self, request).await
+    }
+}
+
+/// Gets a token for the specified scopes from the provider, sending the
+/// request(s) needed to retrieve a new token if it doesn't have a valid
+/// cached token
+pub async fn execute<P: TokenProvider + Sync>(
+    provider: &P,
+    client: &reqwest::Client,
+    scopes: &[&str],
+) -> Result<Token, Error> {
+    provider.token(client, scopes).await
+}
+
+/// Gets an id token for the specified audience from the provider, sending the
+/// request(s) needed to retrieve a new token if it doesn't have a valid
+/// cached token
+pub async fn execute_id_token<P: IdTokenProvider + Sync>(
+    provider: &P,
+    client: &reqwest::Client,
+    audience: &str,
+) -> Result<IdToken, Error> {
+    provider.id_token(client, audience).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{token::TokenOrRequest, token_cache::CachedTokenProvider};
+    use std::io::{Read, Write};
+
+    /// Serves a single token response on a random local port
+    fn serve_token() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).unwrap();
+
+            let body = r#"{"access_token":"access-token","token_type":"Bearer","expires_in":3599}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        format!("http://{}/token", addr)
+    }
+
+    /// A token provider that requests tokens from a local URL
+    struct LocalProvider(String);
+
+    impl TokenProvider for LocalProvider {
+        fn get_token_with_subject<'a, S, I, T>(
+            &self,
+            _subject: Option<T>,
+            _scopes: I,
+        ) -> Result<TokenOrRequest, Error>
+        where
+            S: AsRef<str> + 'a,
+            I: IntoIterator<Item = &'a S> + Clone,
+            T: Into<String>,
+        {
+            Ok(TokenOrRequest::Request {
+                request: http::Request::post(&self.0).body(Vec::new())?,
+                reason: crate::token::RequestReason::ParametersChanged,
+                scope_hash: 0,
+            })
+        }
+
+        fn parse_token_response<S>(
+            &self,
+            _hash: u64,
+            response: http::Response<S>,
+        ) -> Result<Token, Error>
+        where
+            S: AsRef<[u8]>,
+        {
+            assert_eq!(response.headers()["content-type"], "application/json");
+
+            let token: serde_json::Value = serde_json::from_slice(response.body().as_ref())?;
+            Ok(Token::new(
+                token["access_token"].as_str().unwrap(),
+                token["token_type"].as_str().unwrap(),
+                std::time::Duration::from_secs(3599),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_request() {
+        let provider = CachedTokenProvider::wrap(LocalProvider(serve_token()));
+        let client = reqwest::Client::new();
+
+        let token = execute(&provider, &client, &["scope"]).await.unwrap();
+        assert_eq!(token.access_token, "access-token");
+
+        // The token is now cached, so no request is sent
+        let cached = execute(&provider, &client, &["scope"]).await.unwrap();
+        assert_eq!(cached, token);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn fetch_blocking() {
+        let provider = CachedTokenProvider::wrap(LocalProvider(serve_token()));
+        let client = reqwest::blocking::Client::new();
+
+        let token = blocking::fetch_token_blocking(&provider, &client, &["scope"]).unwrap();
+        assert_eq!(token.access_token, "access-token");
+
+        let cached = blocking::fetch_token_blocking(&provider, &client, &["scope"]).unwrap();
+        assert_eq!(cached, token);
+    }
+}
@@ -0,0 +1,49 @@
+//! Synchronous versions of the [`reqwest_client`](crate::reqwest_client)
+//! helpers, using [`reqwest::blocking::Client`], for programs that only need a
+//! token and don't want to run an async runtime to get it.
+
+use crate::{id_token::IdTokenProvider, token::TokenProvider, Error, IdToken, Token};
+use std::convert::TryFrom;
+
+/// Sends a request created by a token provider, returning the response so that
+/// it can be passed back to the token provider
+pub fn send(
+    client: &reqwest::blocking::Client,
+    request: http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let request =
+        reqwest::blocking::Request::try_from(request).map_err(|e| Error::Transport(Box::new(e)))?;
+    let response = client
+        .execute(request)
+        .map_err(|e| Error::Transport(Box::new(e)))?;
+
+    let builder =
+        super::response_builder(response.status(), response.version(), response.headers());
+    let body = response
+        .bytes()
+        .map_err(|e| Error::Transport(Box::new(e)))?;
+
+    Ok(builder.body(body.to_vec())?)
+}
+
+/// Gets a token for the specified scopes from the provider, sending the
+/// request(s) needed to retrieve a new token if it doesn't have a valid
+/// cached token
+pub fn fetch_token_blocking<P: TokenProvider>(
+    provider: &P,
+    client: &reqwest::blocking::Client,
+    scopes: &[&str],
+) -> Result<Token, Error> {
+    crate::drive_token(provider, scopes, |request| send(client, request))
+}
+
+/// Gets an id token for the specified audience from the provider, sending the
+/// request(s) needed to retrieve a new token if it doesn't have a valid
+/// cached token
+pub fn fetch_id_token_blocking<P: IdTokenProvider>(
+    provider: &P,
+    client: &reqwest::blocking::Client,
+    audience: &str,
+) -> Result<IdToken, Error> {
+    crate::drive_id_token(provider, audience, |request| send(client, request))
+}
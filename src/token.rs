@@ -1,55 +1,211 @@
-use crate::{error::Error, token_cache::CacheableToken};
-use std::time::SystemTime;
+use crate::{
+    error::{self, Error},
+    id_token::AccessTokenResponse,
+    token_cache::CacheableToken,
+};
+use std::{
+    convert::TryFrom,
+    time::{Duration, SystemTime},
+};
 
 /// Represents a access token as returned by `OAuth2` servers.
 ///
 /// * It is produced by all authentication flows.
 /// * It authenticates certain operations, and must be refreshed once it has
-///  reached its expiry date.
+///   reached its expiry date.
 ///
 /// The type is tuned to be suitable for direct de-serialization from server
 /// replies, as well as for serialization for later reuse. This is the reason
 /// for the two fields dealing with expiry - once in relative in and once in
 /// absolute terms.
-#[derive(Clone, PartialEq, Eq, Debug, serde::Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Token {
     /// used when authenticating calls to oauth2 enabled services.
     pub access_token: String,
-    /// used to refresh an expired access_token.
+    /// used to refresh an expired `access_token`.
     pub refresh_token: String,
     /// The token type as string - usually 'Bearer'.
     pub token_type: String,
-    /// access_token will expire after this amount of time.
-    /// Prefer using expiry_date()
+    /// `access_token` will expire after this amount of time.
+    /// Prefer using `expiry_date()`
     pub expires_in: Option<i64>,
     /// timestamp is seconds since epoch indicating when the token will expire
     /// in absolute terms.
     pub expires_in_timestamp: Option<SystemTime>,
+    /// The scopes that were actually granted, if the server reported them.
+    /// Prefer using `granted_scopes()`
+    #[serde(default)]
+    pub granted_scopes: Vec<String>,
+}
+
+impl Token {
+    /// Creates a token that expires after the specified lifetime, without a
+    /// refresh token or granted scopes. Mainly useful for test fixtures and
+    /// mock providers.
+    ///
+    /// A lifetime too large to be represented saturates, ie. the token
+    /// expires at the latest time the platform can represent, rather than
+    /// immediately.
+    pub fn new(
+        access_token: impl Into<String>,
+        token_type: impl Into<String>,
+        lifetime: Duration,
+    ) -> Self {
+        Self {
+            access_token: access_token.into(),
+            refresh_token: String::new(),
+            token_type: token_type.into(),
+            expires_in: Some(i64::try_from(lifetime.as_secs()).unwrap_or(i64::MAX)),
+            expires_in_timestamp: Some(saturating_expiry(SystemTime::now(), lifetime)),
+            granted_scopes: Vec::new(),
+        }
+    }
+
+    /// Creates a `Bearer` token that expires after the specified lifetime,
+    /// see [`Token::new`]
+    pub fn new_bearer(access_token: impl Into<String>, lifetime: Duration) -> Self {
+        Self::new(access_token, "Bearer", lifetime)
+    }
+
+    /// Creates a token from the value of an `Authorization` header, eg. one
+    /// received from middleware, which is the inverse of converting a token
+    /// into a [`HeaderValue`](http::HeaderValue). Only the `Bearer` scheme
+    /// is supported.
+    ///
+    /// If `expires_in` is `None` the expiry of the token is unknown, which
+    /// means a [`CachedTokenProvider`](crate::token_cache::CachedTokenProvider)
+    /// treats it as already expired.
+    pub fn from_authorization_header(
+        value: &http::HeaderValue,
+        expires_in: Option<Duration>,
+    ) -> Result<Self, Error> {
+        let value = value.to_str().map_err(|_e| {
+            Error::InvalidAuthorizationHeader("the value contains non-ASCII characters")
+        })?;
+        let (scheme, access_token) =
+            value
+                .trim()
+                .split_once(' ')
+                .ok_or(Error::InvalidAuthorizationHeader(
+                    "missing a space between the scheme and the token",
+                ))?;
+
+        if !scheme.eq_ignore_ascii_case("Bearer") {
+            return Err(Error::InvalidAuthorizationHeader(
+                "the scheme is not Bearer",
+            ));
+        }
+
+        // The value is trimmed, so there is always a token after the space
+        let access_token = access_token.trim_start();
+
+        Ok(match expires_in {
+            Some(lifetime) => Self::new(access_token, scheme, lifetime),
+            None => Self {
+                access_token: access_token.to_owned(),
+                refresh_token: String::new(),
+                token_type: scheme.to_owned(),
+                expires_in: None,
+                expires_in_timestamp: None,
+                granted_scopes: Vec::new(),
+            },
+        })
+    }
+
+    /// The point in time at which the token expires, if known
+    pub fn expiry_date(&self) -> Option<SystemTime> {
+        self.expires_in_timestamp
+    }
+
+    /// The amount of time until the token expires, or `None` if it has
+    /// already expired or the expiry is unknown
+    pub fn time_until_expiry(&self) -> Option<Duration> {
+        self.expires_in_timestamp?
+            .duration_since(SystemTime::now())
+            .ok()
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Compares when this token and `other` expire, a token with an unknown
+    /// expiry is treated as expiring before any token with a known expiry,
+    /// so the longest lived of several tokens can be picked with eg.
+    /// `tokens.iter().max_by(|a, b| a.cmp_expiry(b))`
+    pub fn cmp_expiry(&self, other: &Token) -> std::cmp::Ordering {
+        // `None` is ordered before any `Some`
+        self.expires_in_timestamp.cmp(&other.expires_in_timestamp)
+    }
+
+    /// Returns true if this token expires after `other`, see
+    /// [`Token::cmp_expiry`] for how an unknown expiry is handled
+    pub fn expires_after(&self, other: &Token) -> bool {
+        self.cmp_expiry(other) == std::cmp::Ordering::Greater
+    }
+
+    /// The scopes that were actually granted for this token. This can differ
+    /// from the requested scopes, eg. end user credentials only have the
+    /// scopes the user authorized, so this can be used to detect a scope
+    /// downgrade up front rather than failing later with a 403.
+    ///
+    /// Empty if the server didn't report the granted scopes.
+    pub fn granted_scopes(&self) -> &[String] {
+        &self.granted_scopes
+    }
+
+    /// The requested scopes that were not granted for this token, see
+    /// [`Token::granted_scopes`], empty if all of them were granted.
+    ///
+    /// As it can't be known which scopes are missing if the server didn't
+    /// report the granted scopes, this is also empty in that case.
+    pub fn missing_scopes<'a, S, I>(&self, requested: I) -> Vec<String>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S>,
+    {
+        if self.granted_scopes.is_empty() {
+            return Vec::new();
+        }
+
+        requested
+            .into_iter()
+            .map(AsRef::as_ref)
+            .filter(|scope| !self.granted_scopes.iter().any(|granted| granted == scope))
+            .map(String::from)
+            .collect()
+    }
 }
 
 impl CacheableToken for Token {
-    /// Returns true if we are expired.
+    /// Tokens without an expiry timestamp are treated as already expired.
     #[inline]
     fn has_expired(&self) -> bool {
+        self.expires_at()
+            .is_none_or(|expires_at| expires_at <= SystemTime::now())
+    }
+
+    #[inline]
+    fn expires_at(&self) -> Option<SystemTime> {
         if self.access_token.is_empty() {
-            return true;
+            return None;
         }
 
-        let expiry = self.expires_in_timestamp.unwrap_or_else(SystemTime::now);
-
-        expiry <= SystemTime::now()
+        self.expires_in_timestamp
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestReason {
     /// An existing token has expired
     Expired,
     /// The requested scopes or audience have never been seen before
     ParametersChanged,
+    /// A new token was explicitly requested, bypassing the cache
+    Forced,
 }
 
 /// Either a valid token, or an HTTP request that can be used to acquire one
+///
+/// Two requests are equal if their method, URI, version, headers and body are
+/// equal, which allows asserting the whole shape of a request in tests.
 #[derive(Debug)]
 pub enum TokenOrRequest {
     /// A valid token that can be supplied in an API request
@@ -63,6 +219,64 @@ pub enum TokenOrRequest {
         /// An opaque hash of the unique parameters for which the request was constructed
         scope_hash: u64,
     },
+    /// Some token sources first need an access token from another token
+    /// source before the actual token can be requested. The response to this
+    /// request must be passed to [`TokenProvider::get_token_with_access_token`],
+    /// which returns the request for the actual token.
+    AccessTokenRequest {
+        /// The parts of an HTTP request that must be sent to acquire the
+        /// access token, in the client of your choice
+        request: http::Request<Vec<u8>>,
+        /// The reason we need to retrieve a new token
+        reason: RequestReason,
+        /// An opaque hash of the unique parameters for which the request was constructed
+        scope_hash: u64,
+    },
+}
+
+impl PartialEq for TokenOrRequest {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Token(a), Self::Token(b)) => a == b,
+            (
+                Self::Request {
+                    request: a,
+                    reason: a_reason,
+                    scope_hash: a_hash,
+                },
+                Self::Request {
+                    request: b,
+                    reason: b_reason,
+                    scope_hash: b_hash,
+                },
+            )
+            | (
+                Self::AccessTokenRequest {
+                    request: a,
+                    reason: a_reason,
+                    scope_hash: a_hash,
+                },
+                Self::AccessTokenRequest {
+                    request: b,
+                    reason: b_reason,
+                    scope_hash: b_hash,
+                },
+            ) => a_reason == b_reason && a_hash == b_hash && requests_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for TokenOrRequest {}
+
+/// Compares the parts of two requests that determine what is sent, as
+/// [`http::Request`] doesn't implement [`PartialEq`]
+pub(crate) fn requests_eq(a: &http::Request<Vec<u8>>, b: &http::Request<Vec<u8>>) -> bool {
+    a.method() == b.method()
+        && a.uri() == b.uri()
+        && a.version() == b.version()
+        && a.headers() == b.headers()
+        && a.body() == b.body()
 }
 
 /// A `TokenProvider` has a single method to implement `get_token_with_subject`.
@@ -98,6 +312,44 @@ pub trait TokenProvider {
         I: IntoIterator<Item = &'a S> + Clone,
         T: Into<String>;
 
+    /// Returns true if the provider supports the subject passed to
+    /// [`TokenProvider::get_token_with_subject`], ie. domain-wide delegation,
+    /// otherwise passing a subject returns an error. Defaults to false.
+    fn supports_subject(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the provider can also produce id tokens via
+    /// [`IdTokenProvider`](crate::id_token::IdTokenProvider), so that callers
+    /// can choose another way of authenticating up front rather than handling
+    /// the error. Defaults to false.
+    fn supports_id_tokens(&self) -> bool {
+        false
+    }
+
+    /// Some token sources require an access token from another token source
+    /// to request the actual token. If `get_token` returns a
+    /// [`TokenOrRequest::AccessTokenRequest`], call this method with the
+    /// response to it, and the same scopes, to get the request for the actual
+    /// token, whose response is then passed to `parse_token_response` as usual.
+    ///
+    /// The default implementation returns an error, as most token sources
+    /// only need a single request.
+    fn get_token_with_access_token<'a, S, I, R>(
+        &self,
+        _scopes: I,
+        _response: AccessTokenResponse<R>,
+    ) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        R: AsRef<[u8]>,
+    {
+        Err(Error::Auth(error::AuthError::unsupported(
+            "This token source does not use an intermediate access token",
+        )))
+    }
+
     /// Once a response has been received for a token request, call this method
     /// to deserialize the token (and potentially store it in a local cache for
     /// reuse until it expires).
@@ -108,6 +360,84 @@ pub trait TokenProvider {
     ) -> Result<Token, Error>
     where
         S: AsRef<[u8]>;
+
+    /// Like [`TokenProvider::parse_token_response`], but for a response that
+    /// has already been split into its parts and body, eg. by an HTTP client
+    /// wrapper that is generic over its body type, where inferring the body
+    /// type for `parse_token_response` can get in the way.
+    ///
+    /// Prefer `parse_token_response` if you have an [`http::Response`], this
+    /// only avoids the generic body parameter.
+    fn parse_token_response_bytes(
+        &self,
+        hash: u64,
+        parts: http::response::Parts,
+        body: &[u8],
+    ) -> Result<Token, Error> {
+        self.parse_token_response(hash, http::Response::from_parts(parts, body))
+    }
+}
+
+/// Adds the lifetime to `now`, or as much of it as can be represented by
+/// `SystemTime` on the platform
+fn saturating_expiry(now: SystemTime, mut lifetime: Duration) -> SystemTime {
+    loop {
+        if let Some(expiry) = now.checked_add(lifetime) {
+            return expiry;
+        }
+
+        lifetime /= 2;
+    }
+}
+
+/// Starts a request with the headers all requests created by this crate have,
+/// ie. a `User-Agent` of [`DEFAULT_USER_AGENT`](crate::token_cache::DEFAULT_USER_AGENT)
+/// and `Accept-Encoding: identity`
+#[cfg(any(feature = "jwt", feature = "oauth2"))]
+pub(crate) fn request_builder() -> http::request::Builder {
+    http::Request::builder()
+        .header(
+            http::header::USER_AGENT,
+            crate::token_cache::DEFAULT_USER_AGENT,
+        )
+        // The responses are parsed as is, so they must not be compressed, even
+        // if the HTTP client would otherwise advertise that it accepts
+        // compression
+        .header(http::header::ACCEPT_ENCODING, "identity")
+}
+
+/// Gets a token for the specified scopes from the provider, calling `send`
+/// with each request needed to retrieve a new token if it doesn't have a
+/// valid cached token, and passing the responses back to the provider.
+///
+/// This removes the boilerplate of matching on [`TokenOrRequest`] while
+/// staying agnostic of the HTTP client, which only needs to be able to send
+/// requests synchronously.
+pub fn drive_token<P, F>(provider: &P, scopes: &[&str], mut send: F) -> Result<Token, Error>
+where
+    P: TokenProvider,
+    F: FnMut(http::Request<Vec<u8>>) -> Result<http::Response<Vec<u8>>, Error>,
+{
+    let (request, scope_hash) = match provider.get_token(scopes)? {
+        TokenOrRequest::Token(token) => return Ok(token),
+        TokenOrRequest::Request {
+            request,
+            scope_hash,
+            ..
+        } => (request, scope_hash),
+        TokenOrRequest::AccessTokenRequest {
+            request,
+            scope_hash,
+            ..
+        } => {
+            let response = send(request)?;
+            let request = provider.get_token_with_access_token(scopes, response)?;
+            (request, scope_hash)
+        }
+    };
+
+    let response = send(request)?;
+    provider.parse_token_response(scope_hash, response)
 }
 
 impl std::convert::TryInto<http::header::HeaderValue> for Token {
@@ -119,3 +449,249 @@ impl std::convert::TryInto<http::header::HeaderValue> for Token {
             .map_err(|e| crate::Error::from(http::Error::from(e)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{RequestReason, Token, TokenOrRequest, TokenProvider};
+    use crate::Error;
+    use std::time::{Duration, SystemTime};
+
+    /// A provider that needs an access token to request the actual token
+    struct TwoStepProvider;
+
+    impl TokenProvider for TwoStepProvider {
+        fn get_token_with_subject<'a, S, I, T>(
+            &self,
+            _subject: Option<T>,
+            _scopes: I,
+        ) -> Result<TokenOrRequest, Error>
+        where
+            S: AsRef<str> + 'a,
+            I: IntoIterator<Item = &'a S> + Clone,
+            T: Into<String>,
+        {
+            Ok(TokenOrRequest::AccessTokenRequest {
+                request: http::Request::post("https://example.com/access").body(Vec::new())?,
+                reason: RequestReason::ParametersChanged,
+                scope_hash: 7,
+            })
+        }
+
+        fn get_token_with_access_token<'a, S, I, R>(
+            &self,
+            _scopes: I,
+            response: http::Response<R>,
+        ) -> Result<http::Request<Vec<u8>>, Error>
+        where
+            S: AsRef<str> + 'a,
+            I: IntoIterator<Item = &'a S> + Clone,
+            R: AsRef<[u8]>,
+        {
+            Ok(http::Request::post("https://example.com/token")
+                .body(response.body().as_ref().to_vec())?)
+        }
+
+        fn parse_token_response<S>(
+            &self,
+            hash: u64,
+            response: http::Response<S>,
+        ) -> Result<Token, Error>
+        where
+            S: AsRef<[u8]>,
+        {
+            assert_eq!(hash, 7);
+            Ok(Token::new(
+                std::str::from_utf8(response.body().as_ref()).unwrap(),
+                "Bearer",
+                Duration::from_secs(3600),
+            ))
+        }
+    }
+
+    #[test]
+    fn authorization_header() {
+        use std::convert::TryInto;
+
+        let token = Token::new_bearer("access-token", Duration::from_secs(60));
+        let header: http::HeaderValue = token.clone().try_into().unwrap();
+        assert_eq!(header, "Bearer access-token");
+
+        let parsed =
+            Token::from_authorization_header(&header, Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(parsed.access_token, token.access_token);
+        assert_eq!(parsed.token_type, token.token_type);
+        assert!(parsed.time_until_expiry().is_some());
+
+        let parsed =
+            Token::from_authorization_header(&"bearer  access-token".parse().unwrap(), None)
+                .unwrap();
+        assert_eq!(parsed.access_token, "access-token");
+        assert_eq!(parsed.token_type, "bearer");
+        assert!(parsed.expiry_date().is_none());
+
+        for (value, reason) in [
+            ("Bearer", "missing a space between the scheme and the token"),
+            ("Basic dXNlcjpwYXNz", "the scheme is not Bearer"),
+            (
+                "Bearer  ",
+                "missing a space between the scheme and the token",
+            ),
+        ] {
+            assert!(matches!(
+                Token::from_authorization_header(&value.parse().unwrap(), None),
+                Err(Error::InvalidAuthorizationHeader(r)) if r == reason
+            ));
+        }
+    }
+
+    #[test]
+    fn expiry_ordering() {
+        let short = Token::new("short", "Bearer", Duration::from_secs(60));
+        let long = Token::new("long", "Bearer", Duration::from_secs(3600));
+        let mut unknown = Token::new("unknown", "Bearer", Duration::from_secs(7200));
+        unknown.expires_in_timestamp = None;
+
+        assert!(long.expires_after(&short));
+        assert!(!short.expires_after(&long));
+        assert!(!short.expires_after(&short));
+
+        assert!(short.expires_after(&unknown));
+        assert!(!unknown.expires_after(&short));
+        assert!(!unknown.expires_after(&unknown.clone()));
+
+        let tokens = [short.clone(), unknown, long, short];
+        let longest = tokens.iter().max_by(|a, b| a.cmp_expiry(b)).unwrap();
+        assert_eq!(longest.access_token, "long");
+    }
+
+    #[test]
+    fn drive_two_step_token() {
+        let mut sent = Vec::new();
+        let token = super::drive_token(&TwoStepProvider, &["scope"], |request| {
+            sent.push(request.uri().to_string());
+            let body = if request.uri().path() == "/access" {
+                b"access-token".to_vec()
+            } else {
+                [b"token-for-".as_slice(), request.body()].concat()
+            };
+            Ok(http::Response::new(body))
+        })
+        .unwrap();
+
+        assert_eq!(token.access_token, "token-for-access-token");
+        assert_eq!(
+            sent,
+            ["https://example.com/access", "https://example.com/token"]
+        );
+    }
+
+    #[test]
+    fn token_or_request_eq() {
+        let request = |reason, content_type| TokenOrRequest::Request {
+            request: http::Request::post("https://oauth2.googleapis.com/token")
+                .header(http::header::CONTENT_TYPE, content_type)
+                .body(b"grant_type=foo".to_vec())
+                .unwrap(),
+            reason,
+            scope_hash: 1,
+        };
+
+        assert_eq!(
+            request(RequestReason::Expired, "application/x-www-form-urlencoded"),
+            request(RequestReason::Expired, "application/x-www-form-urlencoded")
+        );
+        assert_ne!(
+            request(RequestReason::Expired, "application/x-www-form-urlencoded"),
+            request(RequestReason::Forced, "application/x-www-form-urlencoded")
+        );
+        assert_ne!(
+            request(RequestReason::Expired, "application/x-www-form-urlencoded"),
+            request(RequestReason::Expired, "application/json")
+        );
+
+        let token = Token::new("access-token", "Bearer", Duration::from_secs(3599));
+        assert_eq!(
+            TokenOrRequest::Token(token.clone()),
+            TokenOrRequest::Token(token.clone())
+        );
+        assert_ne!(
+            TokenOrRequest::Token(token),
+            request(RequestReason::Expired, "application/json")
+        );
+    }
+
+    #[test]
+    fn saturating_lifetime() {
+        use crate::token_cache::CacheableToken;
+
+        let token = Token::new_bearer("access-token", Duration::MAX);
+        assert_eq!(token.expires_in, Some(i64::MAX));
+        assert!(!token.has_expired());
+        assert!(token.expires_at().unwrap() > SystemTime::now() + Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let token = Token {
+            access_token: "access-token".to_owned(),
+            refresh_token: String::new(),
+            token_type: "Bearer".to_owned(),
+            expires_in: Some(3599),
+            expires_in_timestamp: SystemTime::now().checked_add(Duration::new(3599, 123_456_789)),
+            granted_scopes: vec!["https://www.googleapis.com/auth/cloud-platform".to_owned()],
+        };
+
+        let json = serde_json::to_string(&token).unwrap();
+        let deserialized: Token = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(token, deserialized);
+    }
+
+    #[test]
+    fn expiry() {
+        let mut token = Token::new("access-token", "Bearer", Duration::from_secs(3599));
+        assert_eq!(token.access_token, "access-token");
+        assert_eq!(token.token_type, "Bearer");
+        assert_eq!(token.expires_in, Some(3599));
+
+        assert_eq!(token.expiry_date(), token.expires_in_timestamp);
+        let remaining = token.time_until_expiry().unwrap();
+        assert!(remaining > Duration::from_secs(3590) && remaining <= Duration::from_secs(3599));
+
+        token.expires_in_timestamp = SystemTime::now().checked_sub(Duration::from_secs(1));
+        assert!(token.time_until_expiry().is_none());
+
+        token.expires_in_timestamp = None;
+        assert!(token.expiry_date().is_none());
+        assert!(token.time_until_expiry().is_none());
+    }
+
+    #[test]
+    fn parse_token_response_bytes() {
+        let (parts, body) = http::Response::new(b"access-token".to_vec()).into_parts();
+        let token = TwoStepProvider
+            .parse_token_response_bytes(7, parts, &body)
+            .unwrap();
+        assert_eq!(token.access_token, "access-token");
+    }
+
+    #[test]
+    fn missing_scopes() {
+        let mut token = Token::new_bearer("access-token", Duration::from_secs(3599));
+        let requested = ["cloud-platform", "devstorage.read_only"];
+
+        // Unknown, so nothing is reported as missing
+        assert!(token.missing_scopes(&requested).is_empty());
+
+        token.granted_scopes = vec!["cloud-platform".to_owned()];
+        assert_eq!(
+            token.missing_scopes(&requested),
+            vec!["devstorage.read_only".to_owned()]
+        );
+
+        token.granted_scopes.push("devstorage.read_only".to_owned());
+        token.granted_scopes.push("openid".to_owned());
+        assert!(token.missing_scopes(&requested).is_empty());
+        assert!(token.missing_scopes(&Vec::<String>::new()).is_empty());
+    }
+}
@@ -5,20 +5,68 @@ use crate::token::{TokenOrRequest, TokenProvider};
 use crate::{error::Error, token::RequestReason, IdToken, Token};
 
 use std::hash::Hasher;
-use std::sync::RwLock;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::{Duration, SystemTime};
 
 type Hash = u64;
 
+/// The default amount of time before a token actually expires at which it is
+/// already treated as expired, so that it isn't used for requests that might
+/// still be in flight when it expires
+pub const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(10);
+
+/// The `User-Agent` sent with the requests created by the providers of this
+/// crate, unless another one is set with [`CachedTokenProvider::with_user_agent`]
+pub const DEFAULT_USER_AGENT: &str = concat!("tame-oauth/", env!("CARGO_PKG_VERSION"));
+
+/// The header [`CachedTokenProvider::with_request_id`] sends the request id in
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// A source of the current time, used to determine if cached tokens have
+/// expired. Defaults to [`SystemClock`], but can be replaced to control time
+/// in tests or simulations.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> SystemTime;
+}
+
+/// A [`Clock`] that uses [`SystemTime::now`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
 #[derive(Debug)]
 struct Entry<T> {
     hash: Hash,
     token: T,
+    /// The value of [`TokenCache::access_clock`] when the entry was last
+    /// inserted or retrieved
+    last_access: AtomicU64,
 }
 
 /// An in-memory cache for caching tokens.
+///
+/// Clones of a cache share the same tokens, but settings such as the
+/// capacity or refresh margin changed after cloning only apply to that clone.
 #[derive(Debug)]
 pub struct TokenCache<T> {
-    cache: RwLock<Vec<Entry<T>>>,
+    cache: Arc<RwLock<Vec<Entry<T>>>>,
+    refresh_margin: Duration,
+    /// The maximum number of entries, unbounded if `None`
+    capacity: Option<usize>,
+    /// Logical clock used to order entries by their last access
+    access_clock: Arc<AtomicU64>,
+    /// Clock used to determine if tokens have expired
+    clock: Arc<dyn Clock>,
 }
 
 pub enum TokenOrRequestReason<T> {
@@ -29,23 +77,70 @@ pub enum TokenOrRequestReason<T> {
 impl<T> TokenCache<T> {
     pub fn new() -> Self {
         Self {
-            cache: RwLock::new(Vec::new()),
+            cache: Arc::new(RwLock::new(Vec::new())),
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            capacity: None,
+            access_clock: Arc::new(AtomicU64::new(0)),
+            clock: Arc::new(SystemClock),
         }
     }
 
+    /// Sets the clock used to determine if tokens have expired, defaults to
+    /// [`SystemClock`]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Limits the cache to the specified number of tokens, when a token is
+    /// inserted into a full cache the least recently used token is evicted.
+    /// The cache is unbounded by default.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// Gets the maximum number of tokens the cache holds, if limited
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    #[inline]
+    fn tick(&self) -> u64 {
+        self.access_clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Sets the amount of time before a token expires at which it is treated
+    /// as expired and a new one is requested instead. Defaults to
+    /// [`DEFAULT_REFRESH_MARGIN`].
+    pub fn with_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+        self.refresh_margin = refresh_margin;
+        self
+    }
+
+    /// Gets the amount of time before a token expires at which it is treated
+    /// as expired
+    pub fn refresh_margin(&self) -> Duration {
+        self.refresh_margin
+    }
+
     /// Get a token from the cache that matches the hash
-    pub fn get(&self, hash: Hash) -> Result<TokenOrRequestReason<T>, Error>
+    pub fn get(&self, hash: Hash) -> TokenOrRequestReason<T>
     where
         T: CacheableToken + Clone,
     {
         let reason = {
-            let cache = self.cache.read().map_err(|_e| Error::Poisoned)?;
+            let cache = self.read();
             match cache.binary_search_by(|i| i.hash.cmp(&hash)) {
                 Ok(i) => {
-                    let token = &cache[i].token;
+                    let entry = &cache[i];
 
-                    if !token.has_expired() {
-                        return Ok(TokenOrRequestReason::Token(token.clone()));
+                    if !entry
+                        .token
+                        .has_expired_at(self.clock.now(), self.refresh_margin)
+                    {
+                        entry.last_access.store(self.tick(), Ordering::Relaxed);
+                        return TokenOrRequestReason::Token(entry.token.clone());
                     }
 
                     RequestReason::Expired
@@ -54,21 +149,106 @@ impl<T> TokenCache<T> {
             }
         };
 
-        Ok(TokenOrRequestReason::RequestReason(reason))
+        TokenOrRequestReason::RequestReason(reason)
     }
 
     /// Insert a token into the cache
-    pub fn insert(&self, token: T, hash: Hash) -> Result<(), Error> {
+    pub fn insert(&self, token: T, hash: Hash) {
         // Last token wins, which...should?...be fine
-        let mut cache = self.cache.write().map_err(|_e| Error::Poisoned)?;
+        let mut cache = self.write();
+        let last_access = AtomicU64::new(self.tick());
+
         match cache.binary_search_by(|i| i.hash.cmp(&hash)) {
-            Ok(i) => cache[i].token = token,
-            Err(i) => {
-                cache.insert(i, Entry { hash, token });
+            Ok(i) => {
+                cache[i].token = token;
+                cache[i].last_access = last_access;
+            }
+            Err(mut i) => {
+                if self
+                    .capacity
+                    .is_some_and(|capacity| cache.len() >= capacity)
+                {
+                    if let Some(lru) = cache
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, entry)| entry.last_access.load(Ordering::Relaxed))
+                        .map(|(lru, _)| lru)
+                    {
+                        cache.remove(lru);
+
+                        // Keep the insertion point sorted
+                        if lru < i {
+                            i -= 1;
+                        }
+                    }
+                }
+
+                cache.insert(
+                    i,
+                    Entry {
+                        hash,
+                        token,
+                        last_access,
+                    },
+                );
             }
         };
+    }
+
+    /// Get a token from the cache that matches the hash, as long as it hasn't
+    /// actually expired, ignoring the refresh margin
+    pub fn get_unexpired(&self, hash: Hash) -> Option<T>
+    where
+        T: CacheableToken + Clone,
+    {
+        let cache = self.read();
+        let i = cache.binary_search_by(|i| i.hash.cmp(&hash)).ok()?;
+        let entry = &cache[i];
 
-        Ok(())
+        if entry.token.has_expired_at(self.clock.now(), Duration::ZERO) {
+            return None;
+        }
+
+        entry.last_access.store(self.tick(), Ordering::Relaxed);
+        Some(entry.token.clone())
+    }
+
+    /// Removes the token that matches the hash, if any
+    pub fn remove(&self, hash: Hash) {
+        let mut cache = self.write();
+        if let Ok(i) = cache.binary_search_by(|i| i.hash.cmp(&hash)) {
+            cache.remove(i);
+        }
+    }
+
+    /// Removes all tokens
+    pub fn clear(&self) {
+        self.write().clear();
+    }
+
+    // The entries are always left in a consistent state, even if a thread
+    // panics while holding the lock, so poisoning can be safely ignored
+
+    #[inline]
+    fn read(&self) -> RwLockReadGuard<'_, Vec<Entry<T>>> {
+        self.cache.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    fn write(&self) -> RwLockWriteGuard<'_, Vec<Entry<T>>> {
+        self.cache.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Clone for TokenCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            cache: Arc::clone(&self.cache),
+            refresh_margin: self.refresh_margin,
+            capacity: self.capacity,
+            access_clock: Arc::clone(&self.access_clock),
+            clock: Arc::clone(&self.clock),
+        }
     }
 }
 
@@ -79,17 +259,107 @@ impl<T> Default for TokenCache<T> {
 }
 
 pub trait CacheableToken {
+    /// Returns true if the token has expired
     fn has_expired(&self) -> bool;
+
+    /// The point in time at which the token expires, if known. Defaults to
+    /// `None`, in which case margins can't be applied and whether the token
+    /// has expired is determined by [`Self::has_expired`] alone.
+    #[inline]
+    fn expires_at(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Returns true if the token has expired, or will expire within the
+    /// specified margin
+    #[inline]
+    fn has_expired_with_margin(&self, margin: Duration) -> bool {
+        self.has_expired_at(SystemTime::now(), margin)
+    }
+
+    /// Returns true if the token has expired, or will expire within the
+    /// specified margin, at the specified point in time. A margin so large
+    /// that it can't be represented is treated as covering any expiry.
+    #[inline]
+    fn has_expired_at(&self, now: SystemTime, margin: Duration) -> bool {
+        match self.expires_at() {
+            Some(expires_at) => now
+                .checked_add(margin)
+                .is_none_or(|deadline| expires_at <= deadline),
+            None => self.has_expired(),
+        }
+    }
+}
+
+/// The number of requests for new tokens a [`CachedTokenProvider`] has
+/// returned, by [`RequestReason`]
+///
+/// A high `parameters_changed` count relative to `expired` usually means the
+/// caller requests tokens for many slightly different scope sets or
+/// audiences, eg. the same scopes in a different order or with duplicates,
+/// which should be normalized so that the cached tokens are reused.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RefreshCounts {
+    /// Requests made because a cached token expired
+    pub expired: u64,
+    /// Requests made because the scopes or audience had no cached token
+    pub parameters_changed: u64,
+    /// Requests made via `get_token_forced` or `get_id_token_forced`
+    pub forced: u64,
+}
+
+#[derive(Default)]
+struct RefreshCounters {
+    expired: AtomicU64,
+    parameters_changed: AtomicU64,
+    forced: AtomicU64,
+}
+
+impl RefreshCounters {
+    fn record(&self, reason: &RequestReason) {
+        let counter = match reason {
+            RequestReason::Expired => &self.expired,
+            RequestReason::ParametersChanged => &self.parameters_changed,
+            RequestReason::Forced => &self.forced,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 /// Wraps a `TokenProvider` in a cache, only invokes the inner `TokenProvider` if
 /// the token in cache is expired, or if it doesn't exist.
+///
+/// Cloning the provider creates another handle to the same provider, ie. the
+/// clones share their token caches, so a provider can be shared across tasks
+/// without wrapping it in an `Arc`. The provider should be fully configured
+/// before it is cloned, as a clone configured differently, eg. for another
+/// token URI, would still share the tokens of the original.
+///
+/// The `User-Agent` of the requests created by the provider can be replaced
+/// with [`Self::with_user_agent`], and a request id can be added with
+/// [`Self::with_request_id`].
 pub struct CachedTokenProvider<P> {
     access_tokens: TokenCache<Token>,
     id_tokens: TokenCache<IdToken>,
+    on_stale: Option<Arc<dyn Fn(Hash) + Send + Sync>>,
+    headers: RequestHeaders,
+    refreshes: Arc<RefreshCounters>,
     inner: P,
 }
 
+impl<P: Clone> Clone for CachedTokenProvider<P> {
+    fn clone(&self) -> Self {
+        Self {
+            access_tokens: self.access_tokens.clone(),
+            id_tokens: self.id_tokens.clone(),
+            on_stale: self.on_stale.clone(),
+            headers: self.headers.clone(),
+            refreshes: self.refreshes.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
 impl<P: std::fmt::Debug> std::fmt::Debug for CachedTokenProvider<P> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CachedTokenProvider")
@@ -104,20 +374,369 @@ impl<P> CachedTokenProvider<P> {
         Self {
             access_tokens: TokenCache::new(),
             id_tokens: TokenCache::new(),
+            on_stale: None,
+            headers: RequestHeaders::default(),
+            refreshes: Arc::default(),
             inner: token_provider,
         }
     }
 
+    /// Registers a callback that is invoked with the scope hash of a cached
+    /// access token that is within the refresh margin, ie. stale, but hasn't
+    /// actually expired yet. Instead of returning a request, the stale token
+    /// is returned, so that the token can be refreshed out of band, eg. in a
+    /// background task, rather than by whichever request happens to find the
+    /// token stale.
+    ///
+    /// The callback is invoked every time the stale token is retrieved, until
+    /// it has been refreshed with a new token via [`TokenProvider::parse_token_response`],
+    /// so it is up to the callback to deduplicate refreshes. Once the token
+    /// has actually expired, a request is returned as usual.
+    pub fn on_stale(mut self, callback: Box<dyn Fn(u64) + Send + Sync>) -> Self {
+        self.on_stale = Some(Arc::from(callback));
+        self
+    }
+
+    /// Sets the amount of time before a cached token expires at which it is
+    /// treated as expired and a new one is requested instead. Defaults to
+    /// [`DEFAULT_REFRESH_MARGIN`].
+    pub fn with_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+        self.access_tokens.refresh_margin = refresh_margin;
+        self.id_tokens.refresh_margin = refresh_margin;
+        self
+    }
+
+    /// Sets the clock used to determine if cached tokens have expired,
+    /// defaults to [`SystemClock`]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        self.access_tokens = self.access_tokens.with_clock(clock.clone());
+        self.id_tokens = self.id_tokens.with_clock(clock);
+        self
+    }
+
+    /// Limits the number of cached access tokens, and separately id tokens,
+    /// see [`TokenCache::with_capacity`]. The caches are unbounded by
+    /// default, so this is useful for long running processes that request
+    /// tokens for many different scopes or audiences.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.access_tokens = self.access_tokens.with_capacity(capacity);
+        self.id_tokens = self.id_tokens.with_capacity(capacity);
+        self
+    }
+
+    /// Replaces the `User-Agent` header of all token requests, including the
+    /// requests for the access tokens some providers need first, which the
+    /// providers of this crate set to [`DEFAULT_USER_AGENT`]. Google
+    /// recommends identifying the application, eg.
+    /// `my-app/1.2.3 tame-oauth/0.10.0`.
+    ///
+    /// The header is replaced rather than appended to, so a `User-Agent` set
+    /// on a request after it has been returned replaces this one, as long as
+    /// it is set with [`http::HeaderMap::insert`].
+    pub fn with_user_agent(mut self, user_agent: &str) -> Result<Self, Error> {
+        self.headers.user_agent =
+            Some(http::HeaderValue::from_str(user_agent).map_err(http::Error::from)?);
+        Ok(self)
+    }
+
+    /// The `User-Agent` header sent with token requests
+    pub fn user_agent(&self) -> &http::HeaderValue {
+        static DEFAULT: http::HeaderValue = http::HeaderValue::from_static(DEFAULT_USER_AGENT);
+        self.headers.user_agent.as_ref().unwrap_or(&DEFAULT)
+    }
+
+    /// Sets the [`REQUEST_ID_HEADER`] of all token requests, including the
+    /// requests for the access tokens some providers need first, so that the
+    /// requests can be correlated with server side logs. Servers and proxies
+    /// that echo the header back make it available as
+    /// [`AuthError::correlation_id`](crate::error::AuthError::correlation_id)
+    /// if the request fails.
+    pub fn with_request_id(self, request_id: &str) -> Result<Self, Error> {
+        self.with_request_id_header(REQUEST_ID_HEADER, request_id)
+    }
+
+    /// Like [`Self::with_request_id`], but sends the request id in the
+    /// specified header, eg. `x-correlation-id`
+    pub fn with_request_id_header(mut self, header: &str, request_id: &str) -> Result<Self, Error> {
+        let header =
+            http::header::HeaderName::from_bytes(header.as_bytes()).map_err(http::Error::from)?;
+        let request_id = http::HeaderValue::from_str(request_id).map_err(http::Error::from)?;
+        self.headers.request_id = Some((header, request_id));
+        Ok(self)
+    }
+
+    /// The header and value of the request id sent with token requests, if any
+    pub fn request_id(&self) -> Option<(&http::header::HeaderName, &http::HeaderValue)> {
+        self.headers
+            .request_id
+            .as_ref()
+            .map(|(header, request_id)| (header, request_id))
+    }
+
+    /// The number of requests for new access and id tokens returned so far,
+    /// shared by all clones of the provider. Requests are counted when they
+    /// are returned, whether or not they are sent, while stale tokens
+    /// returned due to [`Self::on_stale`] are not counted.
+    pub fn refresh_counts(&self) -> RefreshCounts {
+        RefreshCounts {
+            expired: self.refreshes.expired.load(Ordering::Relaxed),
+            parameters_changed: self.refreshes.parameters_changed.load(Ordering::Relaxed),
+            forced: self.refreshes.forced.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Drops all cached access and id tokens, eg. after a grant has been
+    /// revoked, so that new tokens are requested
+    pub fn clear(&self) {
+        self.access_tokens.clear();
+        self.id_tokens.clear();
+    }
+
+    /// Drops the cached access token for the specified scopes, if any, so that
+    /// a new token is requested for them
+    pub fn invalidate_scopes<'a, I, S>(&self, scopes: I)
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+    {
+        self.access_tokens.remove(hash_scopes(&scopes));
+    }
+
+    /// Drops the cached id token for the specified audience, if any, so that
+    /// a new token is requested for it
+    pub fn invalidate_audience(&self, audience: &str) {
+        self.id_tokens.remove(hash_str(audience));
+    }
+
     /// Gets a reference to the wrapped (uncached) token provider
     pub fn inner(&self) -> &P {
         &self.inner
     }
+
+    /// Gets a mutable reference to the wrapped (uncached) token provider
+    #[cfg(feature = "oauth2")]
+    pub(crate) fn inner_mut(&mut self) -> &mut P {
+        &mut self.inner
+    }
+}
+
+impl<P> CachedTokenProvider<P>
+where
+    P: TokenProvider,
+{
+    /// Like [`TokenProvider::get_token`], but skips the cache and always
+    /// returns a request for a new token, eg. when the cached token has been
+    /// rejected by the remote side due to a key rotation or revocation. The
+    /// new token is cached as usual by [`TokenProvider::parse_token_response`].
+    ///
+    /// Note that the existing cached token, if any, is not removed until the
+    /// new token is parsed, so concurrent calls to [`TokenProvider::get_token`]
+    /// keep returning it, use [`Self::invalidate_scopes`] if that is not
+    /// desired.
+    pub fn get_token_forced<'a, S, I>(&self, scopes: I) -> Result<TokenOrRequest, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+    {
+        let scope_hash = hash_scopes(&scopes);
+        let token_or_request = self.inner.get_token_with_subject(None::<&str>, scopes)?;
+        Ok(with_reason(
+            token_or_request,
+            RequestReason::Forced,
+            scope_hash,
+            &self.headers,
+            &self.refreshes,
+        ))
+    }
+}
+
+impl<P> CachedTokenProvider<P>
+where
+    P: IdTokenProvider,
+{
+    /// Like [`IdTokenProvider::get_id_token`], but skips the cache and always
+    /// returns a request for a new token. The new token is cached as usual by
+    /// [`IdTokenProvider::parse_id_token_response`].
+    ///
+    /// Note that the existing cached token, if any, is not removed until the
+    /// new token is parsed, use [`Self::invalidate_audience`] if that is not
+    /// desired.
+    pub fn get_id_token_forced(&self, audience: &str) -> Result<IdTokenOrRequest, Error> {
+        let token_or_request = self.inner.get_id_token(audience)?;
+        Ok(with_id_reason(
+            token_or_request,
+            RequestReason::Forced,
+            hash_str(audience),
+            &self.headers,
+            &self.refreshes,
+        ))
+    }
+
+    /// Returns the cached id token for the audience, if any, otherwise the
+    /// request created by `get_id_token`, which allows providers to offer
+    /// alternative ways of retrieving id tokens that still use the cache
+    pub(crate) fn get_id_token_with(
+        &self,
+        audience: &str,
+        get_id_token: impl FnOnce(&P) -> Result<IdTokenOrRequest, Error>,
+    ) -> Result<IdTokenOrRequest, Error> {
+        let hash = hash_str(audience);
+
+        let reason = match self.id_tokens.get(hash) {
+            TokenOrRequestReason::Token(token) => return Ok(IdTokenOrRequest::IdToken(token)),
+            TokenOrRequestReason::RequestReason(reason) => reason,
+        };
+
+        let token_or_request = get_id_token(&self.inner)?;
+        Ok(with_id_reason(
+            token_or_request,
+            reason,
+            hash,
+            &self.headers,
+            &self.refreshes,
+        ))
+    }
+}
+
+/// Checks that the hash an uncached provider returned, if any, matches the one
+/// computed by the cache, which is the one that is actually used. Providers
+/// outside of this crate may not compute a hash, ie. return 0.
+#[inline]
+fn debug_assert_consistent(inner_hash: Hash, hash: Hash) {
+    debug_assert!(
+        inner_hash == 0 || inner_hash == hash,
+        "the provider returned the hash {} but the cache computed {}",
+        inner_hash,
+        hash
+    );
+}
+
+/// The headers [`CachedTokenProvider`] sets on the requests of the provider
+/// it wraps
+#[derive(Clone, Default)]
+struct RequestHeaders {
+    /// Set with [`CachedTokenProvider::with_user_agent`]
+    user_agent: Option<http::HeaderValue>,
+    /// Set with [`CachedTokenProvider::with_request_id`]
+    request_id: Option<(http::header::HeaderName, http::HeaderValue)>,
+}
+
+/// Replaces the `User-Agent` and request id of a request if they were set on
+/// the [`CachedTokenProvider`]
+fn set_headers(
+    mut request: http::Request<Vec<u8>>,
+    headers: &RequestHeaders,
+) -> http::Request<Vec<u8>> {
+    if let Some(user_agent) = &headers.user_agent {
+        request
+            .headers_mut()
+            .insert(http::header::USER_AGENT, user_agent.clone());
+    }
+    if let Some((header, request_id)) = &headers.request_id {
+        request
+            .headers_mut()
+            .insert(header.clone(), request_id.clone());
+    }
+    request
+}
+
+/// Replaces the reason and hash of a request from an uncached provider with
+/// the ones determined by the cache, sets the headers and counts the refresh
+fn with_reason(
+    token_or_request: TokenOrRequest,
+    reason: RequestReason,
+    scope_hash: Hash,
+    headers: &RequestHeaders,
+    refreshes: &RefreshCounters,
+) -> TokenOrRequest {
+    if !matches!(token_or_request, TokenOrRequest::Token(..)) {
+        refreshes.record(&reason);
+    }
+
+    match token_or_request {
+        TokenOrRequest::Token(token) => TokenOrRequest::Token(token),
+        TokenOrRequest::Request {
+            request,
+            scope_hash: inner_hash,
+            ..
+        } => {
+            debug_assert_consistent(inner_hash, scope_hash);
+            TokenOrRequest::Request {
+                request: set_headers(request, headers),
+                reason,
+                scope_hash,
+            }
+        }
+        TokenOrRequest::AccessTokenRequest {
+            request,
+            scope_hash: inner_hash,
+            ..
+        } => {
+            debug_assert_consistent(inner_hash, scope_hash);
+            TokenOrRequest::AccessTokenRequest {
+                request: set_headers(request, headers),
+                reason,
+                scope_hash,
+            }
+        }
+    }
+}
+
+/// Replaces the reason and hash of a request from an uncached provider with
+/// the ones determined by the cache, sets the headers and counts the refresh
+fn with_id_reason(
+    token_or_request: IdTokenOrRequest,
+    reason: RequestReason,
+    audience_hash: Hash,
+    headers: &RequestHeaders,
+    refreshes: &RefreshCounters,
+) -> IdTokenOrRequest {
+    if !matches!(token_or_request, IdTokenOrRequest::IdToken(..)) {
+        refreshes.record(&reason);
+    }
+
+    match token_or_request {
+        IdTokenOrRequest::IdToken(token) => IdTokenOrRequest::IdToken(token),
+        IdTokenOrRequest::AccessTokenRequest {
+            request,
+            audience_hash: inner_hash,
+            ..
+        } => {
+            debug_assert_consistent(inner_hash, audience_hash);
+            IdTokenOrRequest::AccessTokenRequest {
+                request: set_headers(request, headers),
+                reason,
+                audience_hash,
+            }
+        }
+        IdTokenOrRequest::IdTokenRequest {
+            request,
+            audience_hash: inner_hash,
+            ..
+        } => {
+            debug_assert_consistent(inner_hash, audience_hash);
+            IdTokenOrRequest::IdTokenRequest {
+                request: set_headers(request, headers),
+                reason,
+                audience_hash,
+            }
+        }
+    }
 }
 
 impl<P> TokenProvider for CachedTokenProvider<P>
 where
     P: TokenProvider,
 {
+    fn supports_subject(&self) -> bool {
+        self.inner.supports_subject()
+    }
+
+    fn supports_id_tokens(&self) -> bool {
+        self.inner.supports_id_tokens()
+    }
+
     fn get_token_with_subject<'a, S, I, T>(
         &self,
         subject: Option<T>,
@@ -130,19 +749,40 @@ where
     {
         let scope_hash = hash_scopes(&scopes);
 
-        let reason = match self.access_tokens.get(scope_hash)? {
+        let reason = match self.access_tokens.get(scope_hash) {
             TokenOrRequestReason::Token(token) => return Ok(TokenOrRequest::Token(token)),
             TokenOrRequestReason::RequestReason(reason) => reason,
         };
 
-        match self.inner.get_token_with_subject(subject, scopes)? {
-            TokenOrRequest::Token(token) => Ok(TokenOrRequest::Token(token)),
-            TokenOrRequest::Request { request, .. } => Ok(TokenOrRequest::Request {
-                request,
-                reason,
-                scope_hash,
-            }),
+        if let (Some(on_stale), RequestReason::Expired) = (&self.on_stale, &reason) {
+            if let Some(token) = self.access_tokens.get_unexpired(scope_hash) {
+                on_stale(scope_hash);
+                return Ok(TokenOrRequest::Token(token));
+            }
         }
+
+        let token_or_request = self.inner.get_token_with_subject(subject, scopes)?;
+        Ok(with_reason(
+            token_or_request,
+            reason,
+            scope_hash,
+            &self.headers,
+            &self.refreshes,
+        ))
+    }
+
+    fn get_token_with_access_token<'a, S, I, R>(
+        &self,
+        scopes: I,
+        response: crate::id_token::AccessTokenResponse<R>,
+    ) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        R: AsRef<[u8]>,
+    {
+        let request = self.inner.get_token_with_access_token(scopes, response)?;
+        Ok(set_headers(request, &self.headers))
     }
 
     fn parse_token_response<S>(
@@ -155,7 +795,7 @@ where
     {
         let token = self.inner.parse_token_response(hash, response)?;
 
-        self.access_tokens.insert(token.clone(), hash)?;
+        self.access_tokens.insert(token.clone(), hash);
         Ok(token)
     }
 }
@@ -165,30 +805,7 @@ where
     P: IdTokenProvider,
 {
     fn get_id_token(&self, audience: &str) -> Result<IdTokenOrRequest, Error> {
-        let hash = hash_str(audience);
-
-        let reason = match self.id_tokens.get(hash)? {
-            TokenOrRequestReason::Token(token) => return Ok(IdTokenOrRequest::IdToken(token)),
-            TokenOrRequestReason::RequestReason(reason) => reason,
-        };
-
-        match self.inner.get_id_token(audience)? {
-            IdTokenOrRequest::IdToken(token) => Ok(IdTokenOrRequest::IdToken(token)),
-            IdTokenOrRequest::AccessTokenRequest { request, .. } => {
-                Ok(IdTokenOrRequest::AccessTokenRequest {
-                    request,
-                    reason,
-                    audience_hash: hash,
-                })
-            }
-            IdTokenOrRequest::IdTokenRequest { request, .. } => {
-                Ok(IdTokenOrRequest::IdTokenRequest {
-                    request,
-                    reason,
-                    audience_hash: hash,
-                })
-            }
-        }
+        self.get_id_token_with(audience, |inner| inner.get_id_token(audience))
     }
 
     fn get_id_token_with_access_token<S>(
@@ -199,8 +816,10 @@ where
     where
         S: AsRef<[u8]>,
     {
-        self.inner
-            .get_id_token_with_access_token(audience, response)
+        let request = self
+            .inner
+            .get_id_token_with_access_token(audience, response)?;
+        Ok(set_headers(request, &self.headers))
     }
 
     fn parse_id_token_response<S>(
@@ -213,57 +832,209 @@ where
     {
         let token = self.inner.parse_id_token_response(hash, response)?;
 
-        self.id_tokens.insert(token.clone(), hash)?;
+        self.id_tokens.insert(token.clone(), hash);
         Ok(token)
     }
 }
 
-fn hash_str(str: &str) -> Hash {
-    let hash = {
-        let mut hasher = twox_hash::XxHash::default();
-        hasher.write(str.as_bytes());
-        hasher.finish()
-    };
+/// Wraps a [`CachedTokenProvider`] so that access and id tokens are only
+/// returned if they are valid for at least the specified amount of time, eg.
+/// for a long running streaming RPC, otherwise a request for a new token is
+/// returned.
+///
+/// Unlike [`CachedTokenProvider::with_refresh_margin`], which applies to all
+/// users of the cache, this only applies to the tokens retrieved via this
+/// wrapper, so wrappers with different minimum lifetimes can share a single
+/// cached provider, eg. `MinLifetimeProvider::new(provider.clone(), lifetime)`.
+///
+/// The minimum lifetime must be shorter than the lifetime of the tokens the
+/// server issues, otherwise every call returns a request for a new token.
+#[derive(Clone, Debug)]
+pub struct MinLifetimeProvider<P> {
+    inner: CachedTokenProvider<P>,
+    min_lifetime: Duration,
+}
 
-    hash
+impl<P> MinLifetimeProvider<P> {
+    /// Wraps a cached provider, requiring the access and id tokens it returns
+    /// to be valid for at least `min_lifetime`
+    pub fn new(provider: CachedTokenProvider<P>, min_lifetime: Duration) -> Self {
+        Self {
+            inner: provider,
+            min_lifetime,
+        }
+    }
+
+    /// The minimum remaining lifetime of the returned tokens
+    pub fn min_lifetime(&self) -> Duration {
+        self.min_lifetime
+    }
+
+    /// Gets a reference to the wrapped cached provider
+    pub fn inner(&self) -> &CachedTokenProvider<P> {
+        &self.inner
+    }
 }
 
-fn hash_scopes<'a, I, S>(scopes: &I) -> Hash
+impl<P> TokenProvider for MinLifetimeProvider<P>
 where
-    S: AsRef<str> + 'a,
-    I: IntoIterator<Item = &'a S> + Clone,
+    P: TokenProvider,
 {
-    let scopes_str = scopes
-        .clone()
-        .into_iter()
-        .map(|s| s.as_ref())
-        .collect::<Vec<_>>()
-        .join("|");
-
-    hash_str(&scopes_str)
-}
+    fn supports_subject(&self) -> bool {
+        self.inner.supports_subject()
+    }
 
-#[cfg(test)]
-mod test {
-    use std::{
-        ops::Add,
-        ops::Sub,
-        time::{Duration, SystemTime},
-    };
+    fn supports_id_tokens(&self) -> bool {
+        self.inner.supports_id_tokens()
+    }
 
-    use super::*;
+    fn get_token_with_subject<'a, S, I, T>(
+        &self,
+        subject: Option<T>,
+        scopes: I,
+    ) -> Result<TokenOrRequest, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        T: Into<String>,
+    {
+        let subject = subject.map(Into::into);
 
-    #[test]
-    fn test_hash_scopes() {
-        use std::hash::Hasher;
+        let now = self.inner.access_tokens.clock.now();
+        match self
+            .inner
+            .get_token_with_subject(subject.clone(), scopes.clone())?
+        {
+            TokenOrRequest::Token(token) if token.has_expired_at(now, self.min_lifetime) => {
+                let scope_hash = hash_scopes(&scopes);
+                let token_or_request = self.inner.inner.get_token_with_subject(subject, scopes)?;
+                Ok(with_reason(
+                    token_or_request,
+                    RequestReason::Expired,
+                    scope_hash,
+                    &self.inner.headers,
+                    &self.inner.refreshes,
+                ))
+            }
+            token_or_request => Ok(token_or_request),
+        }
+    }
 
-        let expected = {
-            let mut hasher = twox_hash::XxHash::default();
-            hasher.write(b"scope1|");
-            hasher.write(b"scope2|");
-            hasher.write(b"scope3");
-            hasher.finish()
-        };
+    fn get_token_with_access_token<'a, S, I, R>(
+        &self,
+        scopes: I,
+        response: crate::id_token::AccessTokenResponse<R>,
+    ) -> Result<http::Request<Vec<u8>>, Error>
+    where
+        S: AsRef<str> + 'a,
+        I: IntoIterator<Item = &'a S> + Clone,
+        R: AsRef<[u8]>,
+    {
+        self.inner.get_token_with_access_token(scopes, response)
+    }
+
+    fn parse_token_response<S>(
+        &self,
+        hash: u64,
+        response: http::Response<S>,
+    ) -> Result<Token, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        self.inner.parse_token_response(hash, response)
+    }
+}
+
+impl<P> IdTokenProvider for MinLifetimeProvider<P>
+where
+    P: IdTokenProvider,
+{
+    fn get_id_token(&self, audience: &str) -> Result<IdTokenOrRequest, Error> {
+        let now = self.inner.id_tokens.clock.now();
+        match self.inner.get_id_token(audience)? {
+            IdTokenOrRequest::IdToken(token) if token.has_expired_at(now, self.min_lifetime) => {
+                let token_or_request = self.inner.inner.get_id_token(audience)?;
+                Ok(with_id_reason(
+                    token_or_request,
+                    RequestReason::Expired,
+                    hash_str(audience),
+                    &self.inner.headers,
+                    &self.inner.refreshes,
+                ))
+            }
+            token_or_request => Ok(token_or_request),
+        }
+    }
+
+    fn get_id_token_with_access_token<S>(
+        &self,
+        audience: &str,
+        response: crate::id_token::AccessTokenResponse<S>,
+    ) -> Result<crate::id_token::IdTokenRequest, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        self.inner
+            .get_id_token_with_access_token(audience, response)
+    }
+
+    fn parse_id_token_response<S>(
+        &self,
+        hash: u64,
+        response: http::Response<S>,
+    ) -> Result<IdToken, Error>
+    where
+        S: AsRef<[u8]>,
+    {
+        self.inner.parse_id_token_response(hash, response)
+    }
+}
+
+pub(crate) fn hash_str(str: &str) -> Hash {
+    let hash = {
+        let mut hasher = twox_hash::XxHash::default();
+        hasher.write(str.as_bytes());
+        hasher.finish()
+    };
+
+    hash
+}
+
+pub(crate) fn hash_scopes<'a, I, S>(scopes: &I) -> Hash
+where
+    S: AsRef<str> + 'a,
+    I: IntoIterator<Item = &'a S> + Clone,
+{
+    let scopes_str = scopes
+        .clone()
+        .into_iter()
+        .map(|s| s.as_ref())
+        .collect::<Vec<_>>()
+        .join("|");
+
+    hash_str(&scopes_str)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ops::Add,
+        time::{Duration, SystemTime},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_hash_scopes() {
+        use std::hash::Hasher;
+
+        let expected = {
+            let mut hasher = twox_hash::XxHash::default();
+            hasher.write(b"scope1|");
+            hasher.write(b"scope2|");
+            hasher.write(b"scope3");
+            hasher.finish()
+        };
 
         let hash = hash_scopes(&["scope1", "scope2", "scope3"].iter());
 
@@ -285,26 +1056,274 @@ mod test {
     fn test_cache() {
         let cache = TokenCache::new();
         let hash = hash_scopes(&["scope1", "scope2"].iter());
-        let token = mock_token(100);
-        let expired_token = mock_token(-100);
+        let token = Token::new_bearer("access-token", Duration::from_secs(100));
+        let expired_token = Token::new_bearer("access-token", Duration::ZERO);
 
         assert!(matches!(
-            cache.get(hash).unwrap(),
+            cache.get(hash),
             TokenOrRequestReason::RequestReason(RequestReason::ParametersChanged)
         ));
 
-        cache.insert(expired_token, hash).unwrap();
+        cache.insert(expired_token, hash);
 
         assert!(matches!(
-            cache.get(hash).unwrap(),
+            cache.get(hash),
             TokenOrRequestReason::RequestReason(RequestReason::Expired)
         ));
 
-        cache.insert(token, hash).unwrap();
+        cache.insert(token, hash);
+
+        assert!(matches!(cache.get(hash), TokenOrRequestReason::Token(..)));
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let cache = TokenCache::new().with_capacity(2);
+        let hash1 = hash_scopes(&["scope1"].iter());
+        let hash2 = hash_scopes(&["scope2"].iter());
+        let hash3 = hash_scopes(&["scope3"].iter());
+
+        cache.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            hash1,
+        );
+        cache.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            hash2,
+        );
+
+        // Use the first token so that the second is the least recently used
+        assert!(matches!(cache.get(hash1), TokenOrRequestReason::Token(..)));
+
+        cache.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            hash3,
+        );
+
+        assert!(matches!(cache.get(hash1), TokenOrRequestReason::Token(..)));
+        assert!(matches!(
+            cache.get(hash2),
+            TokenOrRequestReason::RequestReason(RequestReason::ParametersChanged)
+        ));
+        assert!(matches!(cache.get(hash3), TokenOrRequestReason::Token(..)));
+
+        // Replacing an existing token doesn't evict anything
+        cache.insert(
+            Token::new_bearer("access-token", Duration::from_secs(200)),
+            hash3,
+        );
+        assert_eq!(cache.cache.read().unwrap().len(), 2);
+
+        // The cache is kept sorted for lookups
+        let cache = cache.cache.read().unwrap();
+        assert!(cache.windows(2).all(|w| w[0].hash < w[1].hash));
+    }
+
+    #[test]
+    fn test_cache_refresh_margin() {
+        let hash = hash_scopes(&["scope1", "scope2"].iter());
+
+        // A token that is about to expire is treated as expired by default
+        let cache = TokenCache::new();
+        cache.insert(
+            Token::new_bearer("access-token", Duration::from_secs(5)),
+            hash,
+        );
+
+        assert!(matches!(
+            cache.get(hash),
+            TokenOrRequestReason::RequestReason(RequestReason::Expired)
+        ));
+
+        let cache = TokenCache::new().with_refresh_margin(Duration::ZERO);
+        cache.insert(
+            Token::new_bearer("access-token", Duration::from_secs(5)),
+            hash,
+        );
+
+        assert!(matches!(cache.get(hash), TokenOrRequestReason::Token(..)));
+
+        let cache = TokenCache::new().with_refresh_margin(Duration::from_secs(200));
+        cache.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            hash,
+        );
+
+        assert!(matches!(
+            cache.get(hash),
+            TokenOrRequestReason::RequestReason(RequestReason::Expired)
+        ));
+    }
+
+    #[test]
+    fn unrepresentable_margin() {
+        let token = Token::new_bearer("access-token", Duration::from_secs(3600));
+        assert!(!token.has_expired());
+        assert!(token.has_expired_with_margin(Duration::MAX));
+
+        let hash = hash_scopes(&["scope1"].iter());
+        let cache = TokenCache::new().with_refresh_margin(Duration::MAX);
+        cache.insert(token, hash);
+
+        assert!(matches!(
+            cache.get(hash),
+            TokenOrRequestReason::RequestReason(RequestReason::Expired)
+        ));
+
+        let provider =
+            MinLifetimeProvider::new(CachedTokenProvider::wrap(RequestProvider), Duration::MAX);
+        provider.inner().access_tokens.insert(
+            Token::new_bearer("access-token", Duration::from_secs(3600)),
+            hash,
+        );
+        assert!(matches!(
+            provider.get_token(&["scope1"]).unwrap(),
+            TokenOrRequest::Request {
+                reason: RequestReason::Expired,
+                ..
+            }
+        ));
+    }
+
+    /// A clock that only moves when told to
+    #[derive(Debug, Clone)]
+    struct FakeClock(Arc<std::sync::Mutex<SystemTime>>);
+
+    impl FakeClock {
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Creates a provider with a fake clock starting at the epoch, and a
+    /// cached token for the scopes that expires `expires_in` after the epoch
+    fn provider_with_token(
+        scopes: &[&str],
+        expires_in: Duration,
+    ) -> (FakeClock, CachedTokenProvider<RequestProvider>) {
+        let clock = FakeClock(Arc::new(std::sync::Mutex::new(SystemTime::UNIX_EPOCH)));
+        let provider = CachedTokenProvider::wrap(RequestProvider).with_clock(clock.clone());
+
+        let mut token = Token::new_bearer("access-token", Duration::ZERO);
+        token.expires_in_timestamp = Some(SystemTime::UNIX_EPOCH + expires_in);
+        provider
+            .access_tokens
+            .insert(token, hash_scopes(&scopes.iter()));
+
+        (clock, provider)
+    }
+
+    #[test]
+    fn test_cache_clock() {
+        let scopes = ["scope1"];
+        let (clock, cached_provider) = provider_with_token(&scopes, Duration::from_secs(100));
+
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+
+        // Still valid, but within the refresh margin
+        clock.advance(Duration::from_secs(95));
+
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Request {
+                reason: RequestReason::Expired,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_on_stale() {
+        use std::sync::atomic::AtomicU64;
+
+        let scopes = ["scope1"];
+        let scope_hash = hash_scopes(&scopes.iter());
+        let (clock, cached_provider) = provider_with_token(&scopes, Duration::from_secs(100));
+        let stale_hash = Arc::new(AtomicU64::new(0));
+        let cached_provider = {
+            let stale_hash = stale_hash.clone();
+            cached_provider.on_stale(Box::new(move |hash| {
+                stale_hash.store(hash, Ordering::SeqCst);
+            }))
+        };
+
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+        assert_eq!(stale_hash.load(Ordering::SeqCst), 0);
+
+        // Within the refresh margin, the token is still returned
+        clock.advance(Duration::from_secs(95));
+
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+        assert_eq!(stale_hash.load(Ordering::SeqCst), scope_hash);
+
+        // But not once it has actually expired
+        clock.advance(Duration::from_secs(5));
+
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Request {
+                reason: RequestReason::Expired,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_has_expired_with_margin() {
+        let token = Token::new_bearer("access-token", Duration::from_secs(100));
+        assert!(!token.has_expired());
+        assert!(!token.has_expired_with_margin(Duration::from_secs(50)));
+        assert!(token.has_expired_with_margin(Duration::from_secs(150)));
+
+        assert!(Token::new_bearer("access-token", Duration::ZERO).has_expired());
+
+        let mut token = Token::new_bearer("access-token", Duration::from_secs(100));
+        token.expires_in_timestamp = None;
+        assert!(token.has_expired());
 
+        let id_token =
+            IdToken::from_parts("id-token", SystemTime::now().add(Duration::from_secs(100)));
+        assert!(!id_token.has_expired());
+        assert!(id_token.has_expired_with_margin(Duration::from_secs(150)));
+    }
+
+    #[test]
+    fn has_expired_only() {
+        /// A token that only implements the required method, so margins
+        /// can't be applied to it
+        #[derive(Clone)]
+        struct Expired(bool);
+        impl CacheableToken for Expired {
+            fn has_expired(&self) -> bool {
+                self.0
+            }
+        }
+
+        assert!(!Expired(false).has_expired_with_margin(Duration::MAX));
+        assert!(Expired(true).has_expired_with_margin(Duration::ZERO));
+
+        let cache = TokenCache::new();
+        cache.insert(Expired(false), 1);
+        cache.insert(Expired(true), 2);
+        assert!(matches!(cache.get(1), TokenOrRequestReason::Token(..)));
         assert!(matches!(
-            cache.get(hash).unwrap(),
-            TokenOrRequestReason::Token(..)
+            cache.get(2),
+            TokenOrRequestReason::RequestReason(RequestReason::Expired)
         ));
     }
 
@@ -313,9 +1332,9 @@ mod test {
         let cached_provider = CachedTokenProvider::wrap(PanicProvider);
 
         let hash = hash_scopes(&["scope1", "scope2"].iter());
-        let token = mock_token(100);
+        let token = Token::new_bearer("access-token", Duration::from_secs(100));
 
-        cached_provider.access_tokens.insert(token, hash).unwrap();
+        cached_provider.access_tokens.insert(token, hash);
 
         let tor = cached_provider.get_token(&["scope1", "scope2"]).unwrap();
 
@@ -323,19 +1342,345 @@ mod test {
         assert!(matches!(tor, TokenOrRequest::Token(..)));
     }
 
-    fn mock_token(expires_in: i64) -> Token {
-        let expires_in_timestamp = if expires_in > 0 {
-            SystemTime::now().add(Duration::from_secs(expires_in as u64))
-        } else {
-            SystemTime::now().sub(Duration::from_secs(expires_in.unsigned_abs()))
-        };
+    #[test]
+    fn test_cache_invalidation() {
+        let cached_provider = CachedTokenProvider::wrap(RequestProvider);
+        let scopes = ["scope1", "scope2"];
+        let other_scopes = ["scope3"];
+
+        cached_provider.access_tokens.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            hash_scopes(&scopes.iter()),
+        );
+        cached_provider.access_tokens.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            hash_scopes(&other_scopes.iter()),
+        );
+
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+
+        cached_provider.invalidate_scopes(&scopes);
+
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Request {
+                reason: RequestReason::ParametersChanged,
+                ..
+            }
+        ));
+        assert!(matches!(
+            cached_provider.get_token(&other_scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+
+        cached_provider.clear();
+
+        assert!(matches!(
+            cached_provider.get_token(&other_scopes).unwrap(),
+            TokenOrRequest::Request { .. }
+        ));
+    }
+
+    #[test]
+    fn test_forced_token() {
+        let cached_provider = CachedTokenProvider::wrap(RequestProvider);
+        let scopes = ["scope1", "scope2"];
+        let scope_hash = hash_scopes(&scopes.iter());
+
+        cached_provider.access_tokens.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            scope_hash,
+        );
+
+        match cached_provider.get_token_forced(&scopes).unwrap() {
+            TokenOrRequest::Request {
+                reason: RequestReason::Forced,
+                scope_hash: hash,
+                ..
+            } => assert_eq!(hash, scope_hash),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // The existing token is kept until the new one is parsed
+        assert!(matches!(
+            cached_provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+    }
+
+    #[test]
+    fn test_clones_share_cache() {
+        let cached_provider = CachedTokenProvider::wrap(RequestProvider);
+        let clone = cached_provider.clone();
+        let scopes = ["scope1", "scope2"];
+
+        assert!(matches!(
+            clone.get_token(&scopes).unwrap(),
+            TokenOrRequest::Request { .. }
+        ));
+
+        cached_provider.access_tokens.insert(
+            Token::new_bearer("access-token", Duration::from_secs(100)),
+            hash_scopes(&scopes.iter()),
+        );
+
+        // A token cached by one handle is available to all of its clones
+        assert!(matches!(
+            clone.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+        assert!(matches!(
+            clone.clone().get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+    }
+
+    #[test]
+    fn user_agent() {
+        fn user_agent(provider: &impl TokenProvider) -> http::HeaderValue {
+            match provider.get_token(&["scope"]).unwrap() {
+                TokenOrRequest::Request { request, .. } => {
+                    let values = request.headers().get_all(http::header::USER_AGENT);
+                    assert_eq!(values.iter().count(), 1);
+                    request.headers()[http::header::USER_AGENT].clone()
+                }
+                _ => panic!("expected a request"),
+            }
+        }
+
+        // The default is set by the providers of this crate, so the requests
+        // of other providers are left alone
+        let provider = CachedTokenProvider::wrap(RequestProvider);
+        assert_eq!(provider.user_agent(), DEFAULT_USER_AGENT);
+        match provider.get_token(&["scope"]).unwrap() {
+            TokenOrRequest::Request { request, .. } => {
+                assert!(!request.headers().contains_key(http::header::USER_AGENT));
+            }
+            _ => panic!("expected a request"),
+        }
+        assert!(DEFAULT_USER_AGENT.starts_with("tame-oauth/"));
+
+        let provider = provider.with_user_agent("my-app/1.0").unwrap();
+        assert_eq!(user_agent(&provider), "my-app/1.0");
+        assert_eq!(provider.user_agent(), "my-app/1.0");
+
+        // Wrapping another cached provider replaces its user agent rather than
+        // adding another one
+        let provider = CachedTokenProvider::wrap(provider)
+            .with_user_agent("outer/1.0")
+            .unwrap();
+        assert_eq!(user_agent(&provider), "outer/1.0");
+
+        assert!(CachedTokenProvider::wrap(RequestProvider)
+            .with_user_agent("invalid\n")
+            .is_err());
+    }
+
+    #[test]
+    fn request_id() {
+        fn request(provider: &impl TokenProvider) -> http::Request<Vec<u8>> {
+            match provider.get_token(&["scope"]).unwrap() {
+                TokenOrRequest::Request { request, .. } => request,
+                _ => panic!("expected a request"),
+            }
+        }
+
+        let provider = CachedTokenProvider::wrap(RequestProvider);
+        assert!(provider.request_id().is_none());
+        assert!(!request(&provider).headers().contains_key(REQUEST_ID_HEADER));
+
+        let provider = provider.with_request_id("abcd").unwrap();
+        assert_eq!(request(&provider).headers()[REQUEST_ID_HEADER], "abcd");
+        match provider.get_token_forced(&["scope"]).unwrap() {
+            TokenOrRequest::Request { request, .. } => {
+                assert_eq!(request.headers()[REQUEST_ID_HEADER], "abcd");
+            }
+            _ => panic!("expected a request"),
+        }
+
+        let provider = CachedTokenProvider::wrap(RequestProvider)
+            .with_request_id_header("x-correlation-id", "efgh")
+            .unwrap();
+        let (header, request_id) = provider.request_id().unwrap();
+        assert_eq!(
+            (header.as_str(), request_id.to_str().unwrap()),
+            ("x-correlation-id", "efgh")
+        );
+        let request = request(&provider);
+        assert_eq!(request.headers()["x-correlation-id"], "efgh");
+        assert!(!request.headers().contains_key(REQUEST_ID_HEADER));
 
-        Token {
-            access_token: "access-token".to_string(),
-            refresh_token: "refresh-token".to_string(),
-            token_type: "token-type".to_string(),
-            expires_in: Some(expires_in),
-            expires_in_timestamp: Some(expires_in_timestamp),
+        assert!(CachedTokenProvider::wrap(RequestProvider)
+            .with_request_id("invalid\n")
+            .is_err());
+        assert!(CachedTokenProvider::wrap(RequestProvider)
+            .with_request_id_header("invalid header", "abcd")
+            .is_err());
+    }
+
+    #[test]
+    fn refresh_counts() {
+        let scopes = ["scope"];
+        let (clock, provider) = provider_with_token(&scopes, Duration::from_secs(100));
+        assert_eq!(provider.refresh_counts(), RefreshCounts::default());
+
+        provider.get_token(&["other"]).unwrap();
+
+        // Cached tokens aren't counted
+        assert!(matches!(
+            provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+        assert_eq!(
+            provider.refresh_counts(),
+            RefreshCounts {
+                parameters_changed: 1,
+                ..Default::default()
+            }
+        );
+
+        clock.advance(Duration::from_secs(100));
+        provider.get_token(&scopes).unwrap();
+        provider.get_token(&["other"]).unwrap();
+        // Clones share the counters
+        provider.clone().get_token_forced(&scopes).unwrap();
+        assert_eq!(
+            provider.refresh_counts(),
+            RefreshCounts {
+                expired: 1,
+                parameters_changed: 2,
+                forced: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn min_lifetime() {
+        let scopes = ["scope"];
+        let (clock, cached) = provider_with_token(&scopes, Duration::from_secs(1000));
+        let cached = cached.with_refresh_margin(Duration::ZERO);
+        let provider = MinLifetimeProvider::new(cached.clone(), Duration::from_secs(600));
+
+        assert!(matches!(
+            provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+
+        // Exactly the minimum lifetime left is too little, like the refresh margin
+        clock.advance(Duration::from_secs(399));
+        assert!(matches!(
+            provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            provider.get_token(&scopes).unwrap(),
+            TokenOrRequest::Request {
+                reason: RequestReason::Expired,
+                ..
+            }
+        ));
+
+        // The token is still returned by the cache itself
+        assert!(matches!(
+            cached.get_token(&scopes).unwrap(),
+            TokenOrRequest::Token(..)
+        ));
+        assert_eq!(cached.refresh_counts().expired, 1);
+
+        // Id tokens are held to the same minimum lifetime
+        cached.id_tokens.insert(
+            IdToken {
+                token: "id-token".to_owned(),
+                expiration: clock.now() + Duration::from_secs(1000),
+            },
+            hash_str("audience"),
+        );
+        assert!(matches!(
+            provider.get_id_token("audience").unwrap(),
+            IdTokenOrRequest::IdToken(..)
+        ));
+        clock.advance(Duration::from_secs(400));
+        assert!(matches!(
+            provider.get_id_token("audience").unwrap(),
+            IdTokenOrRequest::IdTokenRequest {
+                reason: RequestReason::Expired,
+                ..
+            }
+        ));
+        assert!(matches!(
+            cached.get_id_token("audience").unwrap(),
+            IdTokenOrRequest::IdToken(..)
+        ));
+        assert_eq!(cached.refresh_counts().expired, 2);
+    }
+
+    /// `RequestProvider` is a mock token provider that always returns a
+    /// request, as a way of testing when the cache wrapper misses.
+    #[derive(Clone)]
+    struct RequestProvider;
+    impl TokenProvider for RequestProvider {
+        fn get_token_with_subject<'a, S, I, T>(
+            &self,
+            _subject: Option<T>,
+            _scopes: I,
+        ) -> Result<TokenOrRequest, Error>
+        where
+            S: AsRef<str> + 'a,
+            I: IntoIterator<Item = &'a S> + Clone,
+            T: Into<String>,
+        {
+            Ok(TokenOrRequest::Request {
+                request: http::Request::new(Vec::new()),
+                reason: RequestReason::ParametersChanged,
+                scope_hash: 0,
+            })
+        }
+
+        fn parse_token_response<S>(
+            &self,
+            _hash: u64,
+            _response: http::Response<S>,
+        ) -> Result<Token, Error>
+        where
+            S: AsRef<[u8]>,
+        {
+            panic!("should not have been reached")
+        }
+    }
+
+    impl IdTokenProvider for RequestProvider {
+        fn get_id_token(&self, _audience: &str) -> Result<IdTokenOrRequest, Error> {
+            Ok(IdTokenOrRequest::IdTokenRequest {
+                request: http::Request::new(Vec::new()),
+                reason: RequestReason::ParametersChanged,
+                audience_hash: 0,
+            })
+        }
+
+        fn parse_id_token_response<S>(
+            &self,
+            _hash: u64,
+            _response: http::Response<S>,
+        ) -> Result<IdToken, Error>
+        where
+            S: AsRef<[u8]>,
+        {
+            panic!("should not have been reached")
+        }
+
+        fn get_id_token_with_access_token<S>(
+            &self,
+            _audience: &str,
+            _response: crate::id_token::AccessTokenResponse<S>,
+        ) -> Result<crate::id_token::IdTokenRequest, Error>
+        where
+            S: AsRef<[u8]>,
+        {
+            panic!("should not have been reached")
         }
     }
 